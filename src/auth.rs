@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use errors::*;
+
+/// A bearer token read from a file, which may be replaced on disk
+/// (eg: a projected service account token).
+#[derive(Debug)]
+pub struct TokenFile {
+    path: PathBuf,
+    cached: Mutex<Option<(SystemTime, String)>>,
+}
+
+impl TokenFile {
+    pub fn new(path: &Path) -> Self {
+        TokenFile {
+            path: path.to_owned(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The current token, re-read if the file has been modified since
+    /// it was last read.
+    pub fn token(&self) -> Result<String> {
+        let mtime = fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .chain_err(|| format!("Unable to read {}", self.path.display()))?;
+
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((t, ref token)) = *cached {
+            if t == mtime {
+                return Ok(token.clone());
+            }
+        }
+
+        debug!("Reading token from {}", self.path.display());
+        let token = fs::read_to_string(&self.path)
+            .chain_err(|| format!("Unable to read {}", self.path.display()))?
+            .trim()
+            .to_owned();
+        *cached = Some((mtime, token.clone()));
+        Ok(token)
+    }
+}
+
+/// A bearer token to authenticate with
+#[derive(Debug)]
+pub enum Token {
+    Static(String),
+    File(TokenFile),
+}
+
+impl Token {
+    pub fn value(&self) -> Result<String> {
+        match *self {
+            Token::Static(ref t) => Ok(t.clone()),
+            Token::File(ref f) => f.token(),
+        }
+    }
+}
+
+#[test]
+fn test_token_file() {
+    use std::time::Duration;
+
+    let path = ::std::env::temp_dir().join(format!("kubecfg-test-token-{}", ::std::process::id()));
+    fs::write(&path, "first\n").unwrap();
+
+    let t = TokenFile::new(&path);
+    assert_eq!(t.token().unwrap(), "first");
+
+    // Rotated on disk.  Bump the mtime explicitly, in case the
+    // filesystem timestamp resolution is coarse.
+    fs::write(&path, "second\n").unwrap();
+    let f = fs::File::options().write(true).open(&path).unwrap();
+    f.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    assert_eq!(t.token().unwrap(), "second");
+
+    fs::remove_file(&path).unwrap();
+    assert!(t.token().is_err());
+}
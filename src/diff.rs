@@ -77,6 +77,29 @@ impl<'a> fmt::Display for Diff<'a> {
     }
 }
 
+/// A `Diff` displayed with ANSI colours: red for removals, green for
+/// additions.
+pub struct Colored<'a, 'b: 'a>(pub &'a Diff<'b>);
+
+impl<'a, 'b> fmt::Display for Colored<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let color = match *self.0 {
+            Diff::AOnly(..) => "31",
+            Diff::BOnly(..) => "32",
+            Diff::Both(..) => return write!(f, "{}", self.0),
+        };
+        write!(f, "\x1b[{}m{}\x1b[0m", color, self.0)
+    }
+}
+
+#[test]
+fn test_colored() {
+    let v = JsonValue::from(1);
+    assert_eq!(Colored(&Diff::AOnly(0, Node::Leaf(&v))).to_string(), "\x1b[31m- 1\x1b[0m");
+    assert_eq!(Colored(&Diff::BOnly(1, Node::Leaf(&v))).to_string(), "\x1b[32m+   1\x1b[0m");
+    assert_eq!(Colored(&Diff::Both(0, ContextEntry::Name("a"))).to_string(), "  a:");
+}
+
 pub fn diff_walk<'a>(depth: usize, a: &'a JsonValue, b: &'a JsonValue) -> Vec<Diff<'a>> {
     let mut diffs = Vec::new();
     if a.is_array() && b.is_array() {
@@ -151,3 +174,158 @@ fn test_diff() {
                     Diff::AOnly(1, Node::Leaf(&"foo".into())),
                     Diff::BOnly(1, Node::Leaf(&"bar".into()))]);
 }
+
+fn github_escape_data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn github_escape_property(s: &str) -> String {
+    github_escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// A GitHub Actions workflow command that annotates `file` with a
+/// (multi-line) warning.
+pub fn github_warning(file: &str, title: &str, message: &str) -> String {
+    format!("::warning file={},title={}::{}",
+            github_escape_property(file),
+            github_escape_property(title),
+            github_escape_data(message))
+}
+
+#[test]
+fn test_github_warning() {
+    assert_eq!(github_warning("dir/app.jsonnet", "deployment/app in namespace ns", "  spec:\n-   replicas: 1\n+   replicas: 2"),
+               "::warning file=dir/app.jsonnet,title=deployment/app in namespace ns::  spec:%0A-   replicas: 1%0A+   replicas: 2");
+    assert_eq!(github_warning("a,b:c", "100%", "50%"),
+               "::warning file=a%2Cb%3Ac,title=100%25::50%25");
+}
+
+/// A row of a line-by-line diff
+#[derive(Debug,PartialEq)]
+pub enum Row<'a> {
+    Same(&'a str),
+    Changed(&'a str, &'a str),
+    AOnly(&'a str),
+    BOnly(&'a str),
+}
+
+/// Diff the lines of `a` and `b` (by longest common subsequence).
+/// Runs of removed lines followed by added lines are paired up as
+/// changes.
+pub fn line_diff<'a>(a: &'a str, b: &'a str) -> Vec<Row<'a>> {
+    let a: Vec<_> = a.lines().collect();
+    let b: Vec<_> = b.lines().collect();
+
+    // lcs[i][j] is the LCS length of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut rows = vec![];
+    let (mut removed, mut added) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            flush_changes(&mut rows, &mut removed, &mut added);
+            rows.push(Row::Same(a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            removed.push(a[i]);
+            i += 1;
+        } else {
+            added.push(b[j]);
+            j += 1;
+        }
+    }
+    flush_changes(&mut rows, &mut removed, &mut added);
+    rows
+}
+
+fn flush_changes<'a>(rows: &mut Vec<Row<'a>>, removed: &mut Vec<&'a str>, added: &mut Vec<&'a str>) {
+    let n = cmp::max(removed.len(), added.len());
+    for k in 0..n {
+        rows.push(match (removed.get(k), added.get(k)) {
+            (Some(a), Some(b)) => Row::Changed(a, b),
+            (Some(a), None) => Row::AOnly(a),
+            (None, Some(b)) => Row::BOnly(b),
+            (None, None) => unreachable!(),
+        });
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// Render `rows` as two columns fitting in `width`, marked like
+/// `diff -y`.  Long lines are wrapped within their column.
+pub fn side_by_side(rows: &[Row], width: usize) -> Vec<String> {
+    let col = cmp::max(width.saturating_sub(3) / 2, 10);
+    let chunks = |s: &str| -> Vec<String> {
+        let chars: Vec<_> = s.chars().collect();
+        if chars.is_empty() {
+            vec![String::new()]
+        } else {
+            chars.chunks(col).map(|c| c.iter().collect()).collect()
+        }
+    };
+
+    let mut lines = vec![];
+    for row in rows {
+        let (a, marker, b) = match *row {
+            Row::Same(s) => (s, ' ', s),
+            Row::Changed(a, b) => (a, '|', b),
+            Row::AOnly(a) => (a, '<', ""),
+            Row::BOnly(b) => (b, '>', ""),
+        };
+        let (left, right) = match *row {
+            Row::BOnly(_) => (vec![String::new()], chunks(a)),
+            _ => (chunks(a), chunks(b)),
+        };
+        for k in 0..cmp::max(left.len(), right.len()) {
+            let l = left.get(k).map(|s| s.as_str()).unwrap_or("");
+            let r = right.get(k).map(|s| s.as_str()).unwrap_or("");
+            let line = format!("{:<width$} {} {}", l, marker, r, width = col);
+            lines.push(line.trim_end().to_owned());
+        }
+    }
+    lines
+}
+
+#[test]
+fn test_side_by_side() {
+    let a = "kind: Deployment\nspec:\n  replicas: 1\n  paused: true\n";
+    let b = "kind: Deployment\nspec:\n  replicas: 2\n  minReadySeconds: 5\n  paused: true\n  extra: x\n";
+
+    let rows = line_diff(a, b);
+    assert_eq!(rows, vec![
+        Row::Same("kind: Deployment"),
+        Row::Same("spec:"),
+        Row::Changed("  replicas: 1", "  replicas: 2"),
+        Row::BOnly("  minReadySeconds: 5"),
+        Row::Same("  paused: true"),
+        Row::BOnly("  extra: x"),
+    ]);
+    assert_eq!(line_diff("", "a\n"), vec![Row::BOnly("a")]);
+
+    assert_eq!(side_by_side(&rows, 43), vec![
+        "kind: Deployment       kind: Deployment",
+        "spec:                  spec:",
+        "  replicas: 1        |   replicas: 2",
+        "                     >   minReadySeconds: 5",
+        "  paused: true           paused: true",
+        "                     >   extra: x",
+    ]);
+
+    // Wrapped within the (minimum width) column
+    assert_eq!(side_by_side(&[Row::AOnly("0123456789abc")], 0), vec![
+        "0123456789 <",
+        "abc        <",
+    ]);
+}
@@ -1,15 +1,26 @@
 use std::io::Write;
 use json::JsonValue;
-use yaml_rust::{YamlLoader, YamlEmitter};
 use std::str::FromStr;
 use std::fmt;
+use std::fs::{self,File};
+use std::iter;
+use std::path::{Path,PathBuf};
 
 use errors::*;
+use kutils::{self,JsonValueExt};
+use sources;
 
 #[derive(Debug,Clone,Copy,PartialEq)]
 pub enum OutputFormat {
+    /// Pretty-printed, as given: a List stays a List, which (unlike
+    /// an array) kubectl can apply
     Json,
+    /// Single-line array of objects
+    JsonCompact,
+    /// Newline-delimited JSON, one object per line
+    Ndjson,
     Yaml,
+    Toml,
 }
 
 impl FromStr for OutputFormat {
@@ -17,7 +28,10 @@ impl FromStr for OutputFormat {
     fn from_str(s: &str) -> Result<OutputFormat> {
         match s {
             "json" => Ok(OutputFormat::Json),
+            "json-compact" => Ok(OutputFormat::JsonCompact),
+            "ndjson" => Ok(OutputFormat::Ndjson),
             "yaml" => Ok(OutputFormat::Yaml),
+            "toml" => Ok(OutputFormat::Toml),
             _ => Err(ErrorKind::UnknownOutputFormat(s.to_owned()).into()),
         }
     }
@@ -28,8 +42,8 @@ impl Default for OutputFormat {
 }
 
 impl OutputFormat {
-    pub fn variants() -> [&'static str; 2] {
-        ["json", "yaml"]
+    pub fn variants() -> [&'static str; 5] {
+        ["json", "json-compact", "ndjson", "yaml", "toml"]
     }
     pub fn default() -> &'static str {
         let d: OutputFormat = Default::default();
@@ -39,7 +53,20 @@ impl OutputFormat {
     pub fn variant(&self) -> &'static str {
         match *self {
             OutputFormat::Json => "json",
+            OutputFormat::JsonCompact => "json-compact",
+            OutputFormat::Ndjson => "ndjson",
             OutputFormat::Yaml => "yaml",
+            OutputFormat::Toml => "toml",
+        }
+    }
+
+    /// Filename extension for files in this format
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Json | OutputFormat::JsonCompact => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Toml => "toml",
         }
     }
 }
@@ -68,80 +95,492 @@ fn emit_json<W>(content: &JsonValue, mut w: W) -> Result<()>
     where W: Write
 {
     let indent = 4;
-    content.write_pretty(&mut w, indent)
-        .map_err(|e| e.into())
+    content.write_pretty(&mut w, indent)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+fn emit_json_compact<W>(content: &JsonValue, mut w: W) -> Result<()>
+    where W: Write
+{
+    let items = kutils::flatten_list(content).into_iter().cloned().collect();
+    JsonValue::Array(items).write(&mut w)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+fn emit_ndjson<W>(content: &JsonValue, mut w: W) -> Result<()>
+    where W: Write
+{
+    for o in kutils::flatten_list(content) {
+        o.write(&mut w)?;
+        writeln!(w)?;
+    }
+    Ok(())
 }
 
-// `YamlEmitter::EmitError` doesn't implement `::std::error::Error` :(
-#[derive(Debug, Clone)]
-struct YamlEmitError(pub ::yaml_rust::EmitError);
+/// True if `s` can be written as a plain (unquoted) YAML scalar
+/// without being mistaken for anything else.
+fn yaml_plain_ok(s: &str) -> bool {
+    const RESERVED: &[&str] = &["null", "~", "true", "false", "yes", "no",
+                                "on", "off", "y", "n", "<<"];
+    let first = match s.chars().next() {
+        Some(c) => c,
+        None => return false,
+    };
+
+    !(RESERVED.contains(&s.to_lowercase().as_str()) ||
+      "-?:,[]{}#&*!|>'\"%@` .+".contains(first) ||
+      first.is_ascii_digit() ||
+      s.ends_with(' ') || s.ends_with(':') ||
+      s.contains(": ") || s.contains(" #") ||
+      s.chars().any(|c| !(c.is_ascii_graphic() || c == ' ')))
+}
 
-impl From<::yaml_rust::EmitError> for YamlEmitError {
-    fn from(e: ::yaml_rust::EmitError) -> Self { YamlEmitError(e) }
+/// True if `s` can be written as a literal block scalar.
+fn yaml_literal_ok(s: &str) -> bool {
+    s.contains('\n') &&
+        // Newlines alone would leave no content lines to keep
+        !s.trim_end_matches('\n').is_empty() &&
+        !s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') &&
+        // Leading spaces would need an explicit indentation indicator
+        !s.trim_start_matches('\n').starts_with(' ') &&
+        // Whitespace-only lines are not preserved
+        !s.lines().any(|l| !l.is_empty() && l.trim().is_empty())
 }
 
-impl ::std::error::Error for YamlEmitError {
-    fn description(&self) -> &str {
-        use yaml_rust::EmitError;
-        match self.0 {
-            EmitError::FmtError(ref e) => e.description(),
-            EmitError::BadHashmapKey => "Bad Hashmap Key",
+fn yaml_string(out: &mut String, s: &str, indent: usize) {
+    if yaml_plain_ok(s) {
+        out.push(' ');
+        out.push_str(s);
+        out.push('\n');
+    } else if yaml_literal_ok(s) {
+        let body = s.trim_end_matches('\n');
+        let chomp = match s.len() - body.len() {
+            0 => "|-",
+            1 => "|",
+            _ => "|+",
+        };
+        out.push(' ');
+        out.push_str(chomp);
+        out.push('\n');
+        for line in s.lines() {
+            if !line.is_empty() {
+                out.push_str(&" ".repeat(indent + 2));
+                out.push_str(line);
+            }
+            out.push('\n');
         }
+    } else {
+        // JSON string syntax is also valid YAML
+        out.push(' ');
+        out.push_str(&JsonValue::from(s).dump());
+        out.push('\n');
     }
+}
 
-    fn cause(&self) -> Option<&::std::error::Error> {
-        use yaml_rust::EmitError;
-        match self.0 {
-            EmitError::FmtError(ref e) => Some(e),
-            EmitError::BadHashmapKey => None,
+/// Write `v` following a `key:` or `- ` indicator, for a node
+/// nested at `indent`.
+fn yaml_node(out: &mut String, v: &JsonValue, indent: usize, after_dash: bool) {
+    match *v {
+        JsonValue::Object(_) if !v.is_empty() => if after_dash {
+            out.push(' ');
+            yaml_mapping(out, v, indent + 2, true);
+        } else {
+            out.push('\n');
+            yaml_mapping(out, v, indent + 2, false);
+        },
+        JsonValue::Array(_) if !v.is_empty() => if after_dash {
+            out.push(' ');
+            yaml_sequence(out, v, indent + 2, true);
+        } else {
+            out.push('\n');
+            yaml_sequence(out, v, indent + 2, false);
+        },
+        JsonValue::Object(_) => out.push_str(" {}\n"),
+        JsonValue::Array(_) => out.push_str(" []\n"),
+        JsonValue::Short(_) | JsonValue::String(_) =>
+            yaml_string(out, v.as_str().unwrap(), indent),
+        _ => {
+            out.push(' ');
+            out.push_str(&v.dump());
+            out.push('\n');
+        },
+    }
+}
+
+fn yaml_mapping(out: &mut String, v: &JsonValue, indent: usize, inline_first: bool) {
+    for (i, (k, v)) in v.entries().enumerate() {
+        if i > 0 || !inline_first {
+            out.push_str(&" ".repeat(indent));
+        }
+        if yaml_plain_ok(k) {
+            out.push_str(k);
+        } else {
+            out.push_str(&JsonValue::from(k).dump());
         }
+        out.push(':');
+        yaml_node(out, v, indent, false);
     }
 }
 
-impl fmt::Display for YamlEmitError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use yaml_rust::EmitError;
-        use ::std::error::Error;
-        match self.0 {
-            EmitError::FmtError(ref e) => write!(f, "Formatting error: {}", e),
-            EmitError::BadHashmapKey => write!(f, "{}", self.description()),
+fn yaml_sequence(out: &mut String, v: &JsonValue, indent: usize, inline_first: bool) {
+    for (i, v) in v.members().enumerate() {
+        if i > 0 || !inline_first {
+            out.push_str(&" ".repeat(indent));
         }
+        out.push('-');
+        yaml_node(out, v, indent, true);
     }
 }
 
+/// Serialise `content` as a YAML document.  Keys are written in
+/// their original order and formatting depends only on the value, so
+/// the output is byte-for-byte reproducible.
 fn emit_yaml<W>(content: &JsonValue, mut w: W) -> Result<()>
     where W: Write
 {
-    let mut buf = String::new();
-    {
-        let mut yaml_writer = YamlEmitter::new(&mut buf);
+    let mut buf = String::from("---");
+    match *content {
+        JsonValue::Object(_) if !content.is_empty() => {
+            buf.push('\n');
+            yaml_mapping(&mut buf, content, 0, false);
+        },
+        JsonValue::Array(_) if !content.is_empty() => {
+            buf.push('\n');
+            yaml_sequence(&mut buf, content, 0, false);
+        },
+        _ => yaml_node(&mut buf, content, 0, false),
+    }
 
-        let json = content.dump();
-        let docs = YamlLoader::load_from_str(&json)
-            .chain_err(|| "Error parsing YAML from JSON")?;
-        for doc in docs {
-            yaml_writer.dump(&doc)
-                .map_err(YamlEmitError)
-                .chain_err(|| "Error generating YAML")?;
+    w.write_all(buf.as_ref())?;
+
+    Ok(())
+}
+
+/// Write `objects` as a YAML stream, with comments before each
+/// document naming the object and where in `sources` it came from.
+pub fn emit_yaml_annotated<W>(objects: &[&JsonValue], sources: &[(PathBuf, String)], mut w: W) -> Result<()>
+    where W: Write
+{
+    for o in objects {
+        let id = o.k8s_id();
+        // Names can't contain newlines, but a malformed object might
+        writeln!(w, "# {}", id.to_string().replace('\n', " "))?;
+        if let Some(location) = sources::locate(&id, sources) {
+            writeln!(w, "# from {}", location)?;
         }
+        emit_yaml(o, &mut w)?;
     }
+    Ok(())
+}
 
-    w.write_all(buf.as_ref())?;
+#[test]
+fn test_yaml_annotated() {
+    use yaml_rust::YamlLoader;
 
+    let a = object!{"apiVersion" => "v1", "kind" => "ConfigMap", "metadata" => object!{"name" => "a"}};
+    let b = object!{"apiVersion" => "v1", "kind" => "Secret", "metadata" => object!{"name" => "b", "namespace" => "x"}};
+    let sources = vec![(PathBuf::from("main.jsonnet"), "{\n  b: { metadata: { name: 'b' } },\n}\n".to_owned())];
+
+    let mut buf = vec![];
+    emit_yaml_annotated(&[&a, &b], &sources, &mut buf).unwrap();
+    let out = String::from_utf8(buf).unwrap();
+
+    assert!(out.starts_with("# configmap/a\n---\n"), "{}", out);
+    assert!(out.contains("# secret/b in namespace x\n# from main.jsonnet:2\n---\n"), "{}", out);
+
+    let docs = YamlLoader::load_from_str(&out).unwrap();
+    assert_eq!(docs, YamlLoader::load_from_str(&format!("{}\n---\n{}", a.dump(), b.dump())).unwrap());
+}
+
+fn toml_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn toml_key(k: &str) -> String {
+    if !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        k.to_owned()
+    } else {
+        toml_string(k)
+    }
+}
+
+/// True if `v` is written as an array of tables (`[[key]]`)
+fn toml_table_array(v: &JsonValue) -> bool {
+    match *v {
+        JsonValue::Array(ref a) => !a.is_empty() && a.iter().all(|m| m.is_object()),
+        _ => false,
+    }
+}
+
+/// Write `v` as an inline value.  Null object fields are left out
+/// (as if absent); nulls in arrays can't be represented.
+fn toml_inline(out: &mut String, at: &str, v: &JsonValue) -> Result<()> {
+    match *v {
+        JsonValue::Null => bail!("TOML has no null, at {}", at),
+        JsonValue::Short(_) | JsonValue::String(_) => out.push_str(&toml_string(v.as_str().unwrap())),
+        JsonValue::Array(ref a) => {
+            out.push('[');
+            for (i, m) in a.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                toml_inline(out, at, m)?;
+            }
+            out.push(']');
+        },
+        JsonValue::Object(_) => {
+            out.push('{');
+            for (i, (k, m)) in v.entries().filter(|&(_, m)| !m.is_null()).enumerate() {
+                out.push_str(if i > 0 { ", " } else { " " });
+                out.push_str(&toml_key(k));
+                out.push_str(" = ");
+                toml_inline(out, &format!("{}.{}", at, k), m)?;
+                out.push(' ');
+            }
+            out.push('}');
+        },
+        _ => out.push_str(&v.dump()),
+    }
     Ok(())
 }
 
+/// Write the fields of the table at `path`: plain values first, since
+/// every key after a `[header]` belongs to that header's table.
+fn toml_table(out: &mut String, path: &mut Vec<String>, v: &JsonValue) -> Result<()> {
+    let is_section = |m: &JsonValue| (m.is_object() && !m.is_empty()) || toml_table_array(m);
+
+    for (k, m) in v.entries() {
+        if !m.is_null() && !is_section(m) {
+            out.push_str(&toml_key(k));
+            out.push_str(" = ");
+            let at: Vec<_> = path.iter().map(|p| p.as_str()).chain(iter::once(k)).collect();
+            toml_inline(out, &at.join("."), m)?;
+            out.push('\n');
+        }
+    }
+
+    for (k, m) in v.entries().filter(|&(_, m)| is_section(m)) {
+        path.push(toml_key(k));
+        let tables: Vec<_> = if m.is_object() { vec![m] } else { m.members().collect() };
+        for table in tables {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            if m.is_object() {
+                out.push_str(&format!("[{}]\n", path.join(".")));
+            } else {
+                out.push_str(&format!("[[{}]]\n", path.join(".")));
+            }
+            toml_table(out, path, table)?;
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Serialise `content` as a TOML document.  A List (or array) is
+/// written as an `items` array of tables.
+fn emit_toml<W>(content: &JsonValue, mut w: W) -> Result<()>
+    where W: Write
+{
+    let wrapped;
+    let table = if content.is_k8s_kind(kutils::V1_LIST) || content.is_array() {
+        let items: Vec<_> = kutils::flatten_list(content).into_iter()
+            .flat_map(|v| if v.is_array() { v.members().collect() } else { vec![v] })
+            .cloned()
+            .collect();
+        wrapped = object!{"items" => items};
+        &wrapped
+    } else if content.is_object() {
+        content
+    } else {
+        bail!("TOML output needs an object or a list of objects, not {}", content.dump());
+    };
+
+    let mut buf = String::new();
+    toml_table(&mut buf, &mut vec![], table)?;
+    w.write_all(buf.as_ref())?;
+    Ok(())
+}
+
+#[test]
+fn test_toml() {
+    let v = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{
+            "name" => "foo",
+            "labels" => object!{"app.kubernetes.io/name" => "foo"},
+            "annotations" => object!{},
+            "finalizers" => JsonValue::Null
+        },
+        "data" => object!{"script" => "#!/bin/sh\necho \"hi\"\n", "ctl" => "\u{7f}"},
+        "spec" => object!{
+            "replicas" => 3,
+            "ratio" => 0.5,
+            "args" => array!["--foo", 1, true, object!{"k" => "v", "n" => JsonValue::Null}],
+            "ports" => array![
+                object!{"name" => "http", "port" => 80, "extra" => object!{"x" => 1}},
+                object!{"name" => "https", "port" => 443, "sel" => object!{"a" => "b", "n" => JsonValue::Null}}
+            ]
+        }
+    };
+
+    let mut buf = vec![];
+    OutputFormat::Toml.emit(&v, &mut buf).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), r##"apiVersion = "v1"
+kind = "ConfigMap"
+
+[metadata]
+name = "foo"
+annotations = {}
+
+[metadata.labels]
+"app.kubernetes.io/name" = "foo"
+
+[data]
+script = "#!/bin/sh\necho \"hi\"\n"
+ctl = "\u007F"
+
+[spec]
+replicas = 3
+ratio = 0.5
+args = ["--foo", 1, true, { k = "v" }]
+
+[[spec.ports]]
+name = "http"
+port = 80
+
+[spec.ports.extra]
+x = 1
+
+[[spec.ports]]
+name = "https"
+port = 443
+
+[spec.ports.sel]
+a = "b"
+"##);
+
+    let list = object!{"apiVersion" => "v1", "kind" => "List", "items" => array![object!{"a" => 1}]};
+    let mut buf = vec![];
+    OutputFormat::Toml.emit(&list, &mut buf).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), "[[items]]\na = 1\n");
+
+    assert!(OutputFormat::Toml.emit(&JsonValue::Null, vec![]).is_err());
+    let e = OutputFormat::Toml.emit(&object!{"a" => array![1, JsonValue::Null]}, vec![]).unwrap_err();
+    assert_eq!(e.to_string(), "TOML has no null, at a");
+}
+
 impl OutputFormat {
     pub fn emit<W>(&self, content: &JsonValue, w: W) -> Result<()>
         where W: Write
     {
         match *self {
             OutputFormat::Json => emit_json(content, w),
+            OutputFormat::JsonCompact => emit_json_compact(content, w),
+            OutputFormat::Ndjson => emit_ndjson(content, w),
             OutputFormat::Yaml => emit_yaml(content, w),
+            OutputFormat::Toml => emit_toml(content, w),
+        }
+    }
+}
+
+/// How `show` writes a result with no objects in it
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum EmptyAs {
+    /// No output at all
+    Nothing,
+    /// An empty JSON array, or an empty YAML/ndjson stream
+    EmptyList,
+    Null,
+}
+
+impl FromStr for EmptyAs {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<EmptyAs> {
+        match s {
+            "nothing" => Ok(EmptyAs::Nothing),
+            "empty-list" => Ok(EmptyAs::EmptyList),
+            "null" => Ok(EmptyAs::Null),
+            _ => Err(format!("Unknown --output-empty-as value: '{}'", s).into()),
         }
     }
 }
 
+impl EmptyAs {
+    pub fn variants() -> [&'static str; 3] {
+        ["nothing", "empty-list", "null"]
+    }
+}
+
+/// True if `v` holds no objects: `null`, `[]` or a List without items
+pub fn is_empty_result(v: &JsonValue) -> bool {
+    match *v {
+        JsonValue::Null => true,
+        JsonValue::Array(ref a) => a.is_empty(),
+        _ => v.is_k8s_kind(kutils::V1_LIST) && v["items"].is_empty(),
+    }
+}
+
+impl OutputFormat {
+    /// Write an empty result, represented as `empty`.
+    pub fn emit_empty<W>(&self, empty: EmptyAs, mut w: W) -> Result<()>
+        where W: Write
+    {
+        match (empty, *self) {
+            (EmptyAs::Nothing, _) => (),
+            (EmptyAs::EmptyList, OutputFormat::Json) |
+            (EmptyAs::EmptyList, OutputFormat::JsonCompact) => writeln!(w, "[]")?,
+            (EmptyAs::EmptyList, OutputFormat::Ndjson) |
+            (EmptyAs::EmptyList, OutputFormat::Yaml) => (),
+            (EmptyAs::Null, OutputFormat::Yaml) => writeln!(w, "--- null")?,
+            (EmptyAs::EmptyList, OutputFormat::Toml) => writeln!(w, "items = []")?,
+            (EmptyAs::Null, OutputFormat::Toml) => bail!("TOML has no null"),
+            (EmptyAs::Null, _) => writeln!(w, "null")?,
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_emit_empty() {
+    let emit = |format: OutputFormat, empty: EmptyAs| {
+        let mut buf = vec![];
+        format.emit_empty(empty, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+
+    assert_eq!(emit(OutputFormat::Json, EmptyAs::EmptyList), "[]\n");
+    assert_eq!(emit(OutputFormat::JsonCompact, EmptyAs::EmptyList), "[]\n");
+    assert_eq!(emit(OutputFormat::Yaml, EmptyAs::EmptyList), "");
+    assert_eq!(emit(OutputFormat::Ndjson, EmptyAs::EmptyList), "");
+    assert_eq!(emit(OutputFormat::Json, EmptyAs::Null), "null\n");
+    assert_eq!(emit(OutputFormat::Yaml, EmptyAs::Null), "--- null\n");
+    assert_eq!(emit(OutputFormat::Json, EmptyAs::Nothing), "");
+
+    assert!(is_empty_result(&JsonValue::Null));
+    assert!(is_empty_result(&array![]));
+    assert!(is_empty_result(&object!{"apiVersion" => "v1", "kind" => "List", "items" => array![]}));
+    assert!(!is_empty_result(&object!{}));
+    assert!(!is_empty_result(&array![object!{}]));
+}
+
 #[test]
 fn test_json() {
     let v = object!{
@@ -159,8 +598,33 @@ fn test_json() {
     assert_eq!(v, ::json::parse(&buf_str).unwrap());
 }
 
+#[test]
+fn test_json_streams() {
+    let a = object!{"kind" => "ConfigMap", "metadata" => object!{"name" => "a"}};
+    let b = object!{"kind" => "Secret", "metadata" => object!{"name" => "b"}};
+    let list = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![a.clone(), b.clone()]
+    };
+
+    let emit = |format: OutputFormat, v: &JsonValue| {
+        let mut buf = vec![];
+        format.emit(v, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+
+    assert_eq!(emit(OutputFormat::JsonCompact, &list), format!("[{},{}]\n", a.dump(), b.dump()));
+    assert_eq!(emit(OutputFormat::JsonCompact, &a), format!("[{}]\n", a.dump()));
+    assert_eq!(emit(OutputFormat::Ndjson, &list), format!("{}\n{}\n", a.dump(), b.dump()));
+    assert_eq!(emit(OutputFormat::Ndjson, &a), format!("{}\n", a.dump()));
+    assert_eq!(json::parse(&emit(OutputFormat::Json, &list)).unwrap(), list);
+}
+
 #[test]
 fn test_yaml() {
+    use yaml_rust::YamlLoader;
+
     let v = object!{
         "foo" => 42,
         "bar" => object!{
@@ -177,3 +641,377 @@ fn test_yaml() {
     assert_eq!(YamlLoader::load_from_str(&v.to_string()).unwrap(),
                YamlLoader::load_from_str(&buf_str).unwrap());
 }
+
+/// Write each `(filename, object)` pair to a file under `dir`,
+/// creating directories as needed.  Objects that share a filename
+/// are written to the same file as a multi-document stream, or (for
+/// formats without one) as a v1 List.
+pub fn write_files(dir: &Path, files: &[(String, &JsonValue)], format: OutputFormat) -> Result<()> {
+    let mut names: Vec<&str> = vec![];
+    for (name, _) in files {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+
+    for name in names {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .chain_err(|| format!("Unable to create directory {}", parent.display()))?;
+        }
+
+        info!("Writing {}", path.display());
+        let mut f = File::create(&path)
+            .chain_err(|| format!("Unable to create {}", path.display()))?;
+        let objects: Vec<_> = files.iter()
+            .filter(|(n, _)| n == name)
+            .map(|&(_, o)| o)
+            .collect();
+        match format {
+            OutputFormat::Yaml | OutputFormat::Ndjson => for o in objects {
+                format.emit(o, &mut f)?;
+                writeln!(f)?;
+            },
+            _ if objects.len() == 1 => {
+                format.emit(objects[0], &mut f)?;
+                writeln!(f)?;
+            },
+            _ => {
+                let list = object!{
+                    "apiVersion" => "v1",
+                    "kind" => "List",
+                    "items" => objects.into_iter().cloned().collect::<Vec<_>>()
+                };
+                format.emit(&list, &mut f)?;
+                writeln!(f)?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Write each object to a separate YAML file in `dir`, along with a
+/// `kustomization.yaml` that lists them all as resources.
+pub fn write_kustomization(dir: &Path, objects: &[&JsonValue]) -> Result<()> {
+    let files: Vec<_> = objects.iter()
+        .map(|&o| (format!("{}.yaml", kutils::object_filename(o)), o))
+        .collect();
+    write_files(dir, &files, OutputFormat::Yaml)?;
+
+    let mut resources: Vec<&str> = vec![];
+    for (name, _) in &files {
+        if !resources.contains(&name.as_str()) {
+            resources.push(name);
+        }
+    }
+
+    let kustomization = object!{
+        "apiVersion" => "kustomize.config.k8s.io/v1beta1",
+        "kind" => "Kustomization",
+        "resources" => resources
+    };
+    write_files(dir, &[("kustomization.yaml".to_owned(), &kustomization)], OutputFormat::Yaml)
+}
+
+/// How `show --split-by` groups objects into files
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SplitBy {
+    Kind,
+    Namespace,
+    Name,
+}
+
+impl FromStr for SplitBy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<SplitBy> {
+        match s {
+            "kind" => Ok(SplitBy::Kind),
+            "namespace" => Ok(SplitBy::Namespace),
+            "name" => Ok(SplitBy::Name),
+            _ => Err(format!("Unknown --split-by key: '{}'", s).into()),
+        }
+    }
+}
+
+impl SplitBy {
+    pub fn variants() -> [&'static str; 3] {
+        ["kind", "namespace", "name"]
+    }
+
+    /// Filename stem (no extension) for the file holding `o`
+    pub fn file_stem(&self, o: &JsonValue) -> String {
+        match *self {
+            // Not pluralised: English plurals (eg "endpoints") can't
+            // be guessed from the kind
+            SplitBy::Kind => kutils::filename_safe(&o.k8s_kind().kind.to_lowercase()),
+            SplitBy::Namespace => o.k8s_namespace()
+                .map(kutils::filename_safe)
+                .unwrap_or_else(|| "_cluster".to_owned()),
+            SplitBy::Name => kutils::filename_safe(o.k8s_name().unwrap_or_default()),
+        }
+    }
+}
+
+/// Escape `{{` so Helm's template engine writes it out unchanged
+fn helm_escape(s: &str) -> String {
+    s.replace("{{", "{{ \"{{\" }}")
+}
+
+/// Write `objects` as a Helm chart in `dir`: one file per object in
+/// `templates/`, plus `Chart.yaml` and an empty `values.yaml`.  The
+/// templates are static; nothing in them refers to values.
+pub fn write_helm_chart(dir: &Path, objects: &[&JsonValue]) -> Result<()> {
+    let templates = dir.join("templates");
+    fs::create_dir_all(&templates)
+        .chain_err(|| format!("Unable to create directory {}", templates.display()))?;
+
+    let write = |path: PathBuf, text: &str| -> Result<()> {
+        info!("Writing {}", path.display());
+        fs::write(&path, text)
+            .chain_err(|| format!("Unable to write {}", path.display()))
+    };
+
+    // Objects that share a filename share a file, as in write_files
+    let mut files: Vec<(String, String)> = vec![];
+    for &o in objects {
+        let filename = format!("{}.yaml", kutils::object_filename(o));
+        let mut buf = vec![];
+        emit_yaml(o, &mut buf)?;
+        let text = helm_escape(&String::from_utf8(buf).unwrap());
+        match files.iter_mut().find(|(n, _)| *n == filename) {
+            Some((_, existing)) => existing.push_str(&text),
+            None => files.push((filename, text)),
+        }
+    }
+    for (filename, text) in files {
+        write(templates.join(filename), &text)?;
+    }
+
+    // Named after the directory, which (as ".") may need resolving
+    let name = dir.canonicalize().ok()
+        .and_then(|d| d.file_name().map(|n| kutils::filename_safe(&n.to_string_lossy()).to_lowercase()))
+        .unwrap_or_else(|| "kubecfg".to_owned());
+    let chart = object!{
+        "apiVersion" => "v2",
+        "name" => name,
+        "description" => "Static templates generated by kubecfg",
+        "type" => "application",
+        "version" => "0.1.0"
+    };
+    let mut buf = vec![];
+    emit_yaml(&chart, &mut buf)?;
+    write(dir.join("Chart.yaml"), &String::from_utf8(buf).unwrap())?;
+    write(dir.join("values.yaml"), "# The templates in this chart are static, and use no values.\n")
+}
+
+#[test]
+fn test_helm_chart() {
+    use yaml_rust::YamlLoader;
+
+    let dir = ::std::env::temp_dir().join(format!("kubecfg-test-helm-{}", ::std::process::id()));
+    let cm = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"},
+        "data" => object!{"tmpl" => "Hello {{ .Name }}"}
+    };
+    write_helm_chart(&dir.join("My Chart"), &[&cm]).unwrap();
+
+    let chart = fs::read_to_string(dir.join("My Chart/Chart.yaml")).unwrap();
+    let chart = &YamlLoader::load_from_str(&chart).unwrap()[0];
+    assert_eq!(chart["apiVersion"].as_str(), Some("v2"));
+    assert_eq!(chart["name"].as_str(), Some("my_chart"));
+    assert!(dir.join("My Chart/values.yaml").exists());
+
+    let template = fs::read_to_string(dir.join("My Chart/templates/myns-configmap-foo.yaml")).unwrap();
+    assert!(template.contains(r#"tmpl: Hello {{ "{{" }} .Name }}"#), "{}", template);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Write `objects` to files in `dir`, grouped by `split`.
+pub fn write_split(dir: &Path, objects: &[&JsonValue], split: SplitBy, format: OutputFormat) -> Result<()> {
+    let files: Vec<_> = objects.iter()
+        .map(|&o| (format!("{}.{}", split.file_stem(o), format.extension()), o))
+        .collect();
+    write_files(dir, &files, format)
+}
+
+/// Placeholders understood by `FilenameTemplate`
+const TEMPLATE_FIELDS: [&str; 5] = ["namespace", "kind", "name", "group", "version"];
+
+/// A `show --filename-template`, eg: `{namespace}/{kind}-{name}.yaml`
+#[derive(Debug,Clone,PartialEq)]
+pub struct FilenameTemplate(String);
+
+impl FromStr for FilenameTemplate {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<FilenameTemplate> {
+        let mut rest = s;
+        while let Some(i) = rest.find('{') {
+            let end = rest[i..].find('}')
+                .ok_or_else(|| format!("Unterminated placeholder in filename template: '{}'", s))?;
+            let field = &rest[i + 1..i + end];
+            if !TEMPLATE_FIELDS.contains(&field) {
+                return Err(format!("Unknown placeholder {{{}}} in filename template, expected one of: {}",
+                                   field, TEMPLATE_FIELDS.join(", ")).into());
+            }
+            rest = &rest[i + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in filename template: '{}'", s).into());
+        }
+        Ok(FilenameTemplate(s.to_owned()))
+    }
+}
+
+impl FilenameTemplate {
+    /// The (relative) filename for `o`.  Cluster-scoped objects have
+    /// namespace `_cluster`, and the core API group is `core`.
+    pub fn filename(&self, o: &JsonValue) -> String {
+        let kind = o.k8s_kind();
+        let group = if kind.api_version.is_core() { "core" } else { &kind.api_version.group };
+        self.0
+            .replace("{namespace}", &o.k8s_namespace()
+                     .map(kutils::filename_safe)
+                     .unwrap_or_else(|| "_cluster".to_owned()))
+            .replace("{kind}", &kutils::filename_safe(&kind.kind.to_lowercase()))
+            .replace("{name}", &kutils::filename_safe(o.k8s_name().unwrap_or_default()))
+            .replace("{group}", &kutils::filename_safe(group))
+            .replace("{version}", &kutils::filename_safe(&kind.api_version.version))
+    }
+}
+
+/// Write `objects` to files in `dir`, named by `template`.
+pub fn write_template(dir: &Path, objects: &[&JsonValue], template: &FilenameTemplate, format: OutputFormat) -> Result<()> {
+    let files: Vec<_> = objects.iter()
+        .map(|&o| (template.filename(o), o))
+        .collect();
+    write_files(dir, &files, format)
+}
+
+#[test]
+fn test_filename_template() {
+    let deploy = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    let ns = object!{
+        "apiVersion" => "v1",
+        "kind" => "Namespace",
+        "metadata" => object!{"name" => "myns"}
+    };
+
+    let t: FilenameTemplate = "{namespace}/{kind}-{name}.yaml".parse().unwrap();
+    assert_eq!(t.filename(&deploy), "myns/deployment-foo.yaml");
+    assert_eq!(t.filename(&ns), "_cluster/namespace-myns.yaml");
+
+    let t: FilenameTemplate = "{group}/{version}/{name}.json".parse().unwrap();
+    assert_eq!(t.filename(&deploy), "apps/v1/foo.json");
+    assert_eq!(t.filename(&ns), "core/v1/myns.json");
+
+    assert!("{namespace}/{nmae}.yaml".parse::<FilenameTemplate>().is_err());
+    assert!("{name.yaml".parse::<FilenameTemplate>().is_err());
+    assert!("name}.yaml".parse::<FilenameTemplate>().is_err());
+}
+
+#[test]
+fn test_split_by() {
+    let deploy = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    let ingress = object!{
+        "apiVersion" => "networking.k8s.io/v1",
+        "kind" => "Ingress",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    let class = object!{
+        "apiVersion" => "scheduling.k8s.io/v1",
+        "kind" => "PriorityClass",
+        "metadata" => object!{"name" => "high"}
+    };
+
+    assert_eq!(SplitBy::Kind.file_stem(&deploy), "deployment");
+    assert_eq!(SplitBy::Kind.file_stem(&ingress), "ingress");
+    assert_eq!(SplitBy::Kind.file_stem(&class), "priorityclass");
+    assert_eq!(SplitBy::Namespace.file_stem(&deploy), "myns");
+    assert_eq!(SplitBy::Namespace.file_stem(&class), "_cluster");
+    assert_eq!(SplitBy::Name.file_stem(&ingress), "foo");
+
+    // Several JSON objects in one file are written as a List
+    let dir = ::std::env::temp_dir().join(format!("kubecfg-test-split-{}", ::std::process::id()));
+    let mut other = deploy.clone();
+    other["metadata"]["name"] = "bar".into();
+    write_split(&dir, &[&deploy, &other, &class], SplitBy::Kind, OutputFormat::Json).unwrap();
+    let read = |name: &str| json::parse(&fs::read_to_string(dir.join(name)).unwrap()).unwrap();
+    assert_eq!(read("deployment.json"), object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![deploy.clone(), other]
+    });
+    assert_eq!(read("priorityclass.json"), class);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_yaml_golden() {
+    use yaml_rust::YamlLoader;
+
+    let v = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{
+            "name" => "golden",
+            "labels" => object!{
+                "app.kubernetes.io/name" => "golden"
+            },
+            "annotations" => object!{}
+        },
+        "data" => object!{
+            "empty" => "",
+            "bool" => "true",
+            "number" => "1.0",
+            "colon" => "a: b",
+            "leading" => " space",
+            "script" => "#!/bin/sh\necho hello\n",
+            "nonl" => "line1\nline2",
+            "unicode" => "caf\u{e9}"
+        },
+        "spec" => object!{
+            "replicas" => 3,
+            "ratio" => 0.5,
+            "paused" => false,
+            "selector" => JsonValue::Null,
+            "ports" => array![
+                object!{"name" => "http", "port" => 80},
+                object!{"name" => "https", "port" => 443}
+            ],
+            "args" => array!["--foo", "bar"],
+            "matrix" => array![array![1, 2], array![]],
+            "finalizers" => array![]
+        }
+    };
+
+    let mut buf = vec![];
+    OutputFormat::Yaml.emit(&v, &mut buf).unwrap();
+    let buf_str = String::from_utf8(buf).unwrap();
+
+    assert_eq!(buf_str, include_str!("testdata/golden.yaml"));
+    assert_eq!(YamlLoader::load_from_str(&v.dump()).unwrap(),
+               YamlLoader::load_from_str(&buf_str).unwrap());
+
+    for s in &["a\n\n", "a\n\n\n", "\n", "\n\n", "\na"] {
+        let v = object!{"data" => object!{"s" => *s}, "next" => 1};
+        let mut buf = vec![];
+        OutputFormat::Yaml.emit(&v, &mut buf).unwrap();
+        let buf_str = String::from_utf8(buf).unwrap();
+        assert_eq!(YamlLoader::load_from_str(&v.dump()).unwrap(),
+                   YamlLoader::load_from_str(&buf_str).unwrap(),
+                   "{:?} as {:?}", s, buf_str);
+    }
+}
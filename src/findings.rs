@@ -0,0 +1,154 @@
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use json::JsonValue;
+
+use errors::*;
+use emitters::OutputFormat;
+use kutils::ObjectId;
+use sources;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A problem found by `check`
+#[derive(Debug,Clone,PartialEq)]
+pub struct Finding {
+    pub object: ObjectId,
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// Location of the problem within the object, if known
+    pub path: Option<String>,
+    /// Approximate `file:line` the object came from, if known
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref location) = self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}: {}", self.object, self.message)
+    }
+}
+
+impl<'a> From<&'a Finding> for JsonValue {
+    fn from(f: &'a Finding) -> Self {
+        let mut res = object!{
+            "object" => f.object.to_string(),
+            "rule" => f.rule,
+            "severity" => f.severity.as_str(),
+            "message" => f.message.clone(),
+            "path" => f.path.clone()
+        };
+        if let Some(ref location) = f.location {
+            res["location"] = location.clone().into();
+        }
+        res
+    }
+}
+
+/// Write `findings` as text lines, or as a single JSON array.
+/// Fails if any finding is an error.
+pub fn report<W>(findings: &[Finding], json: bool, mut w: W) -> Result<()>
+    where W: Write
+{
+    if json {
+        let v = JsonValue::Array(findings.iter().map(JsonValue::from).collect());
+        OutputFormat::Json.emit(&v, w)?;
+    } else {
+        for f in findings {
+            writeln!(w, "{}", f)?;
+        }
+    }
+
+    let errors = findings.iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        Err(ErrorKind::ValidationFailed(errors).into())
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_report() {
+    use kutils::K8sKind;
+
+    let id = ObjectId {
+        kind: K8sKind::new("v1", "ConfigMap"),
+        namespace: None,
+        name: "foo".to_owned(),
+    };
+    let warning = Finding {
+        object: id.clone(),
+        rule: "test-warning",
+        severity: Severity::Warning,
+        message: "looks odd".to_owned(),
+        path: None,
+        location: None,
+    };
+    let error = Finding {
+        object: id,
+        rule: "unknown-field",
+        severity: Severity::Error,
+        message: "unknown field \"metadta\"".to_owned(),
+        path: Some("metadta".to_owned()),
+        location: None,
+    };
+
+    let findings = vec![warning, error];
+
+    let mut out = vec![];
+    report(&findings[..1], false, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "configmap/foo: looks odd\n");
+
+    let mut located = findings[0].clone();
+    located.location = Some("main.jsonnet:3".to_owned());
+    assert_eq!(located.to_string(), "main.jsonnet:3: configmap/foo: looks odd");
+
+    let mut out = vec![];
+    let r = report(&findings, true, &mut out);
+    match r.unwrap_err().kind() {
+        &ErrorKind::ValidationFailed(1) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+    assert_eq!(::json::parse(&String::from_utf8(out).unwrap()).unwrap(), array![
+        object!{
+            "object" => "configmap/foo",
+            "rule" => "test-warning",
+            "severity" => "warning",
+            "message" => "looks odd",
+            "path" => JsonValue::Null
+        },
+        object!{
+            "object" => "configmap/foo",
+            "rule" => "unknown-field",
+            "severity" => "error",
+            "message" => "unknown field \"metadta\"",
+            "path" => "metadta"
+        }
+    ]);
+}
+
+/// Set `location` on each finding to the approximate place its
+/// object was defined, if that can be found in `sources`.
+pub fn locate(findings: &mut [Finding], sources: &[(PathBuf, String)]) {
+    for f in findings {
+        f.location = sources::locate(&f.object, sources);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use base64;
+use json::JsonValue;
+
+use errors::*;
+use kutils;
+
+/// A file, or data inline in the kubeconfig (as for
+/// `certificate-authority` and `certificate-authority-data`).
+#[derive(Debug,Clone,PartialEq)]
+pub enum FileOrData {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+impl FileOrData {
+    pub fn read(&self) -> Result<Vec<u8>> {
+        match *self {
+            FileOrData::File(ref path) => fs::read(path)
+                .chain_err(|| format!("Unable to read {}", path.display())),
+            FileOrData::Data(ref data) => Ok(data.clone()),
+        }
+    }
+}
+
+/// The settings kubecfg uses from one kubeconfig context, with its
+/// cluster and user.
+#[derive(Debug,Default,PartialEq)]
+pub struct KubeConfig {
+    pub context: String,
+    pub server: Option<String>,
+    pub certificate_authority: Option<FileOrData>,
+    pub tls_server_name: Option<String>,
+    pub insecure_skip_tls_verify: bool,
+    pub namespace: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub client_certificate: Option<FileOrData>,
+    pub client_key: Option<FileOrData>,
+}
+
+/// The kubeconfig files to read, as kubectl: the `:`-separated
+/// `$KUBECONFIG`, or `~/.kube/config`.
+pub fn default_paths() -> Vec<PathBuf> {
+    match env::var_os("KUBECONFIG") {
+        Some(ref v) if !v.is_empty() => env::split_paths(v).collect(),
+        _ => env::var_os("HOME")
+            .map(|home| vec![Path::new(&home).join(".kube").join("config")])
+            .unwrap_or_default(),
+    }
+}
+
+/// Load `context` (default: the current context) from the kubeconfig
+/// files `paths`.  As kubectl, the first file to set a value wins, and
+/// missing files are skipped.  None if there is no file or no context
+/// to use.
+pub fn load(paths: &[PathBuf], context: Option<&str>) -> Result<Option<KubeConfig>> {
+    let mut found = false;
+    let mut current = None;
+    // (section, name) => (value, directory of the file it came from)
+    let mut entries: BTreeMap<(&str, String), (JsonValue, PathBuf)> = BTreeMap::new();
+
+    for path in paths {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).chain_err(|| format!("Unable to read {}", path.display())),
+        };
+        debug!("Reading kubeconfig {}", path.display());
+        found = true;
+
+        let config = kutils::parse_json_or_yaml(&text)
+            .chain_err(|| format!("Unable to parse kubeconfig {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(".")).to_owned();
+
+        if current.is_none() {
+            current = config["current-context"].as_str()
+                .filter(|c| !c.is_empty())
+                .map(|c| c.to_owned());
+        }
+        for &(section, field) in &[("clusters", "cluster"), ("users", "user"), ("contexts", "context")] {
+            for entry in config[section].members() {
+                if let Some(name) = entry["name"].as_str() {
+                    entries.entry((section, name.to_owned()))
+                        .or_insert_with(|| (entry[field].clone(), dir.clone()));
+                }
+            }
+        }
+    }
+
+    let name = match context.map(|c| c.to_owned()).or(current) {
+        Some(name) if found => name,
+        _ if context.is_some() => bail!("No kubeconfig found for --context"),
+        _ => return Ok(None),
+    };
+    let get = |section, name: &str| entries.get(&(section, name.to_owned()));
+
+    let (ctx, _) = get("contexts", &name)
+        .ok_or_else(|| format!("Context '{}' not found in kubeconfig", name))?;
+    let cluster_name = ctx["cluster"].as_str().unwrap_or("");
+    let (cluster, cluster_dir) = get("clusters", cluster_name)
+        .ok_or_else(|| format!("Cluster '{}' (of context '{}') not found in kubeconfig", cluster_name, name))?;
+    let no_user = (JsonValue::Null, PathBuf::new());
+    let (user, user_dir) = ctx["user"].as_str()
+        .and_then(|u| get("users", u))
+        .unwrap_or(&no_user);
+
+    let string = |v: &JsonValue| v.as_str().map(|s| s.to_owned());
+    Ok(Some(KubeConfig {
+        server: string(&cluster["server"]),
+        certificate_authority: file_or_data(cluster, "certificate-authority", cluster_dir)?,
+        tls_server_name: string(&cluster["tls-server-name"]),
+        insecure_skip_tls_verify: cluster["insecure-skip-tls-verify"].as_bool().unwrap_or(false),
+        namespace: string(&ctx["namespace"]),
+        token: string(&user["token"]),
+        token_file: user["tokenFile"].as_str().map(|p| user_dir.join(p)),
+        client_certificate: file_or_data(user, "client-certificate", user_dir)?,
+        client_key: file_or_data(user, "client-key", user_dir)?,
+        context: name,
+    }))
+}
+
+/// `key-data` (base64), or else the file `key`, relative to `dir`.
+fn file_or_data(v: &JsonValue, key: &str, dir: &Path) -> Result<Option<FileOrData>> {
+    let data_key = format!("{}-data", key);
+    if let Some(data) = v[data_key.as_str()].as_str() {
+        let data = base64::decode(data)
+            .chain_err(|| format!("Invalid base64 in kubeconfig {}", data_key))?;
+        return Ok(Some(FileOrData::Data(data)));
+    }
+    Ok(v[key].as_str().map(|p| FileOrData::File(dir.join(p))))
+}
+
+#[test]
+fn test_load() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-kubeconfig-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let first = dir.join("first");
+    let second = dir.join("second");
+    fs::write(&first, "
+current-context: dev
+contexts:
+- name: dev
+  context: {cluster: dev, user: dev, namespace: team}
+clusters:
+- name: dev
+  cluster:
+    server: https://dev.example.com:6443
+    certificate-authority-data: aGVsbG8=
+    insecure-skip-tls-verify: true
+").unwrap();
+    fs::write(&second, "
+current-context: prod
+contexts:
+- name: dev
+  context: {cluster: ignored, user: ignored}
+- name: prod
+  context: {cluster: prod, user: admin}
+clusters:
+- name: prod
+  cluster: {server: https://prod.example.com, certificate-authority: certs/ca.crt, tls-server-name: kubernetes}
+users:
+- name: dev
+  user: {token: secret}
+- name: admin
+  user: {client-certificate: admin.crt, client-key: /keys/admin.key, tokenFile: token}
+").unwrap();
+    let paths = vec![dir.join("missing"), first, second];
+
+    assert_eq!(load(&paths, None).unwrap().unwrap(), KubeConfig {
+        context: "dev".to_owned(),
+        server: Some("https://dev.example.com:6443".to_owned()),
+        certificate_authority: Some(FileOrData::Data(b"hello".to_vec())),
+        insecure_skip_tls_verify: true,
+        namespace: Some("team".to_owned()),
+        token: Some("secret".to_owned()),
+        ..Default::default()
+    });
+
+    assert_eq!(load(&paths, Some("prod")).unwrap().unwrap(), KubeConfig {
+        context: "prod".to_owned(),
+        server: Some("https://prod.example.com".to_owned()),
+        certificate_authority: Some(FileOrData::File(dir.join("certs/ca.crt"))),
+        tls_server_name: Some("kubernetes".to_owned()),
+        token_file: Some(dir.join("token")),
+        client_certificate: Some(FileOrData::File(dir.join("admin.crt"))),
+        client_key: Some(FileOrData::File(PathBuf::from("/keys/admin.key"))),
+        ..Default::default()
+    });
+
+    let e = load(&paths, Some("staging")).unwrap_err();
+    assert_eq!(e.to_string(), "Context 'staging' not found in kubeconfig");
+    assert_eq!(load(&[dir.join("missing")], None).unwrap(), None);
+    assert!(load(&[dir.join("missing")], Some("dev")).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,7 +1,9 @@
 use std::io::{Read,BufRead,BufReader};
 use std::fmt;
+use std::str::FromStr;
 use json::JsonValue;
 use hyper::client::Response;
+use yaml_rust::{Yaml,YamlLoader};
 
 use errors::*;
 
@@ -12,8 +14,9 @@ pub struct ApiVersion {
 }
 
 impl ApiVersion {
+    /// The legacy core group, served under `/api` rather than `/apis`
     pub fn is_core(&self) -> bool {
-        self.group == "" && self.version == "v1"
+        self.group.is_empty()
     }
 
     pub fn path_segments(&self, path: &mut ::url::PathSegmentsMut) {
@@ -35,6 +38,8 @@ impl<'a> From<&'a str> for ApiVersion {
             Some(i) => (&s[0..i], &s[i + 1 ..]),
             None => ("", s),
         };
+        // Some tools spell the legacy core group "core/v1"
+        let g = if g == "core" { "" } else { g };
         ApiVersion { group: g.to_owned(), version: v.to_owned() }
     }
 }
@@ -50,11 +55,34 @@ impl fmt::Display for ApiVersion {
     }
 }
 
+#[test]
+fn test_api_version_core() {
+    use url::Url;
+
+    let path = |v: &str| {
+        let mut url = Url::parse("https://example.com").unwrap();
+        ApiVersion::from(v).path_segments(&mut url.path_segments_mut().unwrap());
+        url.path().to_owned()
+    };
+
+    assert_eq!(ApiVersion::from("core/v1"), ApiVersion::from("v1"));
+    assert_eq!(ApiVersion::from("core/v1").to_string(), "v1");
+    assert_eq!(path("v1"), "/api/v1");
+    assert_eq!(path("core/v1"), "/api/v1");
+    assert_eq!(path("apps/v1"), "/apis/apps/v1");
+    assert_eq!(path("v2"), "/api/v2");
+}
+
 #[derive(Debug,Clone,PartialEq)]
 pub struct ApiResource {
     pub name: String,
     pub kind: String,
     pub namespaced: bool,
+    /// Subresources of this resource (eg: `status`, `scale`)
+    pub subresources: Vec<String>,
+    /// Whether this is served from a CustomResourceDefinition, once
+    /// looked up (see `PatchType::for_kind`)
+    pub custom: Option<bool>,
 }
 
 impl ApiResource {
@@ -68,8 +96,14 @@ impl ApiResource {
                 .ok_or_else(|| ErrorKind::MalformedObject(v.to_owned()))?,
             namespaced: v["namespaced"].as_bool()
                 .unwrap_or(false),
+            subresources: vec![],
+            custom: None,
         })
     }
+
+    pub fn has_subresource(&self, name: &str) -> bool {
+        self.subresources.iter().any(|s| s == name)
+    }
 }
 
 #[derive(Clone,Debug,PartialEq,PartialOrd,Eq,Ord)]
@@ -91,6 +125,24 @@ impl fmt::Display for K8sKind {
     }
 }
 
+/// Identity of a single object within a cluster
+#[derive(Clone,Debug,PartialEq,PartialOrd,Eq,Ord)]
+pub struct ObjectId {
+    pub kind: K8sKind,
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.kind.kind.to_lowercase(), self.name)?;
+        if let Some(ref ns) = self.namespace {
+            write!(f, " in namespace {}", ns)?;
+        }
+        Ok(())
+    }
+}
+
 pub const V1_LIST: (&'static str, &'static str) = ("v1", "List");
 pub const V1_NAMESPACE: (&'static str, &'static str) = ("v1", "Namespace");
 pub const V1_SERVICE: (&'static str, &'static str) = ("v1", "Service");
@@ -98,6 +150,11 @@ pub const V1_CONFIGMAP: (&'static str, &'static str) = ("v1", "ConfigMap");
 pub const V1_SECRET: (&'static str, &'static str) = ("v1", "Secret");
 pub const V1_PVC: (&'static str, &'static str) = ("v1", "PersistentVolumeClaim");
 pub const V1BETA1_DEPLOYMENT: (&'static str, &'static str) = ("extensions/v1beta1", "Deployment");
+pub const V1_STATEFULSET: (&'static str, &'static str) = ("apps/v1", "StatefulSet");
+pub const V1_DAEMONSET: (&'static str, &'static str) = ("apps/v1", "DaemonSet");
+
+/// Kinds that `update --wait` waits for the rollout of
+pub const ROLLOUT_KINDS: &'static [(&'static str, &'static str)] = &[V1BETA1_DEPLOYMENT, V1_STATEFULSET, V1_DAEMONSET];
 
 pub trait JsonValueExt {
     fn is_k8s_kind(&self, kind: (&str, &str)) -> bool;
@@ -110,6 +167,15 @@ pub trait JsonValueExt {
         let kind = self.k8s_kind();
         format!("{}/{}", kind.kind.to_lowercase(), self.k8s_name().unwrap_or_default())
     }
+
+    /// Kind, namespace and name
+    fn k8s_id(&self) -> ObjectId {
+        ObjectId {
+            kind: self.k8s_kind(),
+            namespace: self.k8s_namespace().map(|s| s.to_owned()),
+            name: self.k8s_name().unwrap_or_default().to_owned(),
+        }
+    }
 }
 
 impl JsonValueExt for JsonValue {
@@ -130,6 +196,85 @@ impl JsonValueExt for JsonValue {
     fn k8s_namespace(&self) -> Option<&str> { self["metadata"]["namespace"].as_str() }
 }
 
+/// The name of `o`, or an error describing as much of its identity
+/// as it has.
+pub fn require_name(o: &JsonValue) -> Result<&str> {
+    match o.k8s_name() {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => {
+            let kind = o.k8s_kind();
+            let mut what = format!("{} ({})", kind.kind, kind.api_version);
+            if let Some(ns) = o.k8s_namespace() {
+                what.push_str(&format!(" in namespace {}", ns));
+            }
+            if let Some(prefix) = o["metadata"]["generateName"].as_str() {
+                what.push_str(&format!(" with generateName {}", prefix));
+            }
+            Err(ErrorKind::MissingName(what).into())
+        },
+    }
+}
+
+#[test]
+fn test_require_name() {
+    let mut o = object!{
+        "apiVersion" => "example.com/v1",
+        "kind" => "Widget",
+        "metadata" => object!{"name" => "foo"}
+    };
+    assert_eq!(require_name(&o).unwrap(), "foo");
+
+    o["metadata"]["name"] = "".into();
+    assert_eq!(require_name(&o).unwrap_err().to_string(),
+               "Widget (example.com/v1) has no metadata.name");
+
+    o["metadata"].remove("name");
+    o["metadata"]["generateName"] = "foo-".into();
+    assert_eq!(require_name(&o).unwrap_err().to_string(),
+               "Widget (example.com/v1) with generateName foo- has no metadata.name");
+}
+
+/// Replace anything that isn't safe in a filename
+pub fn filename_safe(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+/// A filename stem (no extension) derived from object identity,
+/// eg: `myns-deployment-foo`.
+pub fn object_filename(v: &JsonValue) -> String {
+    let mut parts = vec![];
+    if let Some(ns) = v.k8s_namespace() {
+        parts.push(filename_safe(ns));
+    }
+    parts.push(filename_safe(&v.k8s_kind().kind.to_lowercase()));
+    parts.push(filename_safe(v.k8s_name().unwrap_or_default()));
+    parts.join("-")
+}
+
+#[test]
+fn test_object_filename() {
+    let o = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "metadata" => object!{
+            "name" => "foo",
+            "namespace" => "myns"
+        }
+    };
+    assert_eq!(object_filename(&o), "myns-deployment-foo");
+
+    let o = object!{
+        "apiVersion" => "rbac.authorization.k8s.io/v1",
+        "kind" => "ClusterRole",
+        "metadata" => object!{
+            "name" => "system:foo"
+        }
+    };
+    assert_eq!(object_filename(&o), "clusterrole-system_foo");
+}
+
 fn is_potential_pod_dependency(v: &JsonValue) -> bool {
     v.is_k8s_kind(V1_SERVICE) ||
         v.is_k8s_kind(V1_CONFIGMAP) ||
@@ -142,6 +287,11 @@ pub fn flatten_list(v: &JsonValue) -> Vec<&JsonValue> {
     ::std::iter::once(v)
         .flat_map(|item| {
             if item.is_k8s_kind(V1_LIST) {
+                let extra = list_extra_keys(item);
+                if !extra.is_empty() {
+                    warn!("Ignoring unexpected field(s) {} in List. Did you mean to output a single object?",
+                          extra.join(", "));
+                }
                 item["items"].members().collect()
             } else {
                 vec![item]
@@ -149,6 +299,803 @@ pub fn flatten_list(v: &JsonValue) -> Vec<&JsonValue> {
         }).collect()
 }
 
+/// Top-level fields of a v1.List other than the ones a List has.
+/// These are ignored when the List is expanded.
+fn list_extra_keys(v: &JsonValue) -> Vec<&str> {
+    v.entries()
+        .map(|(k, _)| k)
+        .filter(|k| !["apiVersion", "kind", "items", "metadata"].contains(k))
+        .collect()
+}
+
+#[test]
+fn test_list_extra_keys() {
+    let list = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "metadata" => object!{},
+        "items" => array![object!{"apiVersion" => "v1", "kind" => "ConfigMap"}]
+    };
+    assert!(list_extra_keys(&list).is_empty());
+
+    let mut malformed = list.clone();
+    malformed["spec"] = object!{"replicas" => 1};
+    assert_eq!(list_extra_keys(&malformed), vec!["spec"]);
+    assert_eq!(flatten_list(&malformed).len(), 1);
+}
+
+/// Remove fields that are populated by the server, leaving something
+/// resembling the original manifest.
+pub fn strip_server_fields(v: &mut JsonValue) {
+    v.remove("status");
+    for field in &["uid", "selfLink", "resourceVersion", "generation",
+                   "creationTimestamp", "managedFields"] {
+        v["metadata"].remove(field);
+    }
+}
+
+/// A copy of `v` with object keys sorted and null fields removed, so
+/// that equivalent values serialise identically.
+pub fn normalize(v: &JsonValue) -> JsonValue {
+    match *v {
+        JsonValue::Object(ref o) => {
+            let mut entries: Vec<_> = o.iter()
+                .filter(|&(_, v)| !v.is_null())
+                .collect();
+            entries.sort_by_key(|&(k, _)| k);
+
+            let mut res = JsonValue::new_object();
+            for (k, v) in entries {
+                res[k] = normalize(v);
+            }
+            res
+        },
+        JsonValue::Array(ref a) => JsonValue::Array(a.iter().map(normalize).collect()),
+        ref v => v.clone(),
+    }
+}
+
+#[test]
+fn test_normalize() {
+    let v = object!{
+        "b" => array![object!{"y" => 1, "x" => JsonValue::Null}],
+        "a" => "s",
+        "c" => JsonValue::Null
+    };
+    assert_eq!(normalize(&v).dump(), r#"{"a":"s","b":[{"y":1}]}"#);
+}
+
+/// A copy of `v` with object keys sorted, at every level
+pub fn sort_keys(v: &JsonValue) -> JsonValue {
+    match *v {
+        JsonValue::Object(ref o) => {
+            let mut entries: Vec<_> = o.iter().collect();
+            entries.sort_by_key(|&(k, _)| k);
+
+            let mut res = JsonValue::new_object();
+            for (k, v) in entries {
+                res[k] = sort_keys(v);
+            }
+            res
+        },
+        JsonValue::Array(ref a) => JsonValue::Array(a.iter().map(sort_keys).collect()),
+        ref v => v.clone(),
+    }
+}
+
+/// Order the items of a List (or array) by group, version, kind,
+/// namespace and name.
+pub fn sort_objects(v: &mut JsonValue) {
+    let items = if v.is_k8s_kind(V1_LIST) { &mut v["items"] } else { v };
+    if let JsonValue::Array(ref mut a) = *items {
+        a.sort_by_cached_key(|o| o.k8s_id());
+    }
+}
+
+#[test]
+fn test_sort() {
+    let o = |api_version: &str, kind: &str, ns: Option<&str>, name: &str| {
+        let mut o = object!{"metadata" => object!{"name" => name}, "kind" => kind, "apiVersion" => api_version};
+        if let Some(ns) = ns {
+            o["metadata"]["namespace"] = ns.into();
+        }
+        o
+    };
+    let mut list = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![
+            o("v1", "Service", Some("b"), "web"),
+            o("apps/v1", "Deployment", Some("a"), "web"),
+            o("v1", "Service", Some("a"), "web"),
+            o("v1", "ConfigMap", None, "z"),
+            o("v1", "ConfigMap", None, "a")
+        ]
+    };
+    sort_objects(&mut list);
+    let ids: Vec<_> = list["items"].members().map(|o| o.k8s_id().to_string()).collect();
+    assert_eq!(ids, vec![
+        "configmap/a",
+        "configmap/z",
+        "service/web in namespace a",
+        "service/web in namespace b",
+        "deployment/web in namespace a",
+    ]);
+
+    let v = object!{"b" => array![object!{"y" => 1, "x" => JsonValue::Null}], "a" => "s"};
+    assert_eq!(sort_keys(&v).dump(), r#"{"a":"s","b":[{"x":null,"y":1}]}"#);
+    assert_eq!(sort_keys(&list["items"][0]).dump(),
+               r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"a"}}"#);
+}
+
+/// Fields that lists of objects are commonly merged by in strategic
+/// merge patches, most specific first.
+const STRATEGIC_MERGE_KEYS: &'static [&'static str] = &["name", "mountPath", "containerPort", "port"];
+
+/// Apply `patch` to `target` as a strategic merge patch, as far as
+/// can be told without the schema: a list of objects that all have
+/// one of `STRATEGIC_MERGE_KEYS` is merged element by element on that
+/// key (as for containers or env), and other lists are replaced.
+/// `$patch` directives are not supported.
+pub fn strategic_merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    match *patch {
+        JsonValue::Object(ref p) => {
+            if !target.is_object() {
+                *target = JsonValue::new_object();
+            }
+            for (k, v) in p.iter() {
+                if v.is_null() {
+                    target.remove(k);
+                } else {
+                    strategic_merge_patch(&mut target[k], v);
+                }
+            }
+        },
+        JsonValue::Array(ref p) => {
+            let key = STRATEGIC_MERGE_KEYS.iter().find(|&&key| {
+                p.iter().chain(target.members()).all(|e| e[key].is_string() || e[key].is_number())
+            });
+            match (key, target.is_array()) {
+                (Some(&key), true) if !p.is_empty() => for e in p {
+                    match target.members_mut().find(|t| t[key] == e[key]) {
+                        Some(t) => strategic_merge_patch(t, e),
+                        None => target.push(e.clone()).unwrap(),
+                    }
+                },
+                _ => *target = patch.clone(),
+            }
+        },
+        _ => *target = patch.clone(),
+    }
+}
+
+#[test]
+fn test_strategic_merge_patch() {
+    let mut target = object!{
+        "containers" => array![
+            object!{"name" => "a", "image" => "a:1", "imagePullPolicy" => "Always",
+                    "env" => array![object!{"name" => "X", "value" => "1"}, object!{"name" => "Y", "value" => "2"}]},
+            object!{"name" => "b", "image" => "b:1"}
+        ],
+        "args" => array!["--foo"]
+    };
+    strategic_merge_patch(&mut target, &object!{
+        "containers" => array![
+            object!{"name" => "a", "image" => "a:2", "env" => array![object!{"name" => "X", "value" => "3"}]},
+            object!{"name" => "c", "image" => "c:1"}
+        ],
+        "args" => array!["--bar"]
+    });
+    assert_eq!(target, object!{
+        "containers" => array![
+            object!{"name" => "a", "image" => "a:2", "imagePullPolicy" => "Always",
+                    "env" => array![object!{"name" => "X", "value" => "3"}, object!{"name" => "Y", "value" => "2"}]},
+            object!{"name" => "b", "image" => "b:1"},
+            object!{"name" => "c", "image" => "c:1"}
+        ],
+        "args" => array!["--bar"]
+    });
+}
+
+/// Apply `patch` to `target` as a JSON merge patch (RFC 7386), as
+/// the server does for `application/merge-patch+json`.
+pub fn merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    if let JsonValue::Object(ref p) = *patch {
+        if !target.is_object() {
+            *target = JsonValue::new_object();
+        }
+        for (k, v) in p.iter() {
+            if v.is_null() {
+                target.remove(k);
+            } else {
+                merge_patch(&mut target[k], v);
+            }
+        }
+    } else {
+        *target = patch.clone();
+    }
+}
+
+#[test]
+fn test_merge_patch() {
+    let mut target = object!{
+        "a" => "b",
+        "c" => object!{"d" => "e", "f" => "g"},
+        "list" => array![1, 2, 3]
+    };
+    let patch = object!{
+        "a" => "z",
+        "c" => object!{"f" => JsonValue::Null},
+        "list" => array![4],
+        "new" => object!{"x" => 1}
+    };
+    merge_patch(&mut target, &patch);
+    assert_eq!(target, object!{
+        "a" => "z",
+        "c" => object!{"d" => "e"},
+        "list" => array![4],
+        "new" => object!{"x" => 1}
+    });
+
+    let mut target = "scalar".into();
+    merge_patch(&mut target, &object!{"a" => 1, "b" => JsonValue::Null});
+    assert_eq!(target, object!{"a" => 1});
+}
+
+/// Whether applying `patch` to `live` as a `patch_type` patch would be
+/// a no-op.  For merge patches lists are replaced wholesale, so a list
+/// whose elements have server-defaulted fields (eg a container's
+/// `imagePullPolicy`) never counts as applied.
+pub fn is_applied(patch: &JsonValue, live: &JsonValue, patch_type: PatchType) -> bool {
+    let mut patched = live.clone();
+    patch_type.apply(&mut patched, patch);
+    patched == *live
+}
+
+/// The paths (eg `spec.containers[0].image`) of fields set by merge
+/// patch `patch` that `live` doesn't match.  List elements are
+/// compared the same way, so fields the server defaults within them
+/// don't count as a difference.
+pub fn unapplied_fields(patch: &JsonValue, live: &JsonValue) -> Vec<String> {
+    let mut res = vec![];
+    collect_unapplied(patch, live, "", &mut res);
+    res
+}
+
+fn collect_unapplied(patch: &JsonValue, live: &JsonValue, path: &str, res: &mut Vec<String>) {
+    let child = |k: &str| if path.is_empty() { k.to_owned() } else { format!("{}.{}", path, k) };
+    match (patch, live) {
+        (JsonValue::Object(p), JsonValue::Object(_)) =>
+            for (k, v) in p.iter() {
+                if v.is_null() {
+                    if live.has_key(k) {
+                        res.push(child(k));
+                    }
+                } else {
+                    collect_unapplied(v, &live[k], &child(k), res);
+                }
+            },
+        (JsonValue::Array(p), JsonValue::Array(l)) if p.len() == l.len() =>
+            for (i, (p, l)) in p.iter().zip(l).enumerate() {
+                collect_unapplied(p, l, &format!("{}[{}]", path, i), res);
+            },
+        (p, l) => if p != l {
+            res.push(path.to_owned());
+        },
+    }
+}
+
+/// The field paths owned by each manager in `v`'s
+/// `metadata.managedFields`, in the order managers first appear.
+/// Entries for the same manager (eg `Apply` and `Update`) are merged.
+pub fn field_owners(v: &JsonValue) -> Vec<(String, Vec<String>)> {
+    let mut res: Vec<(String, Vec<String>)> = vec![];
+    for entry in v["metadata"]["managedFields"].members() {
+        let manager = entry["manager"].as_str().unwrap_or("<unknown>");
+        let mut paths = vec![];
+        collect_owned_fields(&entry["fieldsV1"], "", &mut paths);
+
+        let i = match res.iter().position(|(m, _)| m == manager) {
+            Some(i) => i,
+            None => {
+                res.push((manager.to_owned(), vec![]));
+                res.len() - 1
+            },
+        };
+        for p in paths {
+            if !res[i].1.contains(&p) {
+                res[i].1.push(p);
+            }
+        }
+    }
+    res
+}
+
+/// Walk a `fieldsV1` set, eg `{"f:data": {"f:a": {}}}`, collecting
+/// the paths of its members.
+fn collect_owned_fields(fields: &JsonValue, path: &str, res: &mut Vec<String>) {
+    if fields.is_empty() && !path.is_empty() {
+        res.push(path.to_owned());
+    }
+    for (k, v) in fields.entries() {
+        let child = if k == "." {
+            res.push(path.to_owned());
+            continue;
+        } else if let Some(name) = k.strip_prefix("f:") {
+            if path.is_empty() { name.to_owned() } else { format!("{}.{}", path, name) }
+        } else if let Some(key) = k.strip_prefix("k:") {
+            let key = ::json::parse(key).unwrap_or(JsonValue::Null);
+            let kvs: Vec<_> = key.entries().map(|(k, v)| format!("{}={}", k, v)).collect();
+            format!("{}[{}]", path, kvs.join(","))
+        } else if let Some(value) = k.strip_prefix("v:") {
+            format!("{}[={}]", path, value)
+        } else if let Some(i) = k.strip_prefix("i:") {
+            format!("{}[{}]", path, i)
+        } else {
+            format!("{}.{}", path, k)
+        };
+        collect_owned_fields(v, &child, res);
+    }
+}
+
+#[test]
+fn test_field_owners() {
+    let fields = |s: &str| ::json::parse(s).unwrap();
+    let v = object!{
+        "metadata" => object!{
+            "managedFields" => array![
+                object!{
+                    "manager" => "kubecfg",
+                    "operation" => "Update",
+                    "fieldsV1" => fields(r#"{"f:data": {"f:a": {}}, "f:spec": {"f:containers": {"k:{\"name\":\"app\"}": {".": {}, "f:image": {}}}}}"#)
+                },
+                object!{
+                    "manager" => "kube-controller-manager",
+                    "operation" => "Update",
+                    "fieldsV1" => fields(r#"{"f:metadata": {"f:finalizers": {"v:\"foo\"": {}}}}"#)
+                },
+                object!{
+                    "manager" => "kubecfg",
+                    "operation" => "Apply",
+                    "fieldsV1" => fields(r#"{"f:data": {"f:a": {}, "f:b": {}}}"#)
+                }
+            ]
+        }
+    };
+    assert_eq!(field_owners(&v), vec![
+        ("kubecfg".to_owned(), vec![
+            "data.a".to_owned(),
+            "spec.containers[name=app]".to_owned(),
+            "spec.containers[name=app].image".to_owned(),
+            "data.b".to_owned(),
+        ]),
+        ("kube-controller-manager".to_owned(), vec![
+            "metadata.finalizers[=\"foo\"]".to_owned(),
+        ]),
+    ]);
+    assert!(field_owners(&object!{"metadata" => object!{}}).is_empty());
+}
+
+#[test]
+fn test_is_applied() {
+    let live = object!{
+        "metadata" => object!{"name" => "foo", "resourceVersion" => "42"},
+        "spec" => object!{
+            "replicas" => 2,
+            "containers" => array![object!{"name" => "web", "image" => "nginx", "imagePullPolicy" => "Always"}]
+        }
+    };
+    let merge = |patch: JsonValue| is_applied(&patch, &live, PatchType::Merge);
+    let strategic = |patch: JsonValue| is_applied(&patch, &live, PatchType::Strategic);
+
+    assert!(merge(object!{
+        "metadata" => object!{"name" => "foo"},
+        "spec" => object!{"replicas" => 2},
+        "gone" => JsonValue::Null
+    }));
+    assert!(merge(object!{
+        "spec" => object!{"containers" => array![object!{"name" => "web", "image" => "nginx", "imagePullPolicy" => "Always"}]}
+    }));
+
+    // A merge patch replaces lists, so a field removed from a
+    // container is a change.  A strategic patch merges containers by
+    // name, so leaves the field alone.
+    let removed = object!{"spec" => object!{"containers" => array![object!{"name" => "web", "image" => "nginx"}]}};
+    assert!(!merge(removed.clone()));
+    assert!(strategic(removed));
+    assert!(!strategic(object!{"spec" => object!{"containers" => array![object!{"name" => "web", "image" => "nginx:2"}]}}));
+
+    assert!(!merge(object!{"spec" => object!{"replicas" => 3}}));
+    assert!(!merge(object!{"spec" => object!{"paused" => true}}));
+    assert!(!merge(object!{"spec" => object!{"replicas" => JsonValue::Null}}));
+    assert!(!merge(object!{"spec" => object!{"containers" => array![]}}));
+
+    let patch = object!{
+        "metadata" => object!{"name" => "foo", "labels" => object!{"team" => "a"}},
+        "spec" => object!{"containers" => array![object!{"image" => "nginx:1.25"}]}
+    };
+    assert_eq!(unapplied_fields(&patch, &live), vec!["metadata.labels", "spec.containers[0].image"]);
+}
+
+/// Apply `f` to each non-list item, as would be returned by `flatten_list`
+pub fn for_each_object_mut<F>(v: &mut JsonValue, mut f: F)
+    where F: FnMut(&mut JsonValue)
+{
+    if v.is_k8s_kind(V1_LIST) {
+        for item in v["items"].members_mut() {
+            f(item);
+        }
+    } else {
+        f(v);
+    }
+}
+
+fn pod_spec_path(v: &JsonValue) -> &'static [&'static str] {
+    if v["kind"] == "Pod" {
+        &["spec"]
+    } else if v["kind"] == "CronJob" {
+        &["spec", "jobTemplate", "spec", "template", "spec"]
+    } else {
+        &["spec", "template", "spec"]
+    }
+}
+
+/// The pod spec within a Pod or workload object, if any
+pub fn pod_spec(v: &JsonValue) -> Option<&JsonValue> {
+    let spec = pod_spec_path(v).iter().fold(v, |v, &k| &v[k]);
+    if spec.is_object() { Some(spec) } else { None }
+}
+
+fn pod_spec_mut(v: &mut JsonValue) -> Option<&mut JsonValue> {
+    // Check first: IndexMut would create missing fields
+    pod_spec(v)?;
+    let path = pod_spec_path(v);
+    Some(path.iter().fold(v, |v, &k| &mut v[k]))
+}
+
+/// A ConfigMap or Secret that a pod spec requires to exist
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct ConfigRef {
+    pub kind: (&'static str, &'static str),
+    pub name: String,
+}
+
+/// The (non-optional) ConfigMaps and Secrets referenced by `spec`,
+/// through environment variables or volumes.
+pub fn config_refs(spec: &JsonValue) -> Vec<ConfigRef> {
+    let mut refs = vec![];
+    {
+        let mut add = |kind, r: &JsonValue, name_field: &str| {
+            if r.is_object() && r["optional"] != true {
+                if let Some(name) = r[name_field].as_str() {
+                    refs.push(ConfigRef { kind, name: name.to_owned() });
+                }
+            }
+        };
+
+        for c in spec["initContainers"].members().chain(spec["containers"].members()) {
+            for e in c["envFrom"].members() {
+                add(V1_CONFIGMAP, &e["configMapRef"], "name");
+                add(V1_SECRET, &e["secretRef"], "name");
+            }
+            for e in c["env"].members() {
+                add(V1_CONFIGMAP, &e["valueFrom"]["configMapKeyRef"], "name");
+                add(V1_SECRET, &e["valueFrom"]["secretKeyRef"], "name");
+            }
+        }
+
+        for vol in spec["volumes"].members() {
+            add(V1_CONFIGMAP, &vol["configMap"], "name");
+            add(V1_SECRET, &vol["secret"], "secretName");
+            for src in vol["projected"]["sources"].members() {
+                add(V1_CONFIGMAP, &src["configMap"], "name");
+                add(V1_SECRET, &src["secret"], "name");
+            }
+        }
+    }
+
+    refs.sort();
+    refs.dedup();
+    refs
+}
+
+#[test]
+fn test_config_refs() {
+    let spec = object!{
+        "containers" => array![
+            object!{
+                "name" => "app",
+                "envFrom" => array![
+                    object!{"configMapRef" => object!{"name" => "app-env"}},
+                    object!{"secretRef" => object!{"name" => "maybe", "optional" => true}}
+                ],
+                "env" => array![
+                    object!{"name" => "PLAIN", "value" => "x"},
+                    object!{"name" => "PASSWORD", "valueFrom" => object!{
+                        "secretKeyRef" => object!{"name" => "db", "key" => "password"}
+                    }}
+                ]
+            }
+        ],
+        "volumes" => array![
+            object!{"name" => "config", "configMap" => object!{"name" => "app-env"}},
+            object!{"name" => "tls", "secret" => object!{"secretName" => "tls"}},
+            object!{"name" => "all", "projected" => object!{"sources" => array![
+                object!{"configMap" => object!{"name" => "extra"}}
+            ]}}
+        ]
+    };
+
+    let refs: Vec<_> = config_refs(&spec).into_iter()
+        .map(|r| (r.kind.1, r.name))
+        .collect();
+    assert_eq!(refs, vec![
+        ("ConfigMap", "app-env".to_owned()),
+        ("ConfigMap", "extra".to_owned()),
+        ("Secret", "db".to_owned()),
+        ("Secret", "tls".to_owned()),
+    ]);
+}
+
+/// The labels applied to pods created from Pod or workload `v`
+fn pod_labels(v: &JsonValue) -> &JsonValue {
+    let path = pod_spec_path(v);
+    let template = path[..path.len() - 1].iter().fold(v, |v, &k| &v[k]);
+    &template["metadata"]["labels"]
+}
+
+/// A relationship from one object to another
+#[derive(Debug,Clone,PartialEq)]
+pub struct Reference {
+    pub from: ObjectId,
+    pub to: ObjectId,
+    /// eg "uses", "selects"
+    pub relation: &'static str,
+}
+
+/// The relationships between `objects`: Services to the workloads
+/// they select, workloads to the ConfigMaps, Secrets and
+/// PersistentVolumeClaims they use, and objects to their owners.
+/// Used objects need not be in `objects`.
+pub fn references(objects: &[&JsonValue]) -> Vec<Reference> {
+    let mut res = vec![];
+    for o in objects {
+        let id = o.k8s_id();
+        let same_ns = |kind: K8sKind, name: &str| ObjectId {
+            kind,
+            namespace: id.namespace.clone(),
+            name: name.to_owned(),
+        };
+        let mut add = |to: ObjectId, relation| res.push(Reference { from: id.clone(), to, relation });
+
+        if let Some(spec) = pod_spec(o) {
+            for r in config_refs(spec) {
+                add(same_ns(K8sKind::new(r.kind.0, r.kind.1), &r.name), "uses");
+            }
+            for vol in spec["volumes"].members() {
+                if let Some(claim) = vol["persistentVolumeClaim"]["claimName"].as_str() {
+                    add(same_ns(K8sKind::new(V1_PVC.0, V1_PVC.1), claim), "mounts");
+                }
+            }
+        }
+
+        if o.is_k8s_kind(V1_SERVICE) && !o["spec"]["selector"].is_empty() {
+            let selector = &o["spec"]["selector"];
+            for w in objects {
+                if pod_spec(w).is_some() && w.k8s_namespace() == o.k8s_namespace() &&
+                    selector.entries().all(|(k, v)| pod_labels(w)[k] == *v)
+                {
+                    add(w.k8s_id(), "selects");
+                }
+            }
+        }
+
+        for owner in o["metadata"]["ownerReferences"].members() {
+            let kind = K8sKind::new(owner["apiVersion"].as_str().unwrap_or_default(),
+                                    owner["kind"].as_str().unwrap_or_default());
+            add(same_ns(kind, owner["name"].as_str().unwrap_or_default()), "owned by");
+        }
+    }
+    res
+}
+
+#[test]
+fn test_references() {
+    let deploy = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "metadata" => object!{"name" => "web", "namespace" => "myns"},
+        "spec" => object!{"template" => object!{
+            "metadata" => object!{"labels" => object!{"app" => "web", "tier" => "frontend"}},
+            "spec" => object!{
+                "containers" => array![object!{
+                    "name" => "web",
+                    "envFrom" => array![object!{"configMapRef" => object!{"name" => "web-env"}}]
+                }],
+                "volumes" => array![object!{"name" => "data", "persistentVolumeClaim" => object!{"claimName" => "data"}}]
+            }
+        }}
+    };
+    let svc = object!{
+        "apiVersion" => "v1",
+        "kind" => "Service",
+        "metadata" => object!{"name" => "web", "namespace" => "myns"},
+        "spec" => object!{"selector" => object!{"app" => "web"}}
+    };
+    let other_svc = object!{
+        "apiVersion" => "v1",
+        "kind" => "Service",
+        "metadata" => object!{"name" => "db", "namespace" => "myns"},
+        "spec" => object!{"selector" => object!{"app" => "db"}}
+    };
+    let cm = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{
+            "name" => "web-env",
+            "namespace" => "myns",
+            "ownerReferences" => array![object!{"apiVersion" => "apps/v1", "kind" => "Deployment", "name" => "web"}]
+        }
+    };
+
+    let tname = |id: &ObjectId| format!("{}/{}", id.kind.kind.to_lowercase(), id.name);
+    let refs: Vec<_> = references(&[&deploy, &svc, &other_svc, &cm]).iter()
+        .map(|r| format!("{} {} {}", tname(&r.from), r.relation, tname(&r.to)))
+        .collect();
+    assert_eq!(refs, vec![
+        "deployment/web uses configmap/web-env",
+        "deployment/web mounts persistentvolumeclaim/data",
+        "service/web selects deployment/web",
+        "configmap/web-env owned by deployment/web",
+    ]);
+}
+
+/// Replace the image of any (init)containers named in `images`.
+pub fn set_images(v: &mut JsonValue, images: &[(&str, &str)]) {
+    if let Some(spec) = pod_spec_mut(v) {
+        for field in &["initContainers", "containers"] {
+            if !spec.has_key(field) {
+                continue;
+            }
+            for c in spec[*field].members_mut() {
+                let image = images.iter()
+                    .find(|&&(name, _)| c["name"] == name)
+                    .map(|&(_, image)| image);
+                if let Some(image) = image {
+                    c["image"] = image.into();
+                }
+            }
+        }
+    }
+}
+
+/// Add `annotations` to object `v`.  Existing annotations are only
+/// replaced if `overwrite` is set.
+pub fn set_annotations(v: &mut JsonValue, annotations: &[(&str, String)], overwrite: bool) {
+    let existing = &mut v["metadata"]["annotations"];
+    for &(key, ref value) in annotations {
+        if overwrite || !existing.has_key(key) {
+            existing[key] = value.clone().into();
+        }
+    }
+}
+
+#[test]
+fn test_set_annotations() {
+    let mut o = object!{
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "annotations" => object!{"owner" => "alice"}}
+    };
+    let annotations = vec![("owner", "ci".to_owned()), ("deployed-by", "ci".to_owned())];
+
+    set_annotations(&mut o, &annotations, false);
+    assert_eq!(o["metadata"]["annotations"], object!{"owner" => "alice", "deployed-by" => "ci"});
+
+    set_annotations(&mut o, &annotations, true);
+    assert_eq!(o["metadata"]["annotations"], object!{"owner" => "ci", "deployed-by" => "ci"});
+
+    let mut o = object!{"kind" => "ConfigMap", "metadata" => object!{"name" => "bar"}};
+    set_annotations(&mut o, &annotations, false);
+    assert_eq!(o["metadata"]["annotations"]["owner"], "ci");
+}
+
+#[test]
+fn test_set_images() {
+    let mut o = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "spec" => object!{
+            "template" => object!{
+                "spec" => object!{
+                    "initContainers" => array![
+                        object!{"name" => "init", "image" => "busybox"}
+                    ],
+                    "containers" => array![
+                        object!{"name" => "app", "image" => "app:v1"},
+                        object!{"name" => "sidecar", "image" => "sidecar:v1"}
+                    ]
+                }
+            }
+        }
+    };
+    set_images(&mut o, &[("app", "app:v2"), ("init", "busybox:1.36")]);
+
+    let spec = &o["spec"]["template"]["spec"];
+    assert_eq!(spec["initContainers"][0]["image"], "busybox:1.36");
+    assert_eq!(spec["containers"][0]["image"], "app:v2");
+    assert_eq!(spec["containers"][1]["image"], "sidecar:v1");
+
+    let mut o = object!{
+        "apiVersion" => "v1",
+        "kind" => "Pod",
+        "spec" => object!{
+            "containers" => array![object!{"name" => "app", "image" => "app:v1"}]
+        }
+    };
+    set_images(&mut o, &[("app", "app:v2")]);
+    assert_eq!(o["spec"]["containers"][0]["image"], "app:v2");
+    assert!(!o["spec"].has_key("initContainers"));
+
+    let mut o = object!{"apiVersion" => "v1", "kind" => "ConfigMap"};
+    set_images(&mut o, &[("app", "app:v2")]);
+    assert_eq!(o, object!{"apiVersion" => "v1", "kind" => "ConfigMap"});
+}
+
+pub const LAST_APPLIED_ANNOTATION: &'static str = "kubectl.kubernetes.io/last-applied-configuration";
+
+/// The configuration last applied to a live object by `kubectl
+/// apply`, if it has one.
+pub fn last_applied(v: &JsonValue) -> Option<JsonValue> {
+    let text = v["metadata"]["annotations"][LAST_APPLIED_ANNOTATION].as_str()?;
+    match ::json::parse(text) {
+        Ok(applied) => Some(applied),
+        Err(e) => {
+            warn!("Ignoring invalid {} on {}: {}", LAST_APPLIED_ANNOTATION, v.k8s_tname(), e);
+            None
+        },
+    }
+}
+
+#[test]
+fn test_last_applied() {
+    let applied = object!{"apiVersion" => "v1", "kind" => "ConfigMap", "data" => object!{"a" => "b"}};
+    let mut live = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo"}
+    };
+    assert_eq!(last_applied(&live), None);
+
+    live["metadata"]["annotations"] = object!{LAST_APPLIED_ANNOTATION => applied.dump()};
+    assert_eq!(last_applied(&live), Some(applied));
+
+    live["metadata"]["annotations"][LAST_APPLIED_ANNOTATION] = "{".into();
+    assert_eq!(last_applied(&live), None);
+}
+
+/// True if the kind of `v` is in `include` (or `include` is empty)
+/// and not in `exclude`.  Kinds are compared case-insensitively.
+pub fn kind_selected(v: &JsonValue, include: &[&str], exclude: &[&str]) -> bool {
+    let kind = v.k8s_kind().kind;
+    let matches = |k: &&str| k.eq_ignore_ascii_case(&kind);
+
+    (include.is_empty() || include.iter().any(&matches)) &&
+        !exclude.iter().any(&matches)
+}
+
+#[test]
+fn test_kind_selected() {
+    let cm = object!{"apiVersion" => "v1", "kind" => "ConfigMap"};
+    let svc = object!{"apiVersion" => "v1", "kind" => "Service"};
+
+    assert!(kind_selected(&cm, &[], &[]));
+    assert!(kind_selected(&cm, &["configmap"], &[]));
+    assert!(!kind_selected(&svc, &["configmap"], &[]));
+    assert!(!kind_selected(&cm, &[], &["ConfigMap"]));
+    // exclude wins
+    assert!(!kind_selected(&cm, &["ConfigMap"], &["configmap"]));
+}
+
 /// Sort key for dependency-first sorting
 #[inline]
 pub fn dep_first(v: &JsonValue) -> u8 {
@@ -165,11 +1112,149 @@ pub fn dep_first(v: &JsonValue) -> u8 {
     rank as u8
 }
 
+/// How the garbage collector should treat dependents of a deleted object
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum PropagationPolicy {
+    Orphan,
+    Background,
+    Foreground,
+}
+
+impl FromStr for PropagationPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<PropagationPolicy> {
+        match s {
+            "Orphan" => Ok(PropagationPolicy::Orphan),
+            "Background" => Ok(PropagationPolicy::Background),
+            "Foreground" => Ok(PropagationPolicy::Foreground),
+            _ => Err(format!("Unknown propagation policy: '{}'", s).into()),
+        }
+    }
+}
+
+impl PropagationPolicy {
+    pub fn variants() -> [&'static str; 3] {
+        ["Orphan", "Background", "Foreground"]
+    }
+
+    pub fn variant(&self) -> &'static str {
+        match *self {
+            PropagationPolicy::Orphan => "Orphan",
+            PropagationPolicy::Background => "Background",
+            PropagationPolicy::Foreground => "Foreground",
+        }
+    }
+}
+
+/// How an update is sent to the server
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum PatchType {
+    /// JSON merge patch (RFC 7386). Lists are replaced wholesale
+    Merge,
+    /// Kubernetes strategic merge patch. Only built-in kinds support it
+    Strategic,
+}
+
+impl FromStr for PatchType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<PatchType> {
+        match s {
+            "merge" => Ok(PatchType::Merge),
+            "strategic" => Ok(PatchType::Strategic),
+            _ => Err(format!("Unknown patch type: '{}'", s).into()),
+        }
+    }
+}
+
+impl PatchType {
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            PatchType::Merge => "application/merge-patch+json",
+            PatchType::Strategic => "application/strategic-merge-patch+json",
+        }
+    }
+
+    /// Apply `patch` to `target` as the server would
+    pub fn apply(&self, target: &mut JsonValue, patch: &JsonValue) {
+        match *self {
+            PatchType::Merge => merge_patch(target, patch),
+            PatchType::Strategic => strategic_merge_patch(target, patch),
+        }
+    }
+
+    /// Parse a `--patch-type-for` value, eg `apps/v1/Deployment=merge`
+    pub fn parse_override(s: &str) -> Result<(K8sKind, PatchType)> {
+        let (kind, strategy) = s.split_once('=')
+            .ok_or_else(|| format!("Expected APIVERSION/KIND=STRATEGY, got '{}'", s))?;
+        let (api_version, kind) = kind.rsplit_once('/')
+            .ok_or_else(|| format!("Expected APIVERSION/KIND, got '{}'", kind))?;
+        Ok((K8sKind::new(api_version, kind), strategy.parse()?))
+    }
+
+    /// The patch type for `kind`, served as `resource`: the first
+    /// matching entry in `overrides`, or else strategic for built-in
+    /// kinds and merge for custom resources (which reject strategic
+    /// patches).  Kinds in groups without a `.` are always built in,
+    /// as CRD groups need one.  Others are only built in if discovery
+    /// found no CRD for them: when unsure, merge works for any kind.
+    pub fn for_kind(kind: &K8sKind, resource: Option<&ApiResource>, overrides: &[(K8sKind, PatchType)]) -> PatchType {
+        if let Some(&(_, t)) = overrides.iter().find(|&(k, _)| k == kind) {
+            return t;
+        }
+        if !kind.api_version.group.contains('.') ||
+            resource.is_some_and(|r| r.custom == Some(false))
+        {
+            PatchType::Strategic
+        } else {
+            PatchType::Merge
+        }
+    }
+}
+
+#[test]
+fn test_patch_type() {
+    let deployment = K8sKind::new("apps/v1", "Deployment");
+    let configmap = K8sKind::new("v1", "ConfigMap");
+    let ingress = K8sKind::new("networking.k8s.io/v1", "Ingress");
+    let crd = K8sKind::new("example.com/v1", "Widget");
+    let gateway = K8sKind::new("gateway.networking.k8s.io/v1", "Gateway");
+    let resource = |custom| ApiResource {
+        name: "things".to_owned(),
+        kind: "Thing".to_owned(),
+        namespaced: true,
+        subresources: vec![],
+        custom,
+    };
+
+    assert_eq!(PatchType::for_kind(&deployment, None, &[]), PatchType::Strategic);
+    assert_eq!(PatchType::for_kind(&configmap, None, &[]), PatchType::Strategic);
+    assert_eq!(PatchType::for_kind(&ingress, Some(&resource(Some(false))), &[]), PatchType::Strategic);
+    assert_eq!(PatchType::for_kind(&crd, Some(&resource(Some(true))), &[]), PatchType::Merge);
+    // A CRD in a k8s.io group, or a group not yet looked up
+    assert_eq!(PatchType::for_kind(&gateway, Some(&resource(Some(true))), &[]), PatchType::Merge);
+    assert_eq!(PatchType::for_kind(&ingress, Some(&resource(None)), &[]), PatchType::Merge);
+    assert_eq!(PatchType::for_kind(&ingress, None, &[]), PatchType::Merge);
+
+    let overrides = vec![
+        PatchType::parse_override("apps/v1/Deployment=merge").unwrap(),
+        PatchType::parse_override("example.com/v1/Widget=strategic").unwrap(),
+    ];
+    assert_eq!(PatchType::for_kind(&deployment, None, &overrides), PatchType::Merge);
+    assert_eq!(PatchType::for_kind(&crd, Some(&resource(Some(true))), &overrides), PatchType::Strategic);
+    assert_eq!(PatchType::for_kind(&configmap, None, &overrides), PatchType::Strategic);
+
+    assert!(PatchType::parse_override("Deployment=merge").is_err());
+    assert!(PatchType::parse_override("apps/v1/Deployment").is_err());
+    assert!(PatchType::parse_override("apps/v1/Deployment=json").is_err());
+}
+
 #[derive(Default,Debug)]
 pub struct DeleteOptions {
     pub orphan_dependents: bool,
     pub grace_period_seconds: Option<u32>,
     pub preconditions: Vec<String>,
+    /// Supersedes `orphan_dependents` when set
+    pub propagation_policy: Option<PropagationPolicy>,
 }
 
 impl From<DeleteOptions> for JsonValue {
@@ -177,10 +1262,15 @@ impl From<DeleteOptions> for JsonValue {
         let mut res = object!{
             "apiVersion" => "v1",
             "kind" => "DeleteOptions",
-            "orphanDependents" => o.orphan_dependents,
             "preconditions" => o.preconditions
         };
 
+        // The server rejects requests that set both
+        match o.propagation_policy {
+            Some(p) => res["propagationPolicy"] = p.variant().into(),
+            None => res["orphanDependents"] = o.orphan_dependents.into(),
+        }
+
         if let Some(n) = o.grace_period_seconds {
             res["gracePeriodSeconds"] = n.into();
         }
@@ -189,6 +1279,27 @@ impl From<DeleteOptions> for JsonValue {
     }
 }
 
+#[test]
+fn test_delete_options() {
+    let o: JsonValue = DeleteOptions {
+        orphan_dependents: false,
+        grace_period_seconds: Some(30),
+        ..Default::default()
+    }.into();
+    assert_eq!(o["orphanDependents"], false);
+    assert_eq!(o["gracePeriodSeconds"], 30);
+    assert!(!o.has_key("propagationPolicy"));
+
+    let o: JsonValue = DeleteOptions {
+        propagation_policy: Some("Foreground".parse().unwrap()),
+        ..Default::default()
+    }.into();
+    assert_eq!(o["propagationPolicy"], "Foreground");
+    assert!(!o.has_key("orphanDependents"));
+
+    assert!("foreground".parse::<PropagationPolicy>().is_err());
+}
+
 fn parse_json(s: &str) -> Result<JsonValue> {
     if s.is_empty() {
         Ok(JsonValue::Null)
@@ -198,14 +1309,214 @@ fn parse_json(s: &str) -> Result<JsonValue> {
     }
 }
 
+fn yaml_to_json(y: &Yaml) -> Result<JsonValue> {
+    Ok(match *y {
+        Yaml::Null => JsonValue::Null,
+        Yaml::Boolean(b) => b.into(),
+        Yaml::Integer(i) => i.into(),
+        Yaml::Real(ref r) => y.as_f64()
+            .ok_or_else(|| format!("Invalid number: {}", r))?
+            .into(),
+        Yaml::String(ref s) => s.as_str().into(),
+        Yaml::Array(ref a) => JsonValue::Array(a.iter().map(yaml_to_json).collect::<Result<_>>()?),
+        Yaml::Hash(ref h) => {
+            let mut o = JsonValue::new_object();
+            for (k, v) in h {
+                let key = match *k {
+                    Yaml::String(ref s) | Yaml::Real(ref s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => bail!("Unsupported YAML mapping key: {:?}", k),
+                };
+                o[key] = yaml_to_json(v)?;
+            }
+            o
+        },
+        Yaml::Alias(_) | Yaml::BadValue => bail!("Unsupported YAML value: {:?}", y),
+    })
+}
+
+/// Parse a single JSON or YAML document, such as a file supplied by
+/// the user.
+pub fn parse_json_or_yaml(s: &str) -> Result<JsonValue> {
+    if let Ok(v) = ::json::parse(s) {
+        return Ok(v);
+    }
+
+    let docs = YamlLoader::load_from_str(s)
+        .chain_err(|| "Unable to parse as JSON or YAML")?;
+    match docs.len() {
+        0 => Ok(JsonValue::Null),
+        1 => yaml_to_json(&docs[0]),
+        n => bail!("Expected a single document, found {}", n),
+    }
+}
+
+#[test]
+fn test_parse_json_or_yaml() {
+    assert_eq!(parse_json_or_yaml(r#"{"a": [1, "b"]}"#).unwrap(),
+               object!{"a" => array![1, "b"]});
+
+    let yaml = "kind: DeleteOptions\ngracePeriodSeconds: 30\npreconditions:\n  uid: abc\nratio: 0.5\ndryRun: [All]\n";
+    assert_eq!(parse_json_or_yaml(yaml).unwrap(), object!{
+        "kind" => "DeleteOptions",
+        "gracePeriodSeconds" => 30,
+        "preconditions" => object!{"uid" => "abc"},
+        "ratio" => 0.5,
+        "dryRun" => array!["All"]
+    });
+
+    assert!(parse_json_or_yaml("a: 1\n---\nb: 2\n").is_err());
+    assert!(parse_json_or_yaml("a: [").is_err());
+}
+
+/// Parse a JSON document or YAML stream of Kubernetes manifests.  A
+/// stream with more than one document becomes a v1.List of all the
+/// objects, with any Lists among them flattened.
+pub fn parse_manifests(s: &str) -> Result<JsonValue> {
+    if let Ok(v) = ::json::parse(s) {
+        return Ok(v);
+    }
+
+    let docs = YamlLoader::load_from_str(s)
+        .chain_err(|| "Unable to parse as JSON or YAML")?;
+    if docs.len() == 1 {
+        return yaml_to_json(&docs[0]);
+    }
+    let mut items = vec![];
+    for doc in &docs {
+        let v = yaml_to_json(doc)?;
+        items.extend(flatten_list(&v).into_iter().filter(|o| !o.is_null()).cloned());
+    }
+    Ok(object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => JsonValue::Array(items)
+    })
+}
+
+#[test]
+fn test_parse_manifests() {
+    assert_eq!(parse_manifests(r#"{"kind": "List", "items": []}"#).unwrap(),
+               object!{"kind" => "List", "items" => array![]});
+    assert_eq!(parse_manifests("a: 1\n").unwrap(), object!{"a" => 1});
+
+    let yaml = "
+apiVersion: v1
+kind: ConfigMap
+metadata: {name: a}
+---
+# only a comment
+---
+apiVersion: v1
+kind: List
+items:
+- {apiVersion: v1, kind: Secret, metadata: {name: b}}
+- {apiVersion: v1, kind: Secret, metadata: {name: c}}
+";
+    let list = parse_manifests(yaml).unwrap();
+    let names: Vec<_> = flatten_list(&list).iter()
+        .map(|o| o["metadata"]["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+
+    assert!(parse_manifests("a: [").is_err());
+}
+
+/// A `Status` object, returned by the apiserver in place of a
+/// resource (eg: from DELETE) and on errors.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Status {
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub code: Option<u16>,
+}
+
+impl Status {
+    /// Parse `v` as a `Status`, if it is one.
+    pub fn from_json(v: &JsonValue) -> Option<Self> {
+        if v["kind"] != "Status" {
+            return None;
+        }
+        let s = |k: &str| v[k].as_str().unwrap_or("").to_owned();
+        Some(Status {
+            status: s("status"),
+            reason: s("reason"),
+            message: s("message"),
+            code: v["code"].as_u16(),
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == "Success"
+    }
+}
+
+/// Turn a parsed response body into a result.  A `Status` body
+/// reporting failure is an error, whatever the HTTP status.
+fn status_result(success: bool, json: JsonValue) -> Result<JsonValue> {
+    match Status::from_json(&json) {
+        Some(ref s) if !s.is_success() => Err(ErrorKind::Kubernetes(json).into()),
+        _ if success => Ok(json),
+        _ => Err(ErrorKind::Kubernetes(json).into()),
+    }
+}
+
+#[test]
+fn test_status_result() {
+    let success = object!{
+        "kind" => "Status",
+        "apiVersion" => "v1",
+        "status" => "Success",
+        "details" => object!{"name" => "foo", "kind" => "configmaps"}
+    };
+    let s = Status::from_json(&success).unwrap();
+    assert!(s.is_success());
+    assert_eq!(s.code, None);
+    assert_eq!(status_result(true, success.clone()).unwrap(), success);
+
+    let failure = object!{
+        "kind" => "Status",
+        "apiVersion" => "v1",
+        "status" => "Failure",
+        "reason" => "Conflict",
+        "message" => "operation cannot be fulfilled",
+        "code" => 409
+    };
+    assert_eq!(Status::from_json(&failure), Some(Status {
+        status: "Failure".to_owned(),
+        reason: "Conflict".to_owned(),
+        message: "operation cannot be fulfilled".to_owned(),
+        code: Some(409),
+    }));
+    // A Failure status is an error, even from a 2xx response
+    match status_result(true, failure).unwrap_err().kind() {
+        ErrorKind::Kubernetes(v) => assert_eq!(v["reason"], "Conflict"),
+        k => panic!("unexpected error {:?}", k),
+    }
+
+    let cm = object!{"kind" => "ConfigMap", "apiVersion" => "v1"};
+    assert_eq!(Status::from_json(&cm), None);
+    assert!(status_result(true, cm.clone()).is_ok());
+    assert!(status_result(false, cm).is_err());
+}
+
 pub fn kube_result(mut resp: Response) -> Result<JsonValue> {
     use hyper::mime::{Mime,TopLevel,SubLevel};
-    use hyper::header::{ContentType};
+    use hyper::header::{ContentType,ContentEncoding,Encoding};
 
     let json = match resp.headers.get::<ContentType>() {
         Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => {
-            let mut body = String::new();
-            resp.read_to_string(&mut body)?;
+            let mut body = vec![];
+            resp.read_to_end(&mut body)?;
+            if let Some(ContentEncoding(encodings)) = resp.headers.get::<ContentEncoding>() {
+                if encodings.contains(&Encoding::Gzip) {
+                    body = gunzip(&body)?;
+                }
+            }
+            let body = String::from_utf8(body)
+                .chain_err(|| "Response is not valid UTF-8")?;
 
             parse_json(&body)?
         },
@@ -214,16 +1525,80 @@ pub fn kube_result(mut resp: Response) -> Result<JsonValue> {
         },
     };
 
-    if resp.status.is_success() {
-        Ok(json)
-    } else {
-        Err(ErrorKind::Kubernetes(json).into())
+    status_result(resp.status.is_success(), json)
+}
+
+/// Decompress a gzip (RFC 1952) member
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 8] {
+        bail!("Invalid gzip header");
+    }
+    let flags = data[3];
+    let mut rest = &data[10..];
+    if flags & FEXTRA != 0 {
+        let len = rest.get(..2).map(|l| l[0] as usize | (l[1] as usize) << 8)
+            .ok_or("Truncated gzip header")?;
+        rest = rest.get(2 + len..).ok_or("Truncated gzip header")?;
     }
+    for &flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = rest.iter().position(|&b| b == 0).ok_or("Truncated gzip header")?;
+            rest = &rest[end + 1..];
+        }
+    }
+    if flags & FHCRC != 0 {
+        rest = rest.get(2..).ok_or("Truncated gzip header")?;
+    }
+
+    // The CRC and length trailer follow the deflate stream, and are
+    // not checked
+    ::miniz_oxide::inflate::decompress_to_vec(rest)
+        .map_err(|e| format!("Invalid gzip data: {:?}", e.status).into())
+}
+
+#[test]
+fn test_gunzip() {
+    let data = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xab\x56\xca\xce\xcc\x4b\x51\xb2\x52\x0a\x2e\x49\x2c\x29\x2d\x56\xd2\x51\x4a\xce\x4f\x49\x55\xb2\x32\x32\x30\xa8\x05\x00\x3c\x9b\xac\x7f\x1c\x00\x00\x00";
+    assert_eq!(gunzip(data).unwrap(), br#"{"kind":"Status","code":200}"#);
+
+    assert!(gunzip(b"{}").is_err());
+    assert!(gunzip(&data[..20]).is_err());
 }
 
 pub fn is_rollout_done(v: &JsonValue) -> bool {
     let observed_gen = v["status"]["observedGeneration"].as_i64().unwrap_or_default();
     let generation = v["metadata"]["generation"].as_i64().unwrap_or_default();
+
+    if v.is_k8s_kind(V1_STATEFULSET) {
+        let updated_replicas = v["status"]["updatedReplicas"].as_i32().unwrap_or_default();
+        let current_replicas = v["status"]["currentReplicas"].as_i32().unwrap_or_default();
+        let replicas = v["spec"]["replicas"].as_i32().unwrap_or_default();
+
+        info!("Updated {}/{} replicas ({} current)",
+              updated_replicas, replicas, current_replicas);
+
+        return observed_gen >= generation &&
+            updated_replicas >= replicas &&
+            current_replicas >= replicas;
+    }
+
+    if v.is_k8s_kind(V1_DAEMONSET) {
+        let updated = v["status"]["updatedNumberScheduled"].as_i32().unwrap_or_default();
+        let ready = v["status"]["numberReady"].as_i32().unwrap_or_default();
+        let desired = v["status"]["desiredNumberScheduled"].as_i32().unwrap_or_default();
+
+        info!("Updated {}/{} scheduled pods ({} ready)", updated, desired, ready);
+
+        return observed_gen >= generation &&
+            updated >= desired &&
+            ready >= desired;
+    }
+
     let updated_replicas = v["status"]["updatedReplicas"].as_i32().unwrap_or_default();
     let replicas = v["spec"]["replicas"].as_i32().unwrap_or_default();
 
@@ -239,6 +1614,146 @@ pub fn is_rollout_done(v: &JsonValue) -> bool {
         is_available
 }
 
+#[test]
+fn test_is_rollout_done() {
+    let mut sts = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "StatefulSet",
+        "metadata" => object!{"generation" => 2},
+        "spec" => object!{"replicas" => 3},
+        "status" => object!{"observedGeneration" => 2, "updatedReplicas" => 3, "currentReplicas" => 2}
+    };
+    assert!(!is_rollout_done(&sts));
+    sts["status"]["currentReplicas"] = 3.into();
+    assert!(is_rollout_done(&sts));
+    sts["metadata"]["generation"] = 3.into();
+    assert!(!is_rollout_done(&sts));
+
+    let mut ds = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "DaemonSet",
+        "metadata" => object!{"generation" => 1},
+        "status" => object!{
+            "observedGeneration" => 1,
+            "desiredNumberScheduled" => 4,
+            "updatedNumberScheduled" => 4,
+            "numberReady" => 3
+        }
+    };
+    assert!(!is_rollout_done(&ds));
+    ds["status"]["numberReady"] = 4.into();
+    assert!(is_rollout_done(&ds));
+    ds["status"].remove("observedGeneration");
+    assert!(!is_rollout_done(&ds));
+}
+
+/// True if `e` is the server rejecting a change to an immutable field
+/// (eg: a Service's `clusterIP`, or a Job's `template`).
+pub fn is_immutable_field_error(e: &Error) -> bool {
+    let immutable = |v: &JsonValue| v["message"].as_str()
+        .is_some_and(|m| m.contains("field is immutable"));
+    match *e.kind() {
+        ErrorKind::Kubernetes(ref v) =>
+            v["code"] == 422 &&
+            (immutable(v) || v["details"]["causes"].members().any(immutable)),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_immutable_field_error() {
+    let e: Error = ErrorKind::Kubernetes(object!{
+        "kind" => "Status",
+        "status" => "Failure",
+        "reason" => "Invalid",
+        "code" => 422,
+        "message" => "Service \"foo\" is invalid: spec.clusterIP: Invalid value: \"10.0.0.2\": field is immutable",
+        "details" => object!{
+            "causes" => array![object!{
+                "reason" => "FieldValueInvalid",
+                "message" => "Invalid value: \"10.0.0.2\": field is immutable",
+                "field" => "spec.clusterIP"
+            }]
+        }
+    }).into();
+    assert!(is_immutable_field_error(&e));
+
+    let e: Error = ErrorKind::Kubernetes(object!{
+        "code" => 422,
+        "message" => "Deployment.apps \"foo\" is invalid: spec.replicas: must be non-negative"
+    }).into();
+    assert!(!is_immutable_field_error(&e));
+
+    let e: Error = "field is immutable".into();
+    assert!(!is_immutable_field_error(&e));
+}
+
+/// Why a rollout has stalled and will not complete without
+/// intervention, if the object's status reports it.
+pub fn rollout_failure(v: &JsonValue) -> Option<String> {
+    v["status"]["conditions"].members()
+        .find(|c| {
+            (c["type"] == "Progressing" && c["status"] == "False" &&
+             c["reason"] == "ProgressDeadlineExceeded") ||
+                (c["type"] == "ReplicaFailure" && c["status"] == "True")
+        })
+        .map(|c| match c["message"].as_str() {
+            Some(msg) => format!("{}: {}", c["reason"], msg),
+            None => c["reason"].to_string(),
+        })
+}
+
+#[test]
+fn test_rollout_failure() {
+    let mut v = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "status" => object!{
+            "conditions" => array![
+                object!{"type" => "Available", "status" => "False"},
+                object!{"type" => "Progressing", "status" => "True", "reason" => "ReplicaSetUpdated"}
+            ]
+        }
+    };
+    assert_eq!(rollout_failure(&v), None);
+
+    v["status"]["conditions"][1] = object!{
+        "type" => "Progressing",
+        "status" => "False",
+        "reason" => "ProgressDeadlineExceeded",
+        "message" => "ReplicaSet \"foo-123\" has timed out progressing."
+    };
+    assert_eq!(rollout_failure(&v).unwrap(),
+               "ProgressDeadlineExceeded: ReplicaSet \"foo-123\" has timed out progressing.");
+
+    v["status"]["conditions"] = array![
+        object!{"type" => "ReplicaFailure", "status" => "True", "reason" => "FailedCreate"}
+    ];
+    assert_eq!(rollout_failure(&v).unwrap(), "FailedCreate");
+}
+
+/// True if `v` has a `status.conditions` entry of type `condition`
+/// with status `True`.
+pub fn is_condition_true(v: &JsonValue, condition: &str) -> bool {
+    v["status"]["conditions"].members()
+        .any(|c| c["type"] == condition && c["status"] == "True")
+}
+
+#[test]
+fn test_is_condition_true() {
+    let v = object!{
+        "status" => object!{
+            "conditions" => array![
+                object!{"type" => "Ready", "status" => "True"},
+                object!{"type" => "Synced", "status" => "False"}
+            ]
+        }
+    };
+    assert!(is_condition_true(&v, "Ready"));
+    assert!(!is_condition_true(&v, "Synced"));
+    assert!(!is_condition_true(&v, "Available"));
+}
+
 /// `f` returns false to stop watch iteration.
 pub fn kube_watch<F>(mut resp: Response, mut f: F) -> Result<bool>
     where F: FnMut(JsonValue) -> Result<bool>
@@ -12,6 +12,10 @@ extern crate error_chain;
 extern crate hyper;
 extern crate url;
 extern crate hyper_native_tls;
+extern crate sha2;
+extern crate miniz_oxide;
+extern crate libc;
+extern crate base64;
 
 mod errors {
     error_chain! {
@@ -28,10 +32,51 @@ mod errors {
                 description("Unknown Kubernetes resource")
                 display("Unknown resource: '{}'", v)
             }
+            Connection(url: String, reason: String) {
+                description("Unable to connect to Kubernetes server")
+                display("Unable to connect to {}: {}", url, reason)
+            }
+            MissingName(what: String) {
+                description("Object has no name")
+                display("{} has no metadata.name", what)
+            }
             MalformedObject(v: ::json::JsonValue) {
                 description("Unexpected JSON value")
                 display("Unexpected JSON value in {}", v.dump())
             }
+            ObjectTooLarge(id: String, size: usize, limit: usize) {
+                description("Object too large")
+                display("{} is {} bytes, more than --max-object-size {}", id, size, limit)
+            }
+            WaitTimeout(pending: String) {
+                description("Timed out waiting for objects")
+                display("Timed out waiting for {}", pending)
+            }
+            RolloutFailed(reason: String) {
+                description("Rollout failed")
+                display("Rollout failed: {}", reason)
+            }
+            ServerChanged(id: String, fields: Vec<String>) {
+                description("Server changed the object")
+                display("Server changed {} from what was sent: {}", id, fields.join(", "))
+            }
+            KindNotAllowed(objects: Vec<String>) {
+                description("Object kind not allowed")
+                display("Refusing to apply, as --allowed-kinds/--denied-kinds don't permit: {}", objects.join(", "))
+            }
+            DiffsFound(code: i32) {
+                description("Differences found")
+                display("Differences found")
+            }
+            Interrupted(summary: String, skipped: Vec<String>) {
+                description("Interrupted")
+                display("Interrupted, after: {}{}", summary,
+                        if skipped.is_empty() { String::new() } else { format!("; not sent: {}", skipped.join(", ")) })
+            }
+            ValidationFailed(n: usize) {
+                description("Validation failed")
+                display("Validation failed with {} problem(s)", n)
+            }
             Kubernetes(v: ::json::JsonValue) {
                 description("Error from Kubernetes server")
                 display("Error from Kubernetes: {}",
@@ -48,23 +93,46 @@ mod errors {
 mod emitters;
 mod kutils;
 mod diff;
+mod schema;
+mod tls;
+mod results;
+mod findings;
+mod sources;
+mod auth;
+mod kubeconfig;
 
 use clap::{Arg,App,SubCommand,AppSettings,Shell,ArgGroup,ArgMatches};
 use jsonnet::{jsonnet_version,JsonnetVm};
 use url::Url;
 use hyper::Client;
-use hyper::header::{ContentType,Accept};
+use hyper::client::Response;
+use hyper::client::pool::Pool;
+use hyper::header::{ContentType,Accept,AcceptEncoding,Authorization,Bearer,Encoding,UserAgent,qitem};
+use hyper::method::Method;
 use hyper::net::HttpsConnector;
 use hyper_native_tls::NativeTlsClient;
+use hyper_native_tls::native_tls::TlsConnector;
 use json::JsonValue;
-use std::ffi::OsStr;
+use sha2::{Digest,Sha256};
+use std::cell::RefCell;
+use std::ffi::{CString,OsStr};
 use std::io::{self,Write};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap,BTreeSet};
 use std::env;
+use std::path::{Path,PathBuf};
+use std::fs;
+use std::iter;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
 
 use errors::*;
 use emitters::OutputFormat;
 use kutils::{JsonValueExt,kube_result};
+use results::{Operation,Outcome,Results};
+use findings::{Finding,Severity};
 
 const JPATH_ENVVAR: &'static str = "KUBECFG_JPATH";
 
@@ -82,6 +150,187 @@ fn test_parse_kv() {
     assert_eq!(parse_kv("foo="), ("foo", ""));
 }
 
+/// Arguments for filtering the objects from the input file
+fn filter_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("include_kind")
+            .long("include-kind")
+            .value_name("KIND")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only process objects of this kind"),
+        Arg::with_name("exclude_kind")
+            .long("exclude-kind")
+            .value_name("KIND")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Skip objects of this kind (takes precedence over --include-kind)"),
+    ]
+}
+
+/// Arguments for refusing to apply some kinds of object
+fn kind_policy_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("allowed_kinds")
+            .long("allowed-kinds")
+            .value_name("GROUP/VERSION/KIND,...")
+            .use_delimiter(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Refuse to apply anything if any object is not one of these kinds (eg apps/v1/Deployment,v1/ConfigMap)"),
+        Arg::with_name("denied_kinds")
+            .long("denied-kinds")
+            .value_name("GROUP/VERSION/KIND,...")
+            .use_delimiter(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Refuse to apply anything if any object is one of these kinds"),
+    ]
+}
+
+/// Parse a `--allowed-kinds` style `group/version/kind` (or
+/// `version/kind` for the core group).
+fn parse_kind_arg(s: &str) -> Result<kutils::K8sKind> {
+    match s.rfind('/') {
+        Some(i) if i > 0 && i + 1 < s.len() => Ok(kutils::K8sKind::new(&s[..i], &s[i + 1..])),
+        _ => bail!("Invalid kind '{}': expected group/version/kind", s),
+    }
+}
+
+/// Fail, naming every offending object, if any of `objects` isn't in
+/// `--allowed-kinds` or is in `--denied-kinds`.
+fn check_kind_policy<'a>(objects: &[&JsonValue], matches: &ArgMatches<'a>) -> Result<()> {
+    let parse = |name| -> Result<Option<Vec<kutils::K8sKind>>> {
+        matches.values_of(name)
+            .map(|v| v.map(parse_kind_arg).collect())
+            .transpose()
+    };
+    let allowed = parse("allowed_kinds")?;
+    let denied = parse("denied_kinds")?.unwrap_or_default();
+
+    let refused: Vec<_> = objects.iter()
+        .filter(|o| {
+            let kind = o.k8s_kind();
+            allowed.as_ref().is_some_and(|a| !a.contains(&kind)) || denied.contains(&kind)
+        })
+        .map(|o| format!("{} ({})", o.k8s_id(), o.k8s_kind()))
+        .collect();
+    if refused.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorKind::KindNotAllowed(refused).into())
+    }
+}
+
+#[test]
+fn test_check_kind_policy() {
+    let cm = object!{"apiVersion" => "v1", "kind" => "ConfigMap", "metadata" => object!{"name" => "cm"}};
+    let deploy = object!{"apiVersion" => "apps/v1", "kind" => "Deployment", "metadata" => object!{"name" => "app"}};
+    let crb = object!{"apiVersion" => "rbac.authorization.k8s.io/v1", "kind" => "ClusterRoleBinding", "metadata" => object!{"name" => "admin"}};
+    let objects = [&cm, &deploy, &crb];
+
+    let check = |args: &[&str]| {
+        let mut argv = vec!["kubecfg", "update", "-f", "x"];
+        argv.extend(args);
+        let m = build_cli("test").get_matches_from(argv);
+        check_kind_policy(&objects, m.subcommand_matches("update").unwrap())
+    };
+    let refused = |args: &[&str]| match *check(args).unwrap_err().kind() {
+        ErrorKind::KindNotAllowed(ref objects) => objects.clone(),
+        ref k => panic!("unexpected error {:?}", k),
+    };
+
+    assert!(check(&[]).is_ok());
+    assert!(check(&["--allowed-kinds", "core/v1/ConfigMap,apps/v1/Deployment",
+                    "--allowed-kinds", "rbac.authorization.k8s.io/v1/ClusterRoleBinding"]).is_ok());
+    assert_eq!(refused(&["--allowed-kinds", "v1/ConfigMap,apps/v1beta1/Deployment"]),
+               vec!["deployment/app (apps/v1.Deployment)",
+                    "clusterrolebinding/admin (rbac.authorization.k8s.io/v1.ClusterRoleBinding)"]);
+    assert_eq!(refused(&["--denied-kinds", "rbac.authorization.k8s.io/v1/ClusterRoleBinding"]),
+               vec!["clusterrolebinding/admin (rbac.authorization.k8s.io/v1.ClusterRoleBinding)"]);
+    assert!(check(&["--denied-kinds", "ConfigMap"]).is_err());
+}
+
+/// Arguments for adding annotations to every object
+fn annotation_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("annotation")
+            .long("annotation")
+            .value_name("KEY=VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Add this annotation to every object. {timestamp} and {user} in VALUE are replaced with the current time and $USER"),
+        Arg::with_name("overwrite_annotations")
+            .long("overwrite-annotations")
+            .help("Let --annotation replace annotations already set on an object"),
+    ]
+}
+
+/// Arguments for checking target namespaces exist before applying
+fn namespace_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("ensure_namespace")
+            .long("ensure-namespace")
+            .help("Before applying, check that every target namespace exists or is defined in the input"),
+        Arg::with_name("create_namespace")
+            .long("create-namespace")
+            .help("As --ensure-namespace, but create missing namespaces"),
+    ]
+}
+
+fn output_results_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("output_results")
+        .long("output-results")
+        .value_name("FORMAT")
+        .possible_values(&["json"])
+        .help("At the end of the run, write what happened to each object to stdout")
+}
+
+fn exec_hook_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("exec_hook")
+        .long("exec-hook")
+        .value_name("COMMAND")
+        .help("Pipe each object (as JSON) through COMMAND before applying it. Non-empty output replaces the object")
+}
+
+/// Arguments for jsonnet external variables and top-level arguments
+fn jsonnet_var_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("ext_var")
+            .short("V")
+            .long("ext-var")
+            .value_name("KEY=VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Set a string external variable, for std.extVar"),
+        Arg::with_name("ext_code")
+            .long("ext-code")
+            .value_name("KEY=EXPR")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Set an external variable given as jsonnet code, eg a number, boolean or object"),
+        Arg::with_name("tla_var")
+            .long("tla-var")
+            .value_name("KEY=VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Pass a string top-level argument. Only used if the file evaluates to a function"),
+        Arg::with_name("tla_code")
+            .long("tla-code")
+            .value_name("KEY=EXPR")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Pass a top-level argument given as jsonnet code. Only used if the file evaluates to a function"),
+    ]
+}
+
+fn post_process_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("post_process")
+        .long("post-process")
+        .value_name("FILE")
+        .help("Jsonnet file evaluating to a function, called with each object. The object it returns replaces the original")
+}
+
 fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
     App::new("Kubecfg")
         .setting(AppSettings::SubcommandRequired)
@@ -94,33 +343,216 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
              .long("server")
              .default_value("http://localhost:8001/")
              .value_name("URL")
-             .help("The URL of the Kubernetes API server"))
+             .help("The URL of the Kubernetes API server. Defaults to the server of the kubeconfig context, if there is one. Given explicitly, the kubeconfig is ignored unless --context is also given"))
+        .arg(Arg::with_name("context")
+             .long("context")
+             .value_name("NAME")
+             .help("Use this context from $KUBECONFIG (or ~/.kube/config), rather than the current one"))
+        .arg(Arg::with_name("default_namespace")
+             .short("n")
+             .long("default-namespace")
+             .visible_alias("namespace")
+             .default_value("default")
+             .value_name("NAMESPACE")
+             .help("Namespace for namespaced objects that don't specify one, if not the kubeconfig context's. Cluster-scoped objects ignore it"))
+        .arg(Arg::with_name("force_namespace")
+             .long("force-namespace")
+             .help("Use --namespace even for objects that specify their own namespace"))
+        .arg(Arg::with_name("max_object_size")
+             .long("max-object-size")
+             .value_name("BYTES")
+             .default_value("1500000")
+             .help("Refuse to send objects larger than this, rather than waiting for the server to reject them. 0 disables the check"))
+        .arg(Arg::with_name("response_compression")
+             .long("response-compression")
+             .value_name("ENCODING")
+             .possible_values(&["gzip", "none"])
+             .help("Ask the server to compress responses (other than watches). Default none"))
+        .arg(Arg::with_name("chunk_size")
+             .long("chunk-size")
+             .value_name("N")
+             .help("Fetch lists N objects at a time. 0 (the default) fetches them all at once"))
+        .arg(Arg::with_name("low_bandwidth")
+             .long("low-bandwidth")
+             .help("For slow or metered links: defaults to --response-compression=gzip --chunk-size=50. Either flag overrides this"))
+        .arg(Arg::with_name("max_retries")
+             .long("max-retries")
+             .value_name("N")
+             .default_value("3")
+             .help("Retry requests that fail to connect, or get a 429, 500, 502, 503 or 504 response, up to N times with exponential backoff"))
+        .arg(Arg::with_name("retry_budget")
+             .long("retry-budget")
+             .value_name("N")
+             .help("Retry at most N times in total over the whole run. After that, failures that would be retried fail immediately"))
+        .arg(Arg::with_name("retry_time_budget")
+             .long("retry-time-budget")
+             .value_name("SECS")
+             .help("As --retry-budget, but limiting the total time spent retrying"))
+        .arg(Arg::with_name("tls_server_name")
+             .long("tls-server-name")
+             .value_name("NAME")
+             .help("Server name to use for TLS verification, if different from the --server host"))
+        .arg(Arg::with_name("user_agent")
+             .long("user-agent")
+             .value_name("STRING")
+             .help("User-Agent header to send, instead of one naming kubecfg, its version and the subcommand"))
+        .arg(Arg::with_name("token")
+             .long("token")
+             .value_name("TOKEN")
+             .conflicts_with("token_file")
+             .help("Authenticate with this bearer token. Not sent to plain http:// servers"))
+        .arg(Arg::with_name("token_file")
+             .long("token-file")
+             .value_name("PATH")
+             .help("Authenticate with the bearer token in PATH. The file is re-read whenever it changes. Not sent to plain http:// servers"))
+        .arg(Arg::with_name("pin_server_cert_sha256")
+             .long("pin-server-cert-sha256")
+             .value_name("HEX")
+             .help("Only trust a server certificate with this SHA-256 fingerprint, even if otherwise trusted"))
+        .arg(Arg::with_name("insecure_skip_tls_verify")
+             .long("insecure-skip-tls-verify")
+             .help("Don't verify the server's certificate or hostname. INSECURE: anyone able to intercept the connection can impersonate the server. Only for development clusters with self-signed certificates"))
+        .arg(Arg::with_name("certificate_authority_dir")
+             .long("certificate-authority-dir")
+             .value_name("DIR")
+             .help("Trust CA certificates from every .pem/.crt file in DIR"))
+        .arg(Arg::with_name("certificate_authority")
+             .long("certificate-authority")
+             .value_name("FILE")
+             .help("Trust the CA certificates in this PEM file"))
+        .arg(Arg::with_name("client_certificate")
+             .long("client-certificate")
+             .value_name("FILE")
+             .requires("client_key")
+             .conflicts_with("pfx")
+             .help("Authenticate with the client certificate (chain) in this PEM file. Needs --client-key"))
+        .arg(Arg::with_name("client_key")
+             .long("client-key")
+             .value_name("FILE")
+             .requires("client_certificate")
+             .help("PEM (PKCS#8) private key for --client-certificate"))
+        .arg(Arg::with_name("pfx")
+             .long("pfx")
+             .value_name("FILE")
+             .help("Authenticate with the client certificate and key in this PKCS#12 (.p12/.pfx) bundle"))
+        .arg(Arg::with_name("pfx_password")
+             .long("pfx-password")
+             .value_name("PASSWORD")
+             .requires("pfx")
+             .help("Password for the --pfx bundle. Defaults to empty"))
+        .arg(Arg::with_name("chdir")
+             .short("C")
+             .long("chdir")
+             .value_name("DIR")
+             .help("Change to DIR before doing anything else, so relative paths and imports resolve against it"))
+        .arg(Arg::with_name("allow_cluster_reads")
+             .long("allow-cluster-reads")
+             .global(true)
+             .help("Let jsonnet read objects from the server, with std.native('kubeGet')(apiVersion, kind, namespace, name). Missing objects are null"))
+        .arg(Arg::with_name("log_timestamps")
+             .long("log-timestamps")
+             .help("Start each log line with the time, as RFC 3339 UTC with milliseconds"))
+        .arg(Arg::with_name("allow_remote_files")
+             .long("allow-remote-files")
+             .global(true)
+             .help("Let -f be an http(s) URL. Relative imports in remote jsonnet are fetched too"))
+        .arg(Arg::with_name("trace_imports")
+             .long("trace-imports")
+             .global(true)
+             .help("Report the file that satisfied each jsonnet import"))
         .subcommand(SubCommand::with_name("completions")
                     .about("Generate shell completions")
                     .arg(Arg::with_name("shell")
                          .possible_values(&Shell::variants())
                          .required(true)
                          .help("Shell variant")))
+        .subcommand(SubCommand::with_name("__complete")
+                    .setting(AppSettings::Hidden)
+                    .about("Print live completion candidates, one per line, for use by shell completion functions")
+                    .arg(Arg::with_name("what")
+                         .possible_values(&["kinds", "namespaces"])
+                         .required(true)
+                         .help("What to complete"))
+                    .arg(Arg::with_name("prefix")
+                         .default_value("")
+                         .help("The partial word being completed")))
         .subcommand(SubCommand::with_name("show")
                     .about("Show expanded resource definition")
+                    .arg(Arg::with_name("strict")
+                         .long("strict")
+                         .help("Reject top-level fields not described by the server's schema"))
                     .arg(Arg::with_name("format")
                          .short("o")
                          .long("format")
                          .possible_values(&OutputFormat::variants())
                          .default_value(OutputFormat::default())
                          .value_name("FMT")
-                         .help("Output format"))
+                         .help("Output format. json writes the result as evaluated (eg a v1 List), json-compact a single-line array of the objects, and ndjson one object per line"))
+                    .arg(Arg::with_name("sort_objects")
+                         .long("sort-objects")
+                         .help("Write objects in order of group, version, kind, namespace and name, rather than as the template produced them"))
+                    .arg(Arg::with_name("sort_keys")
+                         .long("sort-keys")
+                         .help("Write the fields of every object in sorted order"))
+                    .arg(Arg::with_name("annotate_source")
+                         .long("annotate-source")
+                         .help("With -o yaml, write each object as a separate document, preceded by comments naming it and (approximately) the file it came from"))
+                    .arg(Arg::with_name("output_empty_as")
+                         .long("output-empty-as")
+                         .value_name("HOW")
+                         .possible_values(&emitters::EmptyAs::variants())
+                         .default_value("empty-list")
+                         .help("How to show a result with no objects: nothing, an empty list (an empty stream for yaml and ndjson), or null"))
+                    .arg(Arg::with_name("output_version")
+                         .long("output-version")
+                         .value_name("GROUP/VERSION")
+                         .help("Convert objects to this API version, by reading them back from the server. Objects must already exist"))
+                    .arg(Arg::with_name("hash")
+                         .long("hash")
+                         .help("Print a SHA-256 hash of the (normalized) objects instead, for detecting changes"))
+                    .arg(Arg::with_name("kustomization")
+                         .long("as-kustomization")
+                         .value_name("DIR")
+                         .help("Write each object to a separate YAML file in DIR, with a kustomization.yaml listing them"))
+                    .arg(Arg::with_name("helm_chart")
+                         .long("as-helm-chart")
+                         .value_name("DIR")
+                         .conflicts_with("kustomization")
+                         .help("Experimental: write a Helm chart to DIR, with each object as a file in templates/. The templates are static: they use no values"))
+                    .arg(Arg::with_name("split_by")
+                         .long("split-by")
+                         .value_name("KEY")
+                         .possible_values(&emitters::SplitBy::variants())
+                         .requires("output_dir")
+                         .conflicts_with_all(&["kustomization", "helm_chart"])
+                         .help("Write objects to one file per kind, namespace or name"))
+                    .arg(Arg::with_name("filename_template")
+                         .long("filename-template")
+                         .value_name("TEMPLATE")
+                         .requires("output_dir")
+                         .conflicts_with_all(&["kustomization", "helm_chart", "split_by"])
+                         .help("Write each object to a file named by TEMPLATE, eg: '{namespace}/{kind}-{name}.yaml'. Placeholders are {namespace}, {kind}, {name}, {group} and {version}"))
+                    .arg(Arg::with_name("output_dir")
+                         .long("output-dir")
+                         .value_name("DIR")
+                         .help("Directory for --split-by or --filename-template files")
+                         .requires("files"))
+                    .group(ArgGroup::with_name("files")
+                           .args(&["split_by", "filename_template"]))
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
                     .arg(Arg::with_name("exec")
                          .short("e")
                          .long("exec")
                          .value_name("EXPR")
-                         .help("Jsonnet expression"))
+                         .help("Jsonnet expression. Evaluated as if it were a file named <exec> in the current directory, for std.thisFile and relative imports"))
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -131,12 +563,52 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                            .required(true)))
         .subcommand(SubCommand::with_name("diff")
                     .about("Show differences between local files and running service")
+                    .args(&filter_args())
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FMT")
+                         .possible_values(&["unified", "github", "side-by-side"])
+                         .default_value("unified")
+                         .help("Output format. \"github\" writes a GitHub Actions warning annotation for each changed object. \"side-by-side\" shows old and new YAML in two columns"))
+                    .arg(parallelism_arg())
+                    .arg(Arg::with_name("error_exitcode")
+                         .long("error-exitcode")
+                         .value_name("N")
+                         .default_value("2")
+                         .help("Exit status when there are differences, distinct from the 1 of other errors. 0 always exits successfully"))
+                    .arg(Arg::with_name("color")
+                         .long("color")
+                         .value_name("WHEN")
+                         .possible_values(&["auto", "always", "never"])
+                         .default_value("auto")
+                         .help("Colour removed lines red and added lines green in unified output. \"auto\" only does so when stdout is a terminal"))
+                    .arg(Arg::with_name("last_applied_only")
+                         .long("diff-against-last-applied-only")
+                         .help("Compare against the last-applied-configuration annotation where present, rather than the whole live object"))
+                    .arg(Arg::with_name("emit_changed")
+                         .long("emit-changed")
+                         .value_name("FMT")
+                         .possible_values(&OutputFormat::variants())
+                         .help("Instead of showing differences, write the local objects that differ as a List in this format, ready to apply"))
+                    .arg(Arg::with_name("local")
+                         .long("local")
+                         .requires("against_file")
+                         .conflicts_with("last_applied_only")
+                         .help("Compare with another local file (--against-file) instead of the server"))
+                    .arg(Arg::with_name("against_file")
+                         .long("against-file")
+                         .value_name("FILE")
+                         .requires("local")
+                         .help("With --local, the file to treat as the old version"))
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -145,12 +617,38 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                          .help("Input file")))
         .subcommand(SubCommand::with_name("create")
                     .about("Create resources only if they do not exist")
+                    .args(&filter_args())
+                    .args(&kind_policy_args())
+                    .arg(output_results_arg())
+                    .arg(plan_arg())
+                    .arg(exec_hook_arg())
+                    .args(&namespace_args())
+                    .arg(Arg::with_name("request_format")
+                         .long("request-format")
+                         .value_name("FMT")
+                         .possible_values(&["json", "yaml"])
+                         .default_value(OutputFormat::default())
+                         .help("Format of request bodies when creating objects"))
+                    .arg(Arg::with_name("set_image")
+                         .long("set-image")
+                         .value_name("CONTAINER=IMAGE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Override the image of containers with this name"))
+                    .args(&annotation_args())
+                    .arg(Arg::with_name("no_sort")
+                         .long("no-sort")
+                         .help("Process objects in input order. Note this no longer guarantees namespaces are created first"))
+                    .arg(parallelism_arg())
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -159,16 +657,34 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                          .help("Input file")))
         .subcommand(SubCommand::with_name("delete")
                     .about("Delete named resources")
+                    .args(&filter_args())
+                    .arg(output_results_arg())
+                    .arg(plan_arg())
                     .arg(Arg::with_name("grace_period")
                          .long("grace-period")
                          .value_name("SECS")
-                         .help("Period of time in seconds given to the resource to terminate gracefully."))
+                         .help("Period of time in seconds given to the resource to terminate gracefully. 0 deletes immediately; for Pods this is a force delete, which doesn't wait for the kubelet to confirm the containers have stopped"))
+                    .arg(Arg::with_name("delete_options_file")
+                         .long("delete-options-file")
+                         .value_name("PATH")
+                         .help("Send this DeleteOptions (JSON or YAML) as the request body, instead of one built from other flags"))
+                    .arg(Arg::with_name("wait")
+                         .long("wait")
+                         .help("Wait for deleted objects to disappear from the server, eg once their finalizers have run"))
+                    .arg(Arg::with_name("wait_timeout")
+                         .long("wait-timeout")
+                         .value_name("SECS")
+                         .requires("wait")
+                         .help("Give up waiting for --wait after this long. Defaults to 120"))
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -177,18 +693,240 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                          .help("Input file")))
         .subcommand(SubCommand::with_name("update")
                     .about("Update existing resources")
+                    .args(&filter_args())
+                    .args(&kind_policy_args())
+                    .arg(output_results_arg())
+                    .arg(plan_arg())
+                    .arg(exec_hook_arg())
+                    .args(&namespace_args())
+                    .arg(Arg::with_name("request_format")
+                         .long("request-format")
+                         .value_name("FMT")
+                         .possible_values(&["json", "yaml"])
+                         .default_value(OutputFormat::default())
+                         .help("Format of request bodies when creating objects"))
+                    .arg(Arg::with_name("preview")
+                         .long("preview")
+                         .help("Show each object as it would be after merging into the live version, instead of updating"))
+                    .arg(Arg::with_name("format")
+                         .short("o")
+                         .long("format")
+                         .possible_values(&OutputFormat::variants())
+                         .default_value(OutputFormat::default())
+                         .value_name("FMT")
+                         .help("Output format for --preview"))
+                    .arg(Arg::with_name("set_image")
+                         .long("set-image")
+                         .value_name("CONTAINER=IMAGE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Override the image of containers with this name"))
+                    .args(&annotation_args())
+                    .arg(Arg::with_name("no_sort")
+                         .long("no-sort")
+                         .help("Process objects in input order. Note this no longer guarantees namespaces are created first"))
+                    .arg(parallelism_arg())
                     .arg(Arg::with_name("create")
                          .long("create")
                          .help("Create missing resources"))
+                    .arg(Arg::with_name("check_permissions")
+                         .long("check-permissions")
+                         .help("Before changing anything, ask the server whether every operation the update needs is allowed, and report all that are not"))
                     .arg(Arg::with_name("wait")
                          .long("wait")
-                         .help("Block until update has completed"))
+                         .help("Block until the rollouts of updated Deployments, StatefulSets and DaemonSets have completed"))
+                    .arg(Arg::with_name("wait_timeout")
+                         .long("wait-timeout")
+                         .value_name("SECS")
+                         .help("Give up waiting (with --wait or --wait-for-condition) after this long"))
+                    .arg(Arg::with_name("wait_for_condition")
+                         .long("wait-for-condition")
+                         .value_name("CONDITION")
+                         .help("Block until every object that reports status conditions has condition CONDITION set to True"))
+                    .arg(Arg::with_name("wait_for_kinds")
+                         .long("wait-for-kinds")
+                         .value_name("GROUP/VERSION/KIND,...")
+                         .use_delimiter(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .requires("wait_for_condition")
+                         .help("With --wait-for-condition, also wait for objects of these kinds that don't report any conditions yet"))
+                    .arg(Arg::with_name("state_file")
+                         .long("state-file")
+                         .value_name("PATH")
+                         .help("Skip objects that haven't changed since the last successful update with this state file. Note changes made directly on the server are not detected"))
+                    .arg(Arg::with_name("output_patch")
+                         .long("output-patch")
+                         .value_name("DIR")
+                         .help("Also write the body of each PATCH sent to a file in DIR, with any --subresource=status patch in its own .status.json file. With --dry-run, only write the files"))
+                    .arg(Arg::with_name("subresource")
+                         .long("subresource")
+                         .value_name("NAME")
+                         .possible_values(&["status"])
+                         .help("Also update this subresource separately, for resources that have it (eg: custom resources with a status subresource)"))
+                    .arg(Arg::with_name("patch_type_for")
+                         .long("patch-type-for")
+                         .value_name("APIVERSION/KIND=TYPE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Update objects of this kind with a \"merge\" or \"strategic\" merge patch. By default built-in kinds use strategic and custom resources use merge"))
+                    .arg(Arg::with_name("max_conflict_retries")
+                         .long("max-conflict-retries")
+                         .value_name("N")
+                         .default_value("3")
+                         .help("Number of times to retry an update that conflicts with a concurrent change"))
+                    .arg(Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .help("Show what would be changed, without modifying anything"))
+                    .arg(Arg::with_name("fail_on_server_change")
+                         .long("fail-on-server-change")
+                         .help("Fail if the object the server returns doesn't match what was sent, eg because an admission webhook rewrote it"))
+                    .arg(Arg::with_name("allow_server_change")
+                         .long("allow-server-change")
+                         .value_name("PATH")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .requires("fail_on_server_change")
+                         .help("Field path (eg spec.replicas) that --fail-on-server-change allows the server to change, as well as status and metadata"))
+                    .arg(Arg::with_name("show_ownership")
+                         .long("show-ownership")
+                         .conflicts_with("output_results")
+                         .help("After updating, print the fields each field manager owns in every object, from its managedFields"))
+                    .arg(Arg::with_name("quiet_unchanged")
+                         .long("quiet-unchanged")
+                         .help("Log requests for objects the update didn't change only at debug level, and a count of them at the end. Each object is fetched first, and is unchanged if the PATCH leaves its resourceVersion the same"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .help("If an update is rejected for changing an immutable field, delete the object and create it again. Disruptive"))
+                    .arg(Arg::with_name("yes")
+                         .long("yes")
+                         .requires("force")
+                         .help("Don't ask for confirmation before --force replaces an object"))
+                    .arg(Arg::with_name("prune")
+                         .long("prune")
+                         .requires("selector")
+                         .help("Delete objects matching --selector that are no longer in the input file"))
+                    .arg(Arg::with_name("prune_namespace")
+                         .long("prune-namespace")
+                         .value_name("NAMESPACE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .requires("prune")
+                         .help("Only prune objects in this namespace. Objects in other namespaces, and cluster-scoped objects, are never pruned"))
+                    .arg(Arg::with_name("prune_propagation_policy")
+                         .long("prune-propagation-policy")
+                         .value_name("POLICY")
+                         .possible_values(&kutils::PropagationPolicy::variants())
+                         .default_value("Background")
+                         .help("How dependents of pruned objects are deleted"))
+                    .arg(Arg::with_name("selector")
+                         .short("l")
+                         .long("selector")
+                         .value_name("SELECTOR")
+                         .help("Label selector identifying objects managed by this input file"))
+                    .arg(Arg::with_name("jpath")
+                         .short("J")
+                         .long("jpath")
+                         .value_name("DIR")
+                         .multiple(true)
+                         .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("Input file")))
+        .subcommand(SubCommand::with_name("apply")
+                    .about("Apply resources with server-side apply, creating them if needed")
+                    .args(&filter_args())
+                    .args(&kind_policy_args())
+                    .arg(output_results_arg())
+                    .args(&namespace_args())
+                    .arg(Arg::with_name("field_manager")
+                         .long("field-manager")
+                         .value_name("NAME")
+                         .default_value("kubecfg")
+                         .help("Field manager to apply as. Conflicting fields owned by other managers are taken over"))
+                    .arg(Arg::with_name("show_ownership")
+                         .long("show-ownership")
+                         .conflicts_with("output_results")
+                         .help("After applying, print the fields each field manager owns in every object, from its managedFields"))
+                    .arg(Arg::with_name("jpath")
+                         .short("J")
+                         .long("jpath")
+                         .value_name("DIR")
+                         .multiple(true)
+                         .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("Input file")))
+        .subcommand(SubCommand::with_name("get")
+                    .about("Fetch the live versions of the objects in a file")
+                    .args(&filter_args())
+                    .arg(Arg::with_name("format")
+                         .short("o")
+                         .long("format")
+                         .possible_values(&OutputFormat::variants())
+                         .default_value(OutputFormat::default())
+                         .value_name("FMT")
+                         .help("Output format"))
+                    .arg(Arg::with_name("strict")
+                         .long("strict")
+                         .help("Fail if any object doesn't exist, rather than logging a warning and leaving it out"))
+                    .arg(Arg::with_name("jpath")
+                         .short("J")
+                         .long("jpath")
+                         .value_name("DIR")
+                         .multiple(true)
+                         .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
+                    .arg(post_process_arg())
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("Input file")))
+        .subcommand(SubCommand::with_name("api-resources")
+                    .about("List the resources discovered from the server, and the paths used for them")
+                    .arg(Arg::with_name("format")
+                         .short("o")
+                         .long("format")
+                         .value_name("FMT")
+                         .possible_values(&["text", "json", "yaml"])
+                         .default_value("text")
+                         .help("Output format")))
+        .subcommand(SubCommand::with_name("explain")
+                    .about("Describe the fields of a resource, from the server's schema")
+                    .arg(Arg::with_name("api_version")
+                         .long("api-version")
+                         .value_name("GROUP/VERSION")
+                         .help("API version of the resource, if not the server's preferred version"))
+                    .arg(Arg::with_name("field")
+                         .value_name("RESOURCE[.FIELD...]")
+                         .required(true)
+                         .help("Kind or resource name, optionally followed by a field path. Eg: deployment.spec.template")))
+        .subcommand(SubCommand::with_name("graph")
+                    .about("Write a Graphviz graph of how the objects in a file refer to each other")
+                    .args(&filter_args())
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -197,12 +935,36 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                          .help("Input file")))
         .subcommand(SubCommand::with_name("check")
                     .about("Validate file against jsonschema")
+                    .arg(Arg::with_name("strict")
+                         .long("strict")
+                         .help("Reject top-level fields not described by the server's schema"))
+                    .arg(Arg::with_name("references")
+                         .long("references")
+                         .help("Check that ConfigMaps and Secrets used by pods are defined in the input file"))
+                    .arg(Arg::with_name("check_cluster")
+                         .long("check-cluster")
+                         .requires("references")
+                         .help("Also accept referenced ConfigMaps and Secrets that exist on the server"))
+                    .arg(Arg::with_name("owners")
+                         .long("owners")
+                         .help("Check that ownerReferences refer to objects in the input file, and don't form cycles"))
+                    .arg(Arg::with_name("source_locations")
+                         .long("source-locations")
+                         .help("Report an approximate file:line for each problem, by searching the input file and the jsonnet files beside it for the object's name"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FMT")
+                         .possible_values(&["text", "json"])
+                         .default_value("text")
+                         .help("Output format. \"json\" writes all problems found as a single array. Exits non-zero only for errors, not warnings"))
                     .arg(Arg::with_name("jpath")
                          .short("J")
                          .long("jpath")
                          .value_name("DIR")
                          .multiple(true)
                          .help("Additional jsonnet library search path"))
+                    .arg(config_file_arg())
+                    .args(&jsonnet_var_args())
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
@@ -211,13 +973,202 @@ fn build_cli<'a>(version: &'a str) -> App<'a, 'a> {
                          .help("Input file")))
 }
 
+/// `--chunk-size` set by `--low-bandwidth`
+const LOW_BANDWIDTH_CHUNK_SIZE: usize = 50;
+
+/// Delay before the first retry of a conflicting update
+const CONFLICT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Delay before the first retry of a transient failure (`--max-retries`),
+/// doubling each time up to `MAX_BACKOFF`
+const TRANSIENT_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Longest `Retry-After` to honour
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How often to check for an interrupt while backing off
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Limits on retrying over a whole run (`--retry-budget` and
+/// `--retry-time-budget`), so a degraded server can't make every
+/// object retry to its own limit.
+#[derive(Debug,Default)]
+struct RetryBudget {
+    retries: Option<u32>,
+    time: Option<Duration>,
+    exhausted: bool,
+}
+
+impl RetryBudget {
+    /// Take one retry from the budget, if there is any left
+    fn try_retry(&mut self) -> bool {
+        let left = self.retries.is_none_or(|n| n > 0) &&
+            self.time.is_none_or(|t| t > Duration::from_secs(0));
+        if !left {
+            if !self.exhausted {
+                warn!("Retry budget exhausted, not retrying any further failures");
+            }
+            self.exhausted = true;
+            return false;
+        }
+        if let Some(ref mut n) = self.retries {
+            *n -= 1;
+        }
+        true
+    }
+
+    /// Count `elapsed` spent retrying against the time budget
+    fn charge(&mut self, elapsed: Duration) {
+        if let Some(ref mut t) = self.time {
+            *t = t.saturating_sub(elapsed);
+        }
+    }
+}
+
+#[test]
+fn test_retry_budget() {
+    let mut unlimited = RetryBudget::default();
+    assert!((0..100).all(|_| unlimited.try_retry()));
+
+    let mut budget = RetryBudget { retries: Some(2), ..Default::default() };
+    assert!(budget.try_retry());
+    assert!(budget.try_retry());
+    assert!(!budget.try_retry());
+    assert!(budget.exhausted);
+
+    let mut budget = RetryBudget { time: Some(Duration::from_secs(1)), ..Default::default() };
+    assert!(budget.try_retry());
+    budget.charge(Duration::from_millis(600));
+    assert!(budget.try_retry());
+    budget.charge(Duration::from_millis(600));
+    assert!(!budget.try_retry());
+}
+
+/// Interval between checks that a `--force` deletion has finished
+const DELETE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long to wait for a `--force` deletion to finish
+const DELETE_TIMEOUT: Duration = Duration::from_secs(120);
+
 type ApiMap = BTreeMap<kutils::K8sKind, kutils::ApiResource>;
 
 struct Context {
     vm: JsonnetVm,
     server_url: Url,
-    client: Client,
+    client: Arc<ApiClient>,
     api_cache: ApiMap,
+    default_namespace: String,
+    /// Where `default_namespace` came from, for logging
+    default_namespace_source: String,
+    /// `--force-namespace`
+    force_namespace: bool,
+    max_object_size: Option<usize>,
+    request_format: OutputFormat,
+    /// Page size for list requests (`--chunk-size`)
+    chunk_size: Option<usize>,
+    /// `--patch-type-for` overrides
+    patch_types: Vec<(kutils::K8sKind, kutils::PatchType)>,
+}
+
+/// Add the resources from an `APIResourceList` to `map`.
+///
+/// Several resources may share a kind (eg: `deployments` and its
+/// `deployments/status` subresource).  Prefer the top-level resource,
+/// in an attempt to find the core CRUD endpoint, then the shortest name.
+fn add_api_resources(map: &mut ApiMap, group_version: &str, resources: &JsonValue) -> Result<()> {
+    use std::collections::btree_map::Entry;
+
+    let rank = |api: &kutils::ApiResource| (api.name.contains('/'), api.name.len());
+    let list_version = kutils::ApiVersion::from(group_version);
+
+    for r in resources.members() {
+        // Subresources may return a kind from another group (eg:
+        // apps/v1 `deployments/scale` is an autoscaling/v1 Scale).
+        // Keying those under this group would route requests for
+        // that kind to the wrong place.
+        if (r["group"].is_string() && r["group"] != list_version.group.as_str()) ||
+            (r["version"].is_string() && r["version"] != list_version.version.as_str()) {
+            continue;
+        }
+
+        let api = kutils::ApiResource::new_from_json(r)?;
+        let kind = kutils::K8sKind::new(group_version, &api.kind);
+        match map.entry(kind) {
+            Entry::Vacant(e) => { e.insert(api); },
+            Entry::Occupied(mut e) => {
+                if rank(e.get()) > rank(&api) {
+                    e.insert(api);
+                }
+            },
+        };
+    }
+
+    // Record subresources against their parent resource
+    for r in resources.members() {
+        let name = r["name"].as_str().unwrap_or("");
+        if let Some((parent, sub)) = name.split_once('/') {
+            let found = map.iter_mut()
+                .find(|&(k, ref api)| k.api_version == list_version && api.name == parent);
+            if let Some((_, api)) = found {
+                if !api.has_subresource(sub) {
+                    api.subresources.push(sub.to_owned());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_add_api_resources() {
+    let resources = array![
+        object!{"name" => "dp/status", "kind" => "Deployment", "namespaced" => true},
+        object!{"name" => "deployments/scale", "kind" => "Scale", "namespaced" => true},
+        object!{"name" => "deployments", "kind" => "Deployment", "namespaced" => true},
+        object!{"name" => "deploymentsandmore", "kind" => "Deployment", "namespaced" => true}
+    ];
+    let mut map = ApiMap::new();
+    add_api_resources(&mut map, "apps/v1", &resources).unwrap();
+
+    let deploy = &map[&kutils::K8sKind::new("apps/v1", "Deployment")];
+    assert_eq!(deploy.name, "deployments");
+    assert_eq!(deploy.subresources, vec!["scale"]);
+
+    // Only reachable through a subresource
+    let scale = &map[&kutils::K8sKind::new("apps/v1", "Scale")];
+    assert_eq!(scale.name, "deployments/scale");
+}
+
+#[test]
+fn test_multi_group_kinds() {
+    let mut map = ApiMap::new();
+    add_api_resources(&mut map, "extensions/v1beta1", &array![
+        object!{"name" => "ingresses", "kind" => "Ingress", "namespaced" => true}
+    ]).unwrap();
+    add_api_resources(&mut map, "networking.k8s.io/v1", &array![
+        object!{"name" => "ingresses", "kind" => "Ingress", "namespaced" => true},
+        object!{"name" => "ingresses/status", "kind" => "Ingress", "namespaced" => true}
+    ]).unwrap();
+    add_api_resources(&mut map, "apps/v1", &array![
+        object!{"name" => "deployments", "kind" => "Deployment", "namespaced" => true},
+        object!{"name" => "deployments/scale", "kind" => "Scale", "namespaced" => true,
+                "group" => "autoscaling", "version" => "v1"}
+    ]).unwrap();
+
+    assert!(!map.contains_key(&kutils::K8sKind::new("apps/v1", "Scale")));
+    assert!(!map.contains_key(&kutils::K8sKind::new("autoscaling/v1", "Scale")));
+
+    let o = object!{
+        "apiVersion" => "networking.k8s.io/v1",
+        "kind" => "Ingress",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    let mut url = Url::parse("http://dummy/").unwrap();
+    url.path_segments_mut().unwrap().clear();
+    api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &o, "default").unwrap();
+    assert_eq!(url.as_str(), "http://dummy/apis/networking.k8s.io/v1/namespaces/myns/ingresses/foo");
 }
 
 fn api_path_for_type(path: &mut url::PathSegmentsMut, map: &ApiMap, kind: &kutils::K8sKind, namespace: Option<&str>) -> Result<()> {
@@ -239,16 +1190,15 @@ fn api_path_for_type(path: &mut url::PathSegmentsMut, map: &ApiMap, kind: &kutil
     Ok(())
 }
 
-fn api_path_for<'a>(path: &mut url::PathSegmentsMut, map: &'a ApiMap, o: &'a JsonValue) -> Result<()> {
+fn api_path_for<'a>(path: &mut url::PathSegmentsMut, map: &'a ApiMap, o: &'a JsonValue, default_ns: &str) -> Result<()> {
     let kind = o.k8s_kind();
-    api_path_for_type(path, map, &kind, o.k8s_namespace())
+    api_path_for_type(path, map, &kind, Some(o.k8s_namespace().unwrap_or(default_ns)))
 }
 
-fn api_named_path_for<'a>(path: &mut url::PathSegmentsMut, map: &'a ApiMap, o: &'a JsonValue) -> Result<()> {
-    let name = o.k8s_name()
-        .ok_or_else(|| ErrorKind::MalformedObject(o.to_owned()))?;
+fn api_named_path_for<'a>(path: &mut url::PathSegmentsMut, map: &'a ApiMap, o: &'a JsonValue, default_ns: &str) -> Result<()> {
+    let name = kutils::require_name(o)?;
 
-    api_path_for(path, map, o)?;
+    api_path_for(path, map, o, default_ns)?;
     path.push(name);
 
     Ok(())
@@ -263,6 +1213,8 @@ fn test_api_paths() {
             name: "mykinds".to_string(),
             kind: "MyKind".to_string(),
             namespaced: true,
+            subresources: vec![],
+            custom: None,
         };
         map.insert(kind, res);
     }
@@ -277,7 +1229,7 @@ fn test_api_paths() {
         }
     };
     url.path_segments_mut().unwrap().clear();
-    assert!(api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json)
+    assert!(api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json, "default")
             .is_err());
 
     let json = object!{
@@ -289,148 +1241,4341 @@ fn test_api_paths() {
         }
     };
     url.path_segments_mut().unwrap().clear();
-    api_path_for(&mut url.path_segments_mut().unwrap(), &map, &json).unwrap();
+    api_path_for(&mut url.path_segments_mut().unwrap(), &map, &json, "default").unwrap();
     assert_eq!(url.to_string(), "http://dummy/apis/test/v0/namespaces/myns/mykinds");
 
     url.path_segments_mut().unwrap().clear();
-    api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json).unwrap();
+    api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json, "default").unwrap();
     assert_eq!(url.to_string(), "http://dummy/apis/test/v0/namespaces/myns/mykinds/foo");
+
+    let json = object!{
+        "apiVersion" => "test/v0",
+        "kind" => "MyKind",
+        "metadata" => object!{"name" => "foo"}
+    };
+    url.path_segments_mut().unwrap().clear();
+    api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json, "default").unwrap();
+    assert_eq!(url.to_string(), "http://dummy/apis/test/v0/namespaces/default/mykinds/foo");
+
+    // A CRD object without a name is reported by kind, not dumped
+    let json = object!{
+        "apiVersion" => "test/v0",
+        "kind" => "MyKind",
+        "metadata" => object!{"namespace" => "myns", "labels" => object!{"app" => "foo"}}
+    };
+    url.path_segments_mut().unwrap().clear();
+    let e = api_named_path_for(&mut url.path_segments_mut().unwrap(), &map, &json, "default").unwrap_err();
+    assert_eq!(e.to_string(), "MyKind (test/v0) in namespace myns has no metadata.name");
 }
 
-impl Context {
-    fn fetch_api_info(&mut self, api_version: &kutils::ApiVersion) -> Result<()> {
-        use std::collections::btree_map::Entry;
+/// Describe why a request failed to reach the server, in terms a
+/// user can act on.
+fn send_error_reason(e: &hyper::Error) -> String {
+    match *e {
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused =>
+            "connection refused".to_owned(),
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::TimedOut =>
+            "connection timed out".to_owned(),
+        // std reports getaddrinfo() failures with no specific ErrorKind
+        hyper::Error::Io(ref e) if e.to_string().contains("failed to lookup address") =>
+            "could not resolve host".to_owned(),
+        hyper::Error::Ssl(ref e) => {
+            let msg = e.to_string();
+            if msg.contains("certificate verify failed") {
+                "TLS handshake failed: untrusted certificate. Pass the server's CA with \
+                 --certificate-authority (or, in development only, use --insecure-skip-tls-verify)".to_owned()
+            } else {
+                format!("TLS handshake failed: {}", msg)
+            }
+        },
+        ref e => e.to_string(),
+    }
+}
 
-        let mut url = self.server_url.clone();
-        api_version.path_segments(&mut url.path_segments_mut().unwrap());
+#[test]
+fn test_send_error_reason() {
+    let refused = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "nope"));
+    assert_eq!(send_error_reason(&refused), "connection refused");
 
-        info!("=> GET {}", url);
-        let req = self.client.get(url)
-            .header(Accept::json());
+    let dns = hyper::Error::Io(io::Error::other("failed to lookup address information: Name or service not known"));
+    assert_eq!(send_error_reason(&dns), "could not resolve host");
 
-        let resp = req.send()
-            .chain_err(|| "Error sending request")?;
-        info!("<= {}", resp.status);
+    let untrusted = hyper::Error::Ssl(Box::new(io::Error::other(
+        "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate verify failed")));
+    assert_eq!(send_error_reason(&untrusted),
+               "TLS handshake failed: untrusted certificate. Pass the server's CA with \
+                --certificate-authority (or, in development only, use --insecure-skip-tls-verify)");
+    let other = hyper::Error::Ssl(Box::new(io::Error::other("unexpected eof")));
+    assert_eq!(send_error_reason(&other), "TLS handshake failed: unexpected eof");
 
-        let list = kube_result(resp)?;
-        let group_version = list["groupVersion"].as_str()
-            .ok_or_else(|| ErrorKind::MalformedObject(list.clone()))?;
-        for r in list["resources"].members() {
-            let api = kutils::ApiResource::new_from_json(r)?;
-            let kind = kutils::K8sKind::new(group_version, &api.kind);
-            match self.api_cache.entry(kind) {
-                Entry::Vacant(e) => { e.insert(api); },
-                Entry::Occupied(mut e) => {
-                    // Take the shortest name, in an attempt to find
-                    // the core CRUD endpoint
-                    if e.get().name.len() > api.name.len() {
-                        e.insert(api);
-                    }
-                },
-            };
-        }
+    let port = {
+        let l = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        l.local_addr().unwrap().port()
+    };
+    let url = format!("http://127.0.0.1:{}/", port);
+    let e = Client::new().get(&url).send().unwrap_err();
+    assert_eq!(send_error_reason(&e), "connection refused");
+}
 
-        Ok(())
-    }
+/// An HTTP client for the API server, adding the headers every
+/// request needs.
+struct ApiClient {
+    client: Client,
+    token: Option<auth::Token>,
+    user_agent: String,
+    /// Ask for gzip-compressed responses (`--response-compression`)
+    accept_gzip: bool,
+    /// Retries of each request after a transient failure (`--max-retries`)
+    max_retries: u32,
+    /// Shared by every retry in the run, including update conflicts
+    retry_budget: Mutex<RetryBudget>,
+    /// Set to stop starting new work: `INTERRUPTED`, other than in tests
+    interrupted: &'static AtomicBool,
+}
 
-    fn url_for(&mut self, o: &JsonValue, named: bool) -> Result<Url> {
-        let kind = o.k8s_kind();
-        if !self.api_cache.contains_key(&kind) {
-            self.fetch_api_info(&kind.api_version)?;
+impl ApiClient {
+    fn new(client: Client) -> Self {
+        ApiClient {
+            client,
+            token: None,
+            user_agent: default_user_agent(None),
+            accept_gzip: false,
+            max_retries: 0,
+            retry_budget: Mutex::new(RetryBudget::default()),
+            interrupted: &INTERRUPTED,
         }
+    }
 
-        let path_func = if named { api_named_path_for } else { api_path_for };
-        let mut url = self.server_url.clone();
-        path_func(&mut url.path_segments_mut().unwrap(), &self.api_cache, o)?;
-        Ok(url)
+    /// Whether SIGINT has been received (see `catch_interrupts`)
+    fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
     }
-}
 
-fn init_vm_options<'a>(vm: &mut JsonnetVm, matches: &ArgMatches<'a>) {
-    if let Some(paths) = matches.values_of_os("jpath") {
-        for path in paths {
-            vm.jpath_add(path);
-        }
+    /// Send a request to the API server.
+    fn request(&self, method: Method, url: Url, body: Option<(ContentType, &str)>) -> Result<Response> {
+        self.request_at(log::Level::Info, method, url, body)
     }
 
-    if let Some(vars) = matches.values_of("ext-var") {
-        for (var, val) in vars.map(parse_kv) {
-            vm.ext_var(var, val);
+    /// As `request`, but logging the request and response at `level`.
+    ///
+    /// Connection errors and transient server errors are retried up to
+    /// `max_retries` times, with exponential backoff.  A POST (create)
+    /// is only retried if the server can't have acted on it, since
+    /// sending it again would otherwise fail with AlreadyExists.
+    fn request_at(&self, level: log::Level, method: Method, url: Url, body: Option<(ContentType, &str)>) -> Result<Response> {
+        let token = match self.token {
+            Some(ref t) => Some(t.value()?),
+            None => None,
+        };
+        let mut attempt = 0;
+        let r = loop {
+            let r = self.send(level, method.clone(), &url, body.clone(), token.as_ref());
+            let idempotent = method != Method::Post;
+            let wait = match r {
+                Err(ref e) if idempotent || never_sent(e) => Some(backoff(attempt)),
+                Ok(ref resp) if is_transient(resp.status) &&
+                    (idempotent || matches!(resp.status.to_u16(), 429 | 503)) =>
+                    Some(retry_after(resp).unwrap_or_else(|| backoff(attempt))),
+                _ => None,
+            };
+            let wait = match wait {
+                Some(wait) if attempt < self.max_retries && !self.interrupted() &&
+                    self.retry_budget.lock().unwrap().try_retry() => wait,
+                _ => break r,
+            };
+
+            attempt += 1;
+            let reason = match r {
+                Ok(ref resp) => resp.status.to_string(),
+                Err(ref e) => send_error_reason(e),
+            };
+            info!("{} {}: {}, retrying in {}ms ({}/{})", method, url, reason, wait.as_millis(), attempt, self.max_retries);
+            // Sleep in steps, so an interrupt doesn't wait for the backoff
+            let deadline = Instant::now() + wait;
+            while !self.interrupted() && Instant::now() < deadline {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()).min(INTERRUPT_POLL));
+            }
+            self.retry_budget.lock().unwrap().charge(wait);
+            if self.interrupted() {
+                break r;
+            }
+        };
+
+        r.map_err(|e| {
+            let reason = send_error_reason(&e);
+            Error::with_chain(e, ErrorKind::Connection(url.to_string(), reason))
+        })
+    }
+
+    fn send(&self, level: log::Level, method: Method, url: &Url, body: Option<(ContentType, &str)>, token: Option<&String>) -> hyper::Result<Response> {
+        log!(level, "=> {} {}", method, url);
+        let mut req = self.client.request(method, url.as_str())
+            .header(Accept::json())
+            .header(UserAgent(self.user_agent.clone()));
+        if let Some(token) = token {
+            req = req.header(Authorization(Bearer { token: token.clone() }));
+        }
+        // Watches are read a line at a time, so can't be compressed
+        if self.accept_gzip && !url.query_pairs().any(|(k, _)| k == "watch") {
+            req = req.header(AcceptEncoding(vec![qitem(Encoding::Gzip)]));
         }
+        if let Some((content_type, body)) = body {
+            req = req.header(content_type).body(body);
+        }
+
+        let resp = req.send()?;
+        log!(level, "<= {}", resp.status);
+
+        Ok(resp)
     }
 }
 
-fn eval_file_or_snippet<'a, 'b>(vm: &'b mut JsonnetVm, matches: &ArgMatches<'a>) -> Result<&'b str> {
-    let result = if let Some(filename) = matches.value_of_os("file") {
-        vm.evaluate_file(filename)
-    } else if let Some(expr) = matches.value_of("exec") {
-        vm.evaluate_snippet("exec", expr)
-    } else {
-        unreachable!()
-    };
-
-    result
-        .map(|v| v.as_str())
-        .map_err(|e| e.as_str().to_owned().into())
+/// Whether the request failing with `e` can't have reached the server
+fn never_sent(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref e) => e.kind() == io::ErrorKind::ConnectionRefused ||
+            e.to_string().contains("failed to lookup address"),
+        _ => false,
+    }
 }
 
-fn do_show<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
-    where W: Write
-{
-    init_vm_options(&mut c.vm, matches);
+/// Whether a request getting `status` may succeed if sent again
+fn is_transient(status: hyper::status::StatusCode) -> bool {
+    matches!(status.to_u16(), 429 | 500 | 502 | 503 | 504)
+}
 
-    let json_text = eval_file_or_snippet(&mut c.vm, matches)?;
+/// The delay before retry `attempt` (from 0) of a transient failure:
+/// exponential, with jitter so many clients don't all retry at once.
+fn backoff(attempt: u32) -> Duration {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher,Hasher};
 
-    let json = json::parse(json_text)
-        .chain_err(|| "Unable to parse jsonnet output")?;
+    let max = TRANSIENT_BACKOFF.saturating_mul(1 << attempt.min(16)).min(MAX_BACKOFF);
+    // Anywhere from half to all of `max`
+    let random = RandomState::new().build_hasher().finish();
+    max / 2 + Duration::from_nanos(random % (max.as_nanos() as u64 / 2 + 1))
+}
 
-    let output: OutputFormat = matches.value_of("format").unwrap().parse()?;
-    output.emit(&json, w)
+/// The `Retry-After` delay (in seconds) of a 429 or 503 response
+fn retry_after(resp: &Response) -> Option<Duration> {
+    if !matches!(resp.status.to_u16(), 429 | 503) {
+        return None;
+    }
+    let value = resp.headers.get_raw("Retry-After")?.first()?;
+    let secs = ::std::str::from_utf8(value).ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs).min(MAX_RETRY_AFTER))
 }
 
-fn do_create<'a>(c: &mut Context, matches: &ArgMatches<'a>) -> Result<()> {
-    init_vm_options(&mut c.vm, matches);
+#[test]
+fn test_backoff() {
+    for attempt in 0..20 {
+        let d = backoff(attempt);
+        let max = (TRANSIENT_BACKOFF * 2u32.pow(attempt.min(16))).min(MAX_BACKOFF);
+        assert!(d >= max / 2 && d <= max, "{:?} for attempt {}", d, attempt);
+    }
+}
+
+#[test]
+fn test_request_retries() {
+    let busy = r#"{"kind":"Status","code":503}"#;
+    let (url, server) = mock_server(vec![(500, busy), (503, busy), (200, "{}")]);
+    let mut client = ApiClient::new(Client::new());
+    client.max_retries = 3;
+    let resp = client.request(Method::Get, url.join("api/v1").unwrap(), None).unwrap();
+    assert_eq!(resp.status, hyper::Ok);
+    assert_eq!(server.join().unwrap().len(), 3);
+
+    // Out of retries, the last response is returned
+    let (url, server) = mock_server(vec![(503, busy), (503, busy)]);
+    client.max_retries = 1;
+    let resp = client.request(Method::Get, url.join("api/v1").unwrap(), None).unwrap();
+    assert_eq!(resp.status, hyper::status::StatusCode::ServiceUnavailable);
+    assert_eq!(server.join().unwrap().len(), 2);
+
+    // Other errors aren't retried
+    let (url, server) = mock_server(vec![(409, r#"{"kind":"Status","code":409}"#)]);
+    let resp = client.request(Method::Get, url.join("api/v1").unwrap(), None).unwrap();
+    assert_eq!(resp.status, hyper::status::StatusCode::Conflict);
+    assert_eq!(server.join().unwrap().len(), 1);
+
+    // A create may have happened despite a 500 or 504, but not a 503
+    let body = Some((ContentType::json(), "{}"));
+    let (url, server) = mock_server(vec![(504, busy)]);
+    let resp = client.request(Method::Post, url.join("api/v1").unwrap(), body.clone()).unwrap();
+    assert_eq!(resp.status, hyper::status::StatusCode::GatewayTimeout);
+    assert_eq!(server.join().unwrap().len(), 1);
+    let (url, server) = mock_server(vec![(503, busy), (201, "{}")]);
+    let resp = client.request(Method::Post, url.join("api/v1").unwrap(), body).unwrap();
+    assert_eq!(resp.status, hyper::status::StatusCode::Created);
+    assert_eq!(server.join().unwrap().len(), 2);
+
+    // Nor once interrupted
+    client.interrupted = Box::leak(Box::new(AtomicBool::new(true)));
+    let (url, server) = mock_server(vec![(503, busy)]);
+    let resp = client.request(Method::Get, url.join("api/v1").unwrap(), None).unwrap();
+    assert_eq!(resp.status, hyper::status::StatusCode::ServiceUnavailable);
+    assert_eq!(server.join().unwrap().len(), 1);
+}
+
+/// eg: `kubecfg/0.1.0 update (jsonnet v0.17.0)`
+fn default_user_agent(operation: Option<&str>) -> String {
+    match operation {
+        Some(op) => format!("kubecfg/{} {} (jsonnet {})", crate_version!(), op, jsonnet_version()),
+        None => format!("kubecfg/{} (jsonnet {})", crate_version!(), jsonnet_version()),
+    }
+}
+
+impl Context {
+    fn request(&self, method: Method, url: Url, body: Option<(ContentType, &str)>) -> Result<Response> {
+        self.client.request(method, url, body)
+    }
+
+    fn fetch_api_info(&mut self, api_version: &kutils::ApiVersion) -> Result<()> {
+        let mut url = self.server_url.clone();
+        api_version.path_segments(&mut url.path_segments_mut().unwrap());
+
+        let resp = self.request(Method::Get, url, None)?;
+
+        let list = kube_result(resp)?;
+        let group_version = list["groupVersion"].as_str()
+            .ok_or_else(|| ErrorKind::MalformedObject(list.clone()))?;
+        add_api_resources(&mut self.api_cache, group_version, &list["resources"])
+    }
+
+    fn api_resource(&mut self, kind: &kutils::K8sKind) -> Result<&kutils::ApiResource> {
+        if !self.api_cache.contains_key(kind) {
+            self.fetch_api_info(&kind.api_version)?;
+        }
+
+        self.api_cache.get(kind)
+            .ok_or_else(|| ErrorKind::UnknownResource(format!("{}", kind)).into())
+    }
+
+    /// How to patch objects of `kind`.  For a kind that might be a
+    /// custom resource, this looks for its CRD, once per kind.
+    fn patch_type(&mut self, kind: &kutils::K8sKind) -> Result<kutils::PatchType> {
+        let group = &kind.api_version.group;
+        if !group.contains('.') || self.patch_types.iter().any(|(k, _)| k == kind) {
+            return Ok(kutils::PatchType::for_kind(kind, None, &self.patch_types));
+        }
+
+        if self.api_resource(kind)?.custom.is_none() {
+            let crd = format!("{}.{}", self.api_resource(kind)?.name, group);
+            let mut url = self.server_url.clone();
+            url.path_segments_mut().unwrap()
+                .pop_if_empty()
+                .extend(&["apis", "apiextensions.k8s.io", "v1", "customresourcedefinitions", &crd]);
+            let resp = self.client.request_at(log::Level::Debug, Method::Get, url, None)?;
+            let custom = match resp.status {
+                hyper::Ok => Some(true),
+                hyper::NotFound => Some(false),
+                // eg not allowed to read CRDs
+                status => {
+                    debug!("Unable to check for CRD {}: {}", crd, status);
+                    None
+                },
+            };
+            if let Some(r) = self.api_cache.get_mut(kind) {
+                r.custom = custom;
+            }
+        }
+
+        Ok(kutils::PatchType::for_kind(kind, self.api_cache.get(kind), &self.patch_types))
+    }
+
+    /// Populate `api_cache` with every resource the server knows about
+    /// (in the preferred version of each API group).
+    fn fetch_all_api_info(&mut self) -> Result<()> {
+        let mut versions = vec![];
+
+        let mut url = self.server_url.clone();
+        url.path_segments_mut().unwrap().push("api");
+        let core = kube_result(self.request(Method::Get, url, None)?)?;
+        for v in core["versions"].members().filter_map(|v| v.as_str()) {
+            versions.push(kutils::ApiVersion::from(v));
+        }
+
+        let mut url = self.server_url.clone();
+        url.path_segments_mut().unwrap().push("apis");
+        let groups = kube_result(self.request(Method::Get, url, None)?)?;
+        for g in groups["groups"].members() {
+            if let Some(gv) = g["preferredVersion"]["groupVersion"].as_str() {
+                versions.push(kutils::ApiVersion::from(gv));
+            }
+        }
+
+        for v in versions {
+            self.fetch_api_info(&v)?;
+        }
+        Ok(())
+    }
+
+    fn fetch_schema(&self) -> Result<schema::Schema> {
+        let mut url = self.server_url.clone();
+        url.path_segments_mut().unwrap()
+            .extend(&["openapi", "v2"]);
+
+        let resp = self.request(Method::Get, url, None)?;
+        Ok(schema::Schema::new_from_json(kube_result(resp)?))
+    }
+
+    /// Serialise `o` as a (JSON) request body, enforcing
+    /// `--max-object-size`
+    fn object_body(&self, o: &JsonValue) -> Result<String> {
+        self.check_size(o, o.dump())
+    }
+
+    /// Serialise `o` for a create (POST) request, in `--request-format`.
+    /// Patches are always JSON.
+    fn create_body(&self, o: &JsonValue) -> Result<(ContentType, String)> {
+        match self.request_format {
+            OutputFormat::Yaml => {
+                let body = yaml_string(o)?;
+                Ok((ContentType("application/yaml".parse().unwrap()), self.check_size(o, body)?))
+            },
+            _ => Ok((ContentType::json(), self.object_body(o)?)),
+        }
+    }
+
+    fn check_size(&self, o: &JsonValue, body: String) -> Result<String> {
+        match self.max_object_size {
+            Some(limit) if body.len() > limit =>
+                Err(ErrorKind::ObjectTooLarge(o.k8s_id().to_string(), body.len(), limit).into()),
+            _ => Ok(body),
+        }
+    }
+
+    fn url_for(&mut self, o: &JsonValue, named: bool) -> Result<Url> {
+        let kind = o.k8s_kind();
+        if !self.api_cache.contains_key(&kind) {
+            self.fetch_api_info(&kind.api_version)?;
+        }
+
+        self.log_default_namespace(&kind, o.k8s_namespace());
+
+        let path_func = if named { api_named_path_for } else { api_path_for };
+        let mut url = self.server_url.clone();
+        path_func(&mut url.path_segments_mut().unwrap(), &self.api_cache, o,
+                  &self.default_namespace)?;
+        Ok(url)
+    }
+
+    fn url_for_id(&mut self, id: &kutils::ObjectId) -> Result<Url> {
+        self.api_resource(&id.kind)?;
+        self.log_default_namespace(&id.kind, id.namespace.as_deref());
+
+        let mut url = self.server_url.clone();
+        {
+            let mut path = url.path_segments_mut().unwrap();
+            let ns = id.namespace.as_deref().unwrap_or(&self.default_namespace);
+            api_path_for_type(&mut path, &self.api_cache, &id.kind, Some(ns))?;
+            path.push(&id.name);
+        }
+        Ok(url)
+    }
+
+    /// The identity of `o`, with the namespace it will actually be
+    /// created in.
+    ///
+    /// Namespaced objects use their own `metadata.namespace`, falling
+    /// back to `--default-namespace`.
+    fn resolved_id(&mut self, o: &JsonValue) -> Result<kutils::ObjectId> {
+        let mut id = o.k8s_id();
+        if id.namespace.is_none() && self.api_resource(&id.kind)?.namespaced {
+            self.log_default_namespace(&id.kind, None);
+            id.namespace = Some(self.default_namespace.clone());
+        }
+        Ok(id)
+    }
+
+    /// With `--force-namespace`, remove `metadata.namespace` from
+    /// every object so that namespaced ones use `--namespace`.
+    fn apply_force_namespace(&self, parsed: &mut JsonValue) {
+        if self.force_namespace {
+            kutils::for_each_object_mut(parsed, |o| { o["metadata"].remove("namespace"); });
+        }
+    }
+
+    fn log_default_namespace(&self, kind: &kutils::K8sKind, namespace: Option<&str>) {
+        let namespaced = self.api_cache.get(kind).map(|a| a.namespaced).unwrap_or(false);
+        if namespace.is_none() && namespaced {
+            debug!("No namespace for {}, using {} (from {})",
+                   kind, self.default_namespace, self.default_namespace_source);
+        }
+    }
+}
+
+/// Find objects matching `selector` that are of the same kind (and
+/// namespace) as something in `objects`, but are not themselves in
+/// `objects`.  With `allowed_namespaces`, only objects in those
+/// namespaces are considered (so never cluster-scoped objects).
+fn find_prunable(c: &mut Context, objects: &[&JsonValue], selector: &str, allowed_namespaces: Option<&[&str]>) -> Result<Vec<kutils::ObjectId>> {
+    let mut wanted = BTreeSet::new();
+    let mut scopes = BTreeMap::new();
+    for o in objects {
+        let id = c.resolved_id(o)?;
+        scopes.entry(id.kind.clone())
+            .or_insert_with(BTreeSet::new)
+            .insert(id.namespace.clone());
+        wanted.insert(id);
+    }
+
+    let mut prunable = Vec::new();
+    for (kind, namespaces) in scopes {
+        let namespaces: Vec<_> = if c.api_resource(&kind)?.namespaced {
+            // Never list across all namespaces
+            namespaces.into_iter().filter(|ns| ns.is_some()).collect()
+        } else {
+            vec![None]
+        };
+
+        for ns in namespaces {
+            if let Some(allowed) = allowed_namespaces {
+                if !ns.as_deref().is_some_and(|ns| allowed.contains(&ns)) {
+                    info!("Not pruning {} in {}: not a --prune-namespace", kind,
+                          ns.as_deref().unwrap_or("the cluster scope"));
+                    continue;
+                }
+            }
+
+            let mut url = c.server_url.clone();
+            api_path_for_type(&mut url.path_segments_mut().unwrap(), &c.api_cache, &kind,
+                              ns.as_deref())?;
+            url.query_pairs_mut().append_pair("labelSelector", selector);
+
+            for item in list_all(c, url)? {
+                // List items don't carry apiVersion/kind
+                let id = kutils::ObjectId {
+                    kind: kind.clone(),
+                    namespace: item.k8s_namespace().map(|s| s.to_owned()),
+                    name: item.k8s_name()
+                        .ok_or_else(|| ErrorKind::MalformedObject(item.clone()))?
+                        .to_owned(),
+                };
+                if !wanted.contains(&id) {
+                    prunable.push(id);
+                }
+            }
+        }
+    }
+
+    Ok(prunable)
+}
+
+#[test]
+fn test_find_prunable_namespaces() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"kind":"ConfigMapList","items":[{"metadata":{"name":"keep","namespace":"a"}},{"metadata":{"name":"old","namespace":"a"}}]}"#),
+    ]);
+    let mut c = mock_context(url);
+    let keep_a = object!{"apiVersion" => "v1", "kind" => "ConfigMap", "metadata" => object!{"name" => "keep", "namespace" => "a"}};
+    let keep_b = object!{"apiVersion" => "v1", "kind" => "ConfigMap", "metadata" => object!{"name" => "keep", "namespace" => "b"}};
+
+    let prunable = find_prunable(&mut c, &[&keep_a, &keep_b], "app=foo", Some(&["a"])).unwrap();
+    let names: Vec<_> = prunable.iter().map(|id| id.to_string()).collect();
+    assert_eq!(names, vec!["configmap/old in namespace a"]);
+    // Namespace b is never listed
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/a/configmaps?labelSelector=app%3Dfoo HTTP/1.1",
+    ]);
+
+    let mut c = mock_context(Url::parse("http://dummy/").unwrap());
+    assert!(find_prunable(&mut c, &[&keep_b], "app=foo", Some(&[])).unwrap().is_empty());
+}
+
+/// GET the list at `url`, a page of `--chunk-size` items at a time
+fn list_all(c: &Context, url: Url) -> Result<Vec<JsonValue>> {
+    let mut items = vec![];
+    let mut token: Option<String> = None;
+    loop {
+        let mut page_url = url.clone();
+        if let Some(n) = c.chunk_size {
+            let mut query = page_url.query_pairs_mut();
+            query.append_pair("limit", &n.to_string());
+            if let Some(ref t) = token {
+                query.append_pair("continue", t);
+            }
+        }
+
+        let mut list = kube_result(c.request(Method::Get, page_url, None)?)?;
+        if let JsonValue::Array(page) = list["items"].take() {
+            items.extend(page);
+        }
+        match list["metadata"]["continue"].as_str() {
+            Some(t) if c.chunk_size.is_some() && !t.is_empty() => token = Some(t.to_owned()),
+            _ => return Ok(items),
+        }
+    }
+}
+
+#[test]
+fn test_list_all() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"kind":"ConfigMapList","metadata":{"continue":"page2"},"items":[{"metadata":{"name":"a"}},{"metadata":{"name":"b"}}]}"#),
+        (200, r#"{"kind":"ConfigMapList","metadata":{"continue":""},"items":[{"metadata":{"name":"c"}}]}"#),
+    ]);
+    let mut c = mock_context(url.clone());
+    c.chunk_size = Some(2);
+
+    let items = list_all(&c, url.join("api/v1/configmaps").unwrap()).unwrap();
+    let names: Vec<_> = items.iter().map(|i| i.k8s_name().unwrap()).collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/configmaps?limit=2 HTTP/1.1",
+        "GET /api/v1/configmaps?limit=2&continue=page2 HTTP/1.1",
+    ]);
+}
+
+#[test]
+fn test_low_bandwidth() {
+    let context = |args: &[&str]| {
+        let mut argv = vec!["kubecfg", "--server", "http://localhost:8001/"];
+        argv.extend(args);
+        argv.extend(&["show", "-f", "x"]);
+        build_context(&build_cli("test").get_matches_from(argv)).unwrap()
+    };
+
+    let c = context(&[]);
+    assert!(!c.client.accept_gzip);
+    assert_eq!(c.chunk_size, None);
+
+    let c = context(&["--low-bandwidth"]);
+    assert!(c.client.accept_gzip);
+    assert_eq!(c.chunk_size, Some(LOW_BANDWIDTH_CHUNK_SIZE));
+
+    let c = context(&["--low-bandwidth", "--response-compression", "none", "--chunk-size", "0"]);
+    assert!(!c.client.accept_gzip);
+    assert_eq!(c.chunk_size, None);
+}
+
+/// How long `__complete kinds` trusts previously discovered kinds
+const COMPLETION_CACHE_TTL: Duration = Duration::from_secs(600);
+
+fn completion_cache_path(server_url: &Url) -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))?;
+    Some(base.join("kubecfg")
+         .join(format!("kinds-{}.json", kutils::filename_safe(server_url.as_str()))))
+}
+
+fn read_cached_kinds(path: &Path) -> Option<Vec<String>> {
+    let age = fs::metadata(path).ok()?
+        .modified().ok()?
+        .elapsed().ok()?;
+    if age > COMPLETION_CACHE_TTL {
+        return None;
+    }
+
+    let text = fs::read_to_string(path).ok()?;
+    let kinds = json::parse(&text).ok()?;
+    Some(kinds.members().filter_map(|k| k.as_str()).map(|k| k.to_owned()).collect())
+}
+
+fn write_cached_kinds(path: &Path, kinds: &[String]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, JsonValue::from(kinds.to_vec()).dump())?;
+    Ok(())
+}
+
+/// All the kinds known to the server, cached on disk so repeated
+/// completions stay fast.
+fn list_kinds(c: &mut Context) -> Result<Vec<String>> {
+    let cache = completion_cache_path(&c.server_url);
+    if let Some(kinds) = cache.as_ref().and_then(|p| read_cached_kinds(p)) {
+        return Ok(kinds);
+    }
+
+    c.fetch_all_api_info()?;
+    let kinds: BTreeSet<_> = c.api_cache.values()
+        .map(|api| api.kind.clone())
+        .collect();
+    let kinds: Vec<_> = kinds.into_iter().collect();
+
+    if let Some(path) = cache {
+        if let Err(e) = write_cached_kinds(&path, &kinds) {
+            debug!("Unable to write {}: {}", path.display(), e);
+        }
+    }
+    Ok(kinds)
+}
+
+fn list_namespaces(c: &mut Context) -> Result<Vec<String>> {
+    let mut url = c.server_url.clone();
+    url.path_segments_mut().unwrap().extend(&["api", "v1", "namespaces"]);
+
+    let list = kube_result(c.request(Method::Get, url, None)?)?;
+    Ok(list["items"].members()
+       .filter_map(|ns| ns.k8s_name())
+       .map(|ns| ns.to_owned())
+       .collect())
+}
+
+/// Candidates that start with `prefix`, ignoring case (kinds are
+/// accepted case-insensitively).
+fn completions_for<'a>(candidates: &'a [String], prefix: &str) -> Vec<&'a str> {
+    let prefix = prefix.to_lowercase();
+    candidates.iter()
+        .filter(|c| c.to_lowercase().starts_with(&prefix))
+        .map(|c| c.as_str())
+        .collect()
+}
+
+#[test]
+fn test_completions_for() {
+    let kinds: Vec<_> = ["ConfigMap", "CronJob", "Deployment"].iter()
+        .map(|k| k.to_string())
+        .collect();
+    assert_eq!(completions_for(&kinds, "c"), vec!["ConfigMap", "CronJob"]);
+    assert_eq!(completions_for(&kinds, "Dep"), vec!["Deployment"]);
+    assert_eq!(completions_for(&kinds, ""), vec!["ConfigMap", "CronJob", "Deployment"]);
+    assert!(completions_for(&kinds, "x").is_empty());
+}
+
+fn do_complete<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    let candidates = match matches.value_of("what").unwrap() {
+        "kinds" => list_kinds(c)?,
+        "namespaces" => list_namespaces(c)?,
+        _ => unreachable!(),
+    };
+
+    for word in completions_for(&candidates, matches.value_of("prefix").unwrap()) {
+        writeln!(w, "{}", word)?;
+    }
+    Ok(())
+}
+
+/// Resolve imports the same way as the default jsonnet importer
+/// (importing directory, then library search paths with the last
+/// one winning), except that with `remote`, imports from an http(s)
+/// file are first fetched relative to its URL.  With `trace`, report
+/// where each import was found.
+fn set_importer(vm: &mut JsonnetVm, jpaths: Vec<PathBuf>, trace: bool, remote: bool) {
+    vm.import_callback(move |_vm, base, rel| {
+        let url = base.to_str()
+            .and_then(|b| Url::parse(b).ok())
+            .filter(|u| remote && (u.scheme() == "http" || u.scheme() == "https"))
+            .and_then(|b| b.join(&rel.to_string_lossy()).ok());
+
+        let mut fetch_error = None;
+        if let Some(ref url) = url {
+            match fetch_remote(url) {
+                Ok(contents) => {
+                    if trace {
+                        let _ = writeln!(io::stderr(), "import {}: {}", rel.display(), url);
+                    }
+                    return Ok((PathBuf::from(url.as_str()), contents));
+                },
+                Err(e) => fetch_error = Some(e.to_string()),
+            }
+        }
+
+        let local = if url.is_some() { None } else { Some((base.join(rel), None)) };
+        let candidates = local.into_iter()
+            .chain(jpaths.iter().rev().map(|dir| (dir.join(rel), Some(dir))));
+
+        for (path, dir) in candidates {
+            if let Ok(contents) = fs::read_to_string(&path) {
+                if trace {
+                    let _ = match dir {
+                        None => writeln!(io::stderr(), "import {}: {}",
+                                         rel.display(), path.display()),
+                        Some(dir) => writeln!(io::stderr(), "import {}: {} (from jpath {})",
+                                              rel.display(), path.display(), dir.display()),
+                    };
+                }
+                return Ok((path, contents));
+            }
+        }
+
+        Err(fetch_error.unwrap_or_else(|| {
+            format!("couldn't open import \"{}\": no match locally or in the Jsonnet library paths",
+                    rel.display())
+        }))
+    });
+}
+
+fn init_vm_options<'a>(vm: &mut JsonnetVm, matches: &ArgMatches<'a>) -> Result<()> {
+    let mut jpaths = vec![];
+    if let Some(paths) = env::var_os(OsStr::new(JPATH_ENVVAR)) {
+        jpaths.extend(env::split_paths(&paths));
+    }
+    // Command line values are applied later, taking precedence
+    if let Some(path) = matches.value_of_os("config_file") {
+        apply_config_file(vm, Path::new(path), &mut jpaths)?;
+    }
+    if let Some(paths) = matches.values_of_os("jpath") {
+        jpaths.extend(paths.map(PathBuf::from));
+    }
+
+    for path in &jpaths {
+        vm.jpath_add(path);
+    }
+
+    let trace = matches.is_present("trace_imports");
+    let remote = matches.is_present("allow_remote_files");
+    if trace || remote {
+        set_importer(vm, jpaths, trace, remote);
+    }
+
+    if let Some(vars) = matches.values_of("ext_var") {
+        for (var, val) in vars.map(parse_kv) {
+            vm.ext_var(var, val);
+        }
+    }
+    if let Some(vars) = matches.values_of("ext_code") {
+        for (var, code) in vars.map(parse_kv) {
+            vm.ext_code(var, code);
+        }
+    }
+    if let Some(vars) = matches.values_of("tla_var") {
+        for (var, val) in vars.map(parse_kv) {
+            vm.tla_var(var, val);
+        }
+    }
+    if let Some(vars) = matches.values_of("tla_code") {
+        for (var, code) in vars.map(parse_kv) {
+            vm.tla_code(var, code);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_jsonnet_var_args() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-tla-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("main.jsonnet");
+    fs::write(&file, "function(name, replicas=1) {name: name, replicas: replicas, env: std.extVar('env'), debug: std.extVar('debug')}").unwrap();
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "show", "--tla-var", "name=web", "--tla-code", "replicas=1 + 2",
+        "-V", "env=prod", "--ext-code", "debug=false",
+        "-f", file.to_str().unwrap(),
+    ]);
+    let show = matches.subcommand_matches("show").unwrap();
+    let mut vm = JsonnetVm::new();
+    init_vm_options(&mut vm, show).unwrap();
+    let json = eval_file_or_snippet(&mut vm, show).unwrap();
+    assert_eq!(json::parse(&json).unwrap(), object!{"name" => "web", "replicas" => 3, "env" => "prod", "debug" => false});
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn config_file_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("config_file")
+        .long("config-file")
+        .value_name("FILE")
+        .help("JSON file setting jsonnet options: {extVars: {...}, extCode: {...}, tlaVars: {...}, jpath: [...]}. Command line options take precedence")
+}
+
+/// Apply the jsonnet options in `--config-file` `path` to `vm`,
+/// adding its `jpath` entries (relative to the file) to `jpaths`.
+/// `extVars` and `tlaVars` values that aren't strings are passed as
+/// code.
+fn apply_config_file(vm: &mut JsonnetVm, path: &Path, jpaths: &mut Vec<PathBuf>) -> Result<()> {
+    let context = || format!("Invalid config file {}", path.display());
+    let text = fs::read_to_string(path)
+        .chain_err(|| format!("Unable to read {}", path.display()))?;
+    let config = json::parse(&text).chain_err(context)?;
+    if !config.is_object() {
+        return Err(Error::from("expected an object").chain_err(context));
+    }
+
+    for (key, value) in config.entries() {
+        let expect = |kind: &str| -> Result<()> {
+            Err(Error::from(format!("{} must be {}", key, kind)).chain_err(context))
+        };
+        match key {
+            "extVars" | "extCode" | "tlaVars" if !value.is_object() => expect("an object")?,
+            "extVars" => for (k, v) in value.entries() {
+                match v.as_str() {
+                    Some(s) => vm.ext_var(k, s),
+                    None => vm.ext_code(k, &v.dump()),
+                }
+            },
+            "extCode" => for (k, v) in value.entries() {
+                match v.as_str() {
+                    Some(code) => vm.ext_code(k, code),
+                    None => expect("an object of strings")?,
+                }
+            },
+            "tlaVars" => for (k, v) in value.entries() {
+                match v.as_str() {
+                    Some(s) => vm.tla_var(k, s),
+                    None => vm.tla_code(k, &v.dump()),
+                }
+            },
+            "jpath" => {
+                if !value.is_array() || !value.members().all(|v| v.is_string()) {
+                    expect("a list of strings")?;
+                }
+                let base = path.parent().unwrap_or_else(|| Path::new(""));
+                jpaths.extend(value.members().map(|v| base.join(v.as_str().unwrap())));
+            },
+            _ => return Err(Error::from(format!("unknown key {}", key)).chain_err(context)),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_config_file() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-config-{}", ::std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib/lib.libsonnet"), "{ greeting: 'hi' }").unwrap();
+    let config = dir.join("config.json");
+    fs::write(&config, r#"{
+        "extVars": {"env": "prod", "replicas": 3},
+        "extCode": {"flags": "{debug: false}"},
+        "tlaVars": {"name": "web", "ports": [80, 443]},
+        "jpath": ["lib"]
+    }"#).unwrap();
+
+    let mut vm = JsonnetVm::new();
+    let mut jpaths = vec![];
+    apply_config_file(&mut vm, &config, &mut jpaths).unwrap();
+    assert_eq!(jpaths, vec![dir.join("lib")]);
+    for p in &jpaths {
+        vm.jpath_add(p);
+    }
+    let result = vm.evaluate_snippet("test", r#"
+        function(name, ports) {
+            env: std.extVar('env'),
+            replicas: std.extVar('replicas') + 1,
+            debug: std.extVar('flags').debug,
+            name: name,
+            ports: ports,
+            greeting: (import 'lib.libsonnet').greeting,
+        }"#).unwrap();
+    assert_eq!(json::parse(&result).unwrap(), object!{
+        "env" => "prod",
+        "replicas" => 4,
+        "debug" => false,
+        "name" => "web",
+        "ports" => array![80, 443],
+        "greeting" => "hi"
+    });
+
+    for bad in &[r#"[]"#, r#"{"extVar": {}}"#, r#"{"extCode": {"a": 1}}"#, r#"{"jpath": "lib"}"#, r#"{"tlaVars": 1}"#] {
+        fs::write(&config, bad).unwrap();
+        assert!(apply_config_file(&mut JsonnetVm::new(), &config, &mut vec![]).is_err(), "{}", bad);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Apply `--chdir`
+fn chdir<'a>(matches: &ArgMatches<'a>) -> Result<()> {
+    if let Some(dir) = matches.value_of_os("chdir") {
+        env::set_current_dir(dir)
+            .chain_err(|| format!("Unable to change directory to {}", Path::new(dir).display()))?;
+    }
+    Ok(())
+}
+
+/// Serialises tests that depend on the process working directory
+#[cfg(test)]
+static CWD_LOCK: ::std::sync::Mutex<()> = ::std::sync::Mutex::new(());
+
+#[test]
+fn test_chdir() {
+    let _lock = CWD_LOCK.lock().unwrap();
+    let orig = env::current_dir().unwrap();
+    let dir = env::temp_dir().join(format!("kubecfg-test-chdir-{}", ::std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("main.jsonnet"), "import 'lib/value.libsonnet'").unwrap();
+    fs::write(dir.join("lib/value.libsonnet"), "{a: 42}").unwrap();
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "-C", dir.to_str().unwrap(), "show", "-f", "main.jsonnet",
+    ]);
+    chdir(&matches).unwrap();
+    let sub = matches.subcommand_matches("show").unwrap();
+    let result = eval_file_or_snippet(&mut JsonnetVm::new(), sub);
+    env::set_current_dir(&orig).unwrap();
+
+    assert_eq!(json::parse(&result.unwrap()).unwrap(), object!{"a" => 42});
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "-C", dir.join("missing").to_str().unwrap(), "show", "-f", "main.jsonnet",
+    ]);
+    assert!(chdir(&matches).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+const POST_PROCESS_FILENAME: &'static str = "<post-process>";
+
+/// Replace each object with the result of calling the `--post-process`
+/// function on it.
+fn post_process<'a>(vm: &mut JsonnetVm, parsed: &mut JsonValue, matches: &ArgMatches<'a>) -> Result<()> {
+    let path = match matches.value_of("post_process") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    // Relative imports in the snippet are relative to the current directory
+    let filename = env::current_dir()
+        .chain_err(|| "Unable to determine current directory")?
+        .join(POST_PROCESS_FILENAME);
+
+    let mut result = Ok(());
+    kutils::for_each_object_mut(parsed, |o| {
+        if result.is_ok() {
+            result = post_process_one(vm, &filename, path, o)
+                .map(|v| *o = v);
+        }
+    });
+    result
+}
+
+fn post_process_one(vm: &mut JsonnetVm, filename: &Path, path: &str, o: &JsonValue) -> Result<JsonValue> {
+    // JSON is valid jsonnet, so the object and path can be inlined
+    let snippet = format!("(import {})({})", JsonValue::from(path).dump(), o.dump());
+    let json = vm.evaluate_snippet(filename, &snippet)
+        .map(|v| v.as_str().to_owned())
+        .map_err(|e| Error::from(e.as_str().to_owned()))
+        .chain_err(|| format!("--post-process failed for {}", o.k8s_id()))?;
+    json::parse(&json)
+        .chain_err(|| format!("Invalid --post-process output for {}", o.k8s_id()))
+}
+
+#[test]
+fn test_post_process() {
+    let _lock = CWD_LOCK.lock().unwrap();
+    let dir = env::temp_dir().join(format!("kubecfg-test-post-process-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("labels.libsonnet");
+    fs::write(&lib, "function(o) o + {metadata+: {labels+: {team: 'infra'}}}").unwrap();
+    fs::write(dir.join("broken.libsonnet"), "function(o) error 'no ' + o.metadata.name").unwrap();
+
+    let mut parsed = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![
+            object!{"apiVersion" => "v1", "kind" => "ConfigMap", "metadata" => object!{"name" => "a"}},
+            object!{"apiVersion" => "v1", "kind" => "Secret", "metadata" => object!{"name" => "b", "labels" => object!{"app" => "b"}}}
+        ]
+    };
+    let mut vm = JsonnetVm::new();
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "show", "-f", "x", "--post-process", lib.to_str().unwrap(),
+    ]);
+    post_process(&mut vm, &mut parsed, matches.subcommand_matches("show").unwrap()).unwrap();
+    assert_eq!(parsed["items"][0]["metadata"]["labels"], object!{"team" => "infra"});
+    assert_eq!(parsed["items"][1]["metadata"]["labels"], object!{"app" => "b", "team" => "infra"});
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "show", "-f", "x", "--post-process", dir.join("broken.libsonnet").to_str().unwrap(),
+    ]);
+    let e = post_process(&mut vm, &mut parsed, matches.subcommand_matches("show").unwrap()).unwrap_err();
+    assert_eq!(e.to_string(), "--post-process failed for configmap/a");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Apply any `--set-image` overrides
+fn set_images<'a>(parsed: &mut JsonValue, matches: &ArgMatches<'a>) {
+    if let Some(values) = matches.values_of("set_image") {
+        let images: Vec<_> = values.map(parse_kv).collect();
+        kutils::for_each_object_mut(parsed, |o| kutils::set_images(o, &images));
+    }
+}
+
+/// Apply any `--annotation` values, expanding `{timestamp}` and
+/// `{user}` in them.
+fn set_annotations<'a>(parsed: &mut JsonValue, matches: &ArgMatches<'a>) {
+    if let Some(values) = matches.values_of("annotation") {
+        let timestamp = rfc3339(SystemTime::now());
+        let user = env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+        let annotations: Vec<_> = values.map(parse_kv)
+            .map(|(k, v)| (k, v.replace("{timestamp}", &timestamp).replace("{user}", &user)))
+            .collect();
+        let overwrite = matches.is_present("overwrite_annotations");
+        kutils::for_each_object_mut(parsed, |o| kutils::set_annotations(o, &annotations, overwrite));
+    }
+}
+
+/// Format `t` as an RFC 3339 UTC timestamp, eg `2006-01-02T15:04:05Z`
+fn rfc3339(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Convert days since 1970-01-01 to a civil date
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// As `rfc3339`, with milliseconds, eg `2006-01-02T15:04:05.000Z`
+fn rfc3339_millis(t: SystemTime) -> String {
+    let millis = t.duration_since(UNIX_EPOCH).map(|d| d.subsec_millis()).unwrap_or(0);
+    let secs = rfc3339(t);
+    format!("{}.{:03}Z", secs.trim_end_matches('Z'), millis)
+}
+
+#[test]
+fn test_rfc3339_millis() {
+    assert_eq!(rfc3339_millis(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert_eq!(rfc3339_millis(UNIX_EPOCH + Duration::from_millis(951_782_400_042)), "2000-02-29T00:00:00.042Z");
+}
+
+/// Log as configured by `RUST_LOG`, with `--log-timestamps` if given
+fn init_logging<'a>(matches: &ArgMatches<'a>) -> Result<()> {
+    let mut builder = env_logger::LogBuilder::new();
+    if matches.is_present("log_timestamps") {
+        builder.format(|record| format!("{} {}:{}: {}", rfc3339_millis(SystemTime::now()),
+                                        record.level(), record.target(), record.args()));
+    }
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse(&spec);
+    }
+    builder.init()
+        .chain_err(|| "Error initialising logging")
+}
+
+#[test]
+fn test_set_annotations() {
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(951782400 + 3723)), "2000-02-29T01:02:03Z");
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "update", "-f", "x",
+        "--annotation", "example.com/deployed-at={timestamp}",
+        "--annotation", "example.com/owner=ci",
+    ]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    let mut parsed = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![object!{
+            "kind" => "ConfigMap",
+            "metadata" => object!{"name" => "foo", "annotations" => object!{"example.com/owner" => "alice"}}
+        }]
+    };
+    set_annotations(&mut parsed, matches);
+
+    let annotations = &parsed["items"][0]["metadata"]["annotations"];
+    assert_eq!(annotations["example.com/owner"], "alice");
+    let at = annotations["example.com/deployed-at"].as_str().unwrap();
+    assert!(at.ends_with('Z') && !at.contains('{'), "{}", at);
+}
+
+/// Flatten `parsed` into individual objects, applying any filters
+/// from the command line.
+fn select_objects<'a, 'b>(parsed: &'b JsonValue, matches: &ArgMatches<'a>) -> Vec<&'b JsonValue> {
+    let include: Vec<_> = matches.values_of("include_kind")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    let exclude: Vec<_> = matches.values_of("exclude_kind")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+
+    kutils::flatten_list(parsed).into_iter()
+        .filter(|o| kutils::kind_selected(o, &include, &exclude))
+        .collect()
+}
+
+/// Filename used for `--exec` expressions, relative to the current
+/// directory.
+const EXEC_FILENAME: &'static str = "<exec>";
+
+/// Evaluate the `-f` file `filename`, which may be an http(s) URL if
+/// `--allow-remote-files` is given.
+fn evaluate_input<'a>(vm: &mut JsonnetVm, matches: &ArgMatches<'a>, filename: &OsStr) -> Result<String> {
+    let path = Path::new(filename);
+    let is_manifest = |p: &Path| matches!(p.extension().and_then(|e| e.to_str()),
+                                          Some("yaml") | Some("yml") | Some("json"));
+
+    let url = match filename.to_str().and_then(|f| Url::parse(f).ok()) {
+        Some(ref url) if url.scheme() == "http" || url.scheme() == "https" => url.clone(),
+        _ if is_manifest(path) => {
+            let text = fs::read_to_string(path)
+                .chain_err(|| format!("Unable to read {}", path.display()))?;
+            let v = kutils::parse_manifests(&text)
+                .chain_err(|| format!("Unable to parse {}", path.display()))?;
+            return Ok(v.dump());
+        },
+        _ => {
+            return vm.evaluate_file(filename)
+                .map(|v| v.as_str().to_owned())
+                .map_err(|e| e.as_str().to_owned().into());
+        },
+    };
+
+    if !matches.is_present("allow_remote_files") {
+        bail!("{} is a URL: remote files need --allow-remote-files", url);
+    }
+    let text = fetch_remote(&url)?;
+
+    if is_manifest(Path::new(url.path())) {
+        let v = kutils::parse_manifests(&text)
+            .chain_err(|| format!("Unable to parse {}", url))?;
+        return Ok(v.dump());
+    }
+    // Named by its URL, so set_importer fetches relative imports
+    vm.evaluate_snippet(url.as_str(), &text)
+        .map(|v| v.as_str().to_owned())
+        .map_err(|e| e.as_str().to_owned().into())
+}
+
+/// GET a remote input file.  Uses a separate client from the
+/// Kubernetes one, so no cluster credentials are sent.
+fn fetch_remote(url: &Url) -> Result<String> {
+    use std::io::Read;
+
+    let ssl = NativeTlsClient::new()
+        .chain_err(|| "Error initialising TLS")?;
+    let client = Client::with_connector(HttpsConnector::new(ssl));
+
+    info!("Fetching {}", url);
+    let mut resp = client.get(url.as_str()).send()
+        .map_err(|e| ErrorKind::Connection(url.to_string(), send_error_reason(&e)))?;
+    if !resp.status.is_success() {
+        bail!("Unable to fetch {}: {}", url, resp.status);
+    }
+    let mut text = String::new();
+    resp.read_to_string(&mut text)
+        .chain_err(|| format!("Unable to read {}", url))?;
+    Ok(text)
+}
+
+#[test]
+fn test_manifest_files() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-manifests-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let yaml = dir.join("all.yaml");
+    fs::write(&yaml, "kind: ConfigMap\n---\nkind: Secret\n").unwrap();
+    let json = dir.join("one.json");
+    fs::write(&json, r#"{"kind": "Secret"}"#).unwrap();
+
+    let m = build_cli("test").get_matches_from(vec!["kubecfg", "show", "-f", "x"]);
+    let show = m.subcommand_matches("show").unwrap();
+    let eval = |path: &Path| {
+        json::parse(&evaluate_input(&mut JsonnetVm::new(), show, path.as_os_str()).unwrap()).unwrap()
+    };
+    assert_eq!(eval(&yaml), object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => array![object!{"kind" => "ConfigMap"}, object!{"kind" => "Secret"}]
+    });
+    assert_eq!(eval(&json), object!{"kind" => "Secret"});
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remote_files() {
+    let (url, server) = mock_server(vec![
+        (200, "{a: 1 + 1}"),
+        (200, "a: [1, 2]\n"),
+        (404, "not found"),
+        (200, "(import 'lib.libsonnet') + {b: 2}"),
+        (200, "{a: 1}"),
+    ]);
+    let matches = |file: &str, allow: bool| {
+        let mut argv = vec!["kubecfg"];
+        if allow {
+            argv.push("--allow-remote-files");
+        }
+        argv.extend(&["show", "-f", file]);
+        build_cli("test").get_matches_from(argv)
+    };
+    let eval = |file: &str, allow: bool| {
+        let m = matches(file, allow);
+        let show = m.subcommand_matches("show").unwrap();
+        let mut vm = JsonnetVm::new();
+        init_vm_options(&mut vm, show).unwrap();
+        evaluate_input(&mut vm, show, OsStr::new(file))
+            .map(|json| json::parse(&json).unwrap())
+    };
+
+    let main = url.join("main.jsonnet").unwrap();
+    let e = eval(main.as_str(), false).unwrap_err();
+    assert!(e.to_string().contains("--allow-remote-files"), "{}", e);
+
+    assert_eq!(eval(main.as_str(), true).unwrap(), object!{"a" => 2});
+    assert_eq!(eval(url.join("m.yaml").unwrap().as_str(), true).unwrap(), object!{"a" => array![1, 2]});
+
+    let missing = url.join("missing.jsonnet").unwrap();
+    let e = eval(missing.as_str(), true).unwrap_err();
+    assert_eq!(e.to_string(), format!("Unable to fetch {}: 404 Not Found", missing));
+
+    // Relative imports are fetched from beside the importing file
+    let importer = url.join("dir/main.jsonnet").unwrap();
+    assert_eq!(eval(importer.as_str(), true).unwrap(), object!{"a" => 1, "b" => 2});
+
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /main.jsonnet HTTP/1.1",
+        "GET /m.yaml HTTP/1.1",
+        "GET /missing.jsonnet HTTP/1.1",
+        "GET /dir/main.jsonnet HTTP/1.1",
+        "GET /dir/lib.libsonnet HTTP/1.1",
+    ]);
+}
+
+fn eval_file_or_snippet<'a>(vm: &mut JsonnetVm, matches: &ArgMatches<'a>) -> Result<String> {
+    if let Some(filename) = matches.value_of_os("file") {
+        return evaluate_input(vm, matches, filename);
+    }
+
+    let expr = matches.value_of("exec").unwrap();
+    let filename = env::current_dir()
+        .chain_err(|| "Unable to determine current directory")?
+        .join(EXEC_FILENAME);
+    vm.evaluate_snippet(&filename, expr)
+        .map(|v| v.as_str().to_owned())
+        .map_err(|e| e.as_str().to_owned().into())
+}
+
+#[test]
+fn test_exec_filename() {
+    let _lock = CWD_LOCK.lock().unwrap();
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "show", "-e", "std.thisFile"]);
+    let matches = matches.subcommand_matches("show").unwrap();
+
+    let result = eval_file_or_snippet(&mut JsonnetVm::new(), matches).unwrap();
+    let expected = env::current_dir().unwrap().join("<exec>");
+    assert_eq!(json::parse(&result).unwrap(), expected.to_str().unwrap());
+}
+
+/// Report top-level fields that the server's schema doesn't describe.
+fn check_unknown_fields(c: &Context, objects: &[&JsonValue]) -> Result<Vec<Finding>> {
+    let schema = c.fetch_schema()?;
+
+    let mut findings = vec![];
+    for o in objects {
+        match schema.unknown_fields(o) {
+            None => debug!("No schema for {}, skipping", o.k8s_kind()),
+            Some(fields) => for f in fields {
+                findings.push(Finding {
+                    object: o.k8s_id(),
+                    rule: "unknown-field",
+                    severity: Severity::Error,
+                    message: format!("unknown field \"{}\"", f),
+                    path: Some(f.to_owned()),
+                    location: None,
+                });
+            },
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Report pods (or workloads) that use ConfigMaps or Secrets that are
+/// not in `objects`.  With `check_cluster`, objects that already exist
+/// on the server are also accepted.
+fn check_config_refs(c: &mut Context, objects: &[&JsonValue], check_cluster: bool) -> Result<Vec<Finding>> {
+    let defined: BTreeSet<_> = objects.iter().map(|o| o.k8s_id()).collect();
+
+    let mut findings = vec![];
+    for o in objects {
+        let spec = match kutils::pod_spec(o) {
+            Some(spec) => spec,
+            None => continue,
+        };
+
+        for r in kutils::config_refs(spec) {
+            let id = kutils::ObjectId {
+                kind: kutils::K8sKind::new(r.kind.0, r.kind.1),
+                namespace: o.k8s_namespace().map(|ns| ns.to_owned()),
+                name: r.name,
+            };
+            if defined.contains(&id) {
+                continue;
+            }
+
+            if check_cluster {
+                let url = c.url_for_id(&id)?;
+                let resp = c.request(Method::Get, url, None)?;
+                if resp.status != hyper::NotFound {
+                    kube_result(resp)?;
+                    continue;
+                }
+            }
+
+            findings.push(Finding {
+                object: o.k8s_id(),
+                rule: "missing-reference",
+                severity: Severity::Error,
+                message: format!("references missing {}", id),
+                path: None,
+                location: None,
+            });
+        }
+    }
+
+    Ok(findings)
+}
+
+/// The index in `objects` of the owner `owner` refers to, for an
+/// object in namespace `ns`.  Owners are in the same namespace, or
+/// cluster-scoped.
+fn find_owner(objects: &[&JsonValue], owner: &JsonValue, ns: Option<&str>) -> Option<usize> {
+    objects.iter().position(|o| {
+        o["apiVersion"] == owner["apiVersion"] && o["kind"] == owner["kind"] &&
+            o.k8s_name().is_some() && o.k8s_name() == owner["name"].as_str() &&
+            (o.k8s_namespace().is_none() || o.k8s_namespace() == ns)
+    })
+}
+
+/// Report `metadata.ownerReferences` to objects that aren't in
+/// `objects` (which the garbage collector would delete the owned
+/// object for), and cycles of ownership.
+fn check_owner_refs(objects: &[&JsonValue]) -> Vec<Finding> {
+    let mut findings = vec![];
+    let mut owners: Vec<Vec<usize>> = vec![];
+
+    for o in objects {
+        let mut found = vec![];
+        for (i, owner) in o["metadata"]["ownerReferences"].members().enumerate() {
+            match find_owner(objects, owner, o.k8s_namespace()) {
+                Some(j) => found.push(j),
+                None => findings.push(Finding {
+                    object: o.k8s_id(),
+                    rule: "dangling-owner",
+                    severity: Severity::Error,
+                    message: format!("owner {}/{} is not in the input, so the object would be garbage collected",
+                                     owner["kind"].as_str().unwrap_or_default().to_lowercase(),
+                                     owner["name"].as_str().unwrap_or_default()),
+                    path: Some(format!("metadata.ownerReferences[{}]", i)),
+                    location: None,
+                }),
+            }
+        }
+        owners.push(found);
+    }
+
+    // Each cycle is found starting from its first object
+    let mut reported = BTreeSet::new();
+    for start in 0..objects.len() {
+        let mut path = vec![start];
+        let mut next = vec![owners[start].clone()];
+        while let Some(candidates) = next.last_mut() {
+            match candidates.pop() {
+                None => {
+                    next.pop();
+                    path.pop();
+                },
+                Some(j) if j == start => {
+                    let mut cycle = path.clone();
+                    cycle.sort();
+                    if reported.insert(cycle) {
+                        let ids: Vec<_> = path.iter().chain(iter::once(&start))
+                            .map(|&i| objects[i].k8s_id().to_string())
+                            .collect();
+                        findings.push(Finding {
+                            object: objects[start].k8s_id(),
+                            rule: "owner-cycle",
+                            severity: Severity::Error,
+                            message: format!("ownerReferences form a cycle: {}", ids.join(" -> ")),
+                            path: Some("metadata.ownerReferences".to_owned()),
+                            location: None,
+                        });
+                    }
+                },
+                Some(j) if j < start || path.contains(&j) => (),
+                Some(j) => {
+                    path.push(j);
+                    next.push(owners[j].clone());
+                },
+            }
+        }
+    }
+
+    findings
+}
+
+#[test]
+fn test_check_owner_refs() {
+    let o = |kind: &str, name: &str, owner: Option<(&str, &str)>| {
+        let mut o = object!{
+            "apiVersion" => "apps/v1",
+            "kind" => kind,
+            "metadata" => object!{"name" => name, "namespace" => "myns"}
+        };
+        if let Some((kind, name)) = owner {
+            o["metadata"]["ownerReferences"] = array![
+                object!{"apiVersion" => "apps/v1", "kind" => kind, "name" => name, "uid" => "1234"}
+            ];
+        }
+        o
+    };
+
+    let deploy = o("Deployment", "web", None);
+    let rs = o("ReplicaSet", "web-1", Some(("Deployment", "web")));
+    assert!(check_owner_refs(&[&deploy, &rs]).is_empty());
+
+    let orphan = o("ReplicaSet", "web-2", Some(("Deployment", "gone")));
+    let findings = check_owner_refs(&[&deploy, &orphan]);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].rule, "dangling-owner");
+    assert_eq!(findings[0].to_string(),
+               "replicaset/web-2 in namespace myns: owner deployment/gone is not in the input, so the object would be garbage collected");
+    assert_eq!(findings[0].path, Some("metadata.ownerReferences[0]".to_owned()));
+
+    let a = o("ReplicaSet", "a", Some(("ReplicaSet", "b")));
+    let b = o("ReplicaSet", "b", Some(("ReplicaSet", "a")));
+    let findings = check_owner_refs(&[&deploy, &a, &b]);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].rule, "owner-cycle");
+    assert_eq!(findings[0].message,
+               "ownerReferences form a cycle: replicaset/a in namespace myns -> replicaset/b in namespace myns -> replicaset/a in namespace myns");
+}
+
+/// Fetch each of `objects` from the server at `api_version`.  The
+/// apiserver converts between versions of the same kind on read.
+fn convert_objects(c: &mut Context, objects: &[&JsonValue], api_version: &str) -> Result<Vec<JsonValue>> {
+    let mut converted = vec![];
+    let mut failed = vec![];
+
+    for o in objects {
+        let mut id = o.k8s_id();
+        id.kind.api_version = api_version.into();
+
+        let result = c.url_for_id(&id)
+            .and_then(|url| c.request(Method::Get, url, None))
+            .and_then(kube_result);
+        match result {
+            Ok(mut v) => {
+                kutils::strip_server_fields(&mut v);
+                converted.push(v);
+            },
+            Err(e) => {
+                debug!("Unable to convert {}: {}", o.k8s_id(), e);
+                failed.push(format!("{} ({})", o.k8s_id(), e));
+            },
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(converted)
+    } else {
+        Err(format!("Unable to convert to {}: {}", api_version, failed.join(", ")).into())
+    }
+}
+
+/// A stable hash of a set of objects.  Independent of field and
+/// object order, and of null fields.
+fn manifest_hash(objects: &[&JsonValue]) -> String {
+    let mut docs: Vec<_> = objects.iter()
+        .map(|&o| kutils::normalize(o).dump())
+        .collect();
+    docs.sort();
+
+    let mut hasher = Sha256::new();
+    for doc in docs {
+        hasher.update(doc.as_bytes());
+        hasher.update(b"\n");
+    }
+    hex(&hasher.finalize())
+}
+
+/// A stable hash of a single object
+fn object_hash(o: &JsonValue) -> String {
+    hex(&Sha256::digest(kutils::normalize(o).dump().as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn test_manifest_hash() {
+    let a = object!{"kind" => "A", "metadata" => object!{"name" => "a", "labels" => JsonValue::Null}};
+    let b = object!{"metadata" => object!{"name" => "b"}, "kind" => "B"};
+    let h = manifest_hash(&[&a, &b]);
+    assert_eq!(h.len(), 64);
+
+    let a2 = object!{"metadata" => object!{"name" => "a"}, "kind" => "A"};
+    assert_eq!(manifest_hash(&[&b, &a2]), h);
+
+    let c = object!{"kind" => "A", "metadata" => object!{"name" => "c"}};
+    assert!(manifest_hash(&[&c, &b]) != h);
+}
+
+/// Key for `o` in a `--state-file`
+fn state_key(o: &JsonValue) -> String {
+    let id = o.k8s_id();
+    format!("{}/{}/{}", id.kind, id.namespace.unwrap_or_default(), id.name)
+}
+
+/// Read the object hashes saved by a previous `update --state-file`.
+fn read_state_file(path: &Path) -> Result<JsonValue> {
+    match fs::read_to_string(path) {
+        Ok(text) => json::parse(&text)
+            .chain_err(|| format!("Unable to parse state file {}", path.display())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(JsonValue::new_object()),
+        Err(e) => Err(e).chain_err(|| format!("Unable to read state file {}", path.display())),
+    }
+}
+
+fn write_state_file(path: &Path, objects: &[&JsonValue]) -> Result<()> {
+    let mut state = JsonValue::new_object();
+    for &o in objects {
+        state[state_key(o)] = object_hash(o).into();
+    }
+
+    let f = fs::File::create(path)
+        .chain_err(|| format!("Unable to write state file {}", path.display()))?;
+    OutputFormat::Json.emit(&state, f)
+}
+
+#[test]
+fn test_state_file() {
+    let path = env::temp_dir().join(format!("kubecfg-test-state-{}.json", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    assert_eq!(read_state_file(&path).unwrap(), JsonValue::new_object());
+
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    write_state_file(&path, &[&o]).unwrap();
+
+    let state = read_state_file(&path).unwrap();
+    assert_eq!(state[state_key(&o)], object_hash(&o).as_str());
+    assert_eq!(state_key(&o), "v1.ConfigMap/myns/foo");
+    fs::remove_file(&path).unwrap();
+}
+
+fn do_show<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
+
+    let json_text = eval_file_or_snippet(&mut c.vm, matches)?;
+
+    let mut json = json::parse(&json_text)
+        .chain_err(|| "Unable to parse jsonnet output")?;
+    post_process(&mut c.vm, &mut json, matches)?;
+
+    if let Some(api_version) = matches.value_of("output_version") {
+        let mut converted = convert_objects(c, &kutils::flatten_list(&json), api_version)?;
+        json = if json.is_k8s_kind(kutils::V1_LIST) {
+            json["items"] = converted.into();
+            json
+        } else {
+            converted.remove(0)
+        };
+    }
+
+    if matches.is_present("strict") {
+        let findings = check_unknown_fields(c, &kutils::flatten_list(&json))?;
+        findings::report(&findings, false, io::stderr())?;
+    }
+
+    if matches.is_present("sort_objects") {
+        kutils::sort_objects(&mut json);
+    }
+    if matches.is_present("sort_keys") {
+        json = kutils::sort_keys(&json);
+    }
+
+    if matches.is_present("hash") {
+        writeln!(w, "{}", manifest_hash(&kutils::flatten_list(&json)))?;
+        return Ok(());
+    }
+
+    if let Some(dir) = matches.value_of_os("kustomization") {
+        let objects = kutils::flatten_list(&json);
+        return emitters::write_kustomization(Path::new(dir), &objects);
+    }
+
+    if let Some(dir) = matches.value_of_os("helm_chart") {
+        let objects = kutils::flatten_list(&json);
+        return emitters::write_helm_chart(Path::new(dir), &objects);
+    }
+
+    let output: OutputFormat = matches.value_of("format").unwrap().parse()?;
+
+    if let Some(template) = matches.value_of("filename_template") {
+        let dir = matches.value_of_os("output_dir").unwrap();
+        let objects = kutils::flatten_list(&json);
+        return emitters::write_template(Path::new(dir), &objects, &template.parse()?, output);
+    }
+
+    if let Some(split) = matches.value_of("split_by") {
+        let dir = matches.value_of_os("output_dir").unwrap();
+        let objects = kutils::flatten_list(&json);
+        return emitters::write_split(Path::new(dir), &objects, split.parse()?, output);
+    }
+
+    if emitters::is_empty_result(&json) {
+        let empty = matches.value_of("output_empty_as").unwrap().parse()?;
+        return output.emit_empty(empty, w);
+    }
+
+    if matches.is_present("annotate_source") {
+        if output != OutputFormat::Yaml {
+            bail!("--annotate-source requires -o yaml");
+        }
+        let sources = matches.value_of_os("file")
+            .map(|f| sources::read_sources(Path::new(f)))
+            .unwrap_or_default();
+        return emitters::emit_yaml_annotated(&kutils::flatten_list(&json), &sources, w);
+    }
+
+    output.emit(&json, w)
+}
+
+fn do_create<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    c.request_format = matches.value_of("request_format").unwrap().parse()?;
+    init_vm_options(&mut c.vm, matches)?;
+
+    let mut parsed = {
+        let filename = matches.value_of_os("file").unwrap();
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
+
+        json::parse(&json)
+            .chain_err(|| "Unable to parse jsonnet output")?
+    };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
+    set_images(&mut parsed, matches);
+    set_annotations(&mut parsed, matches);
+
+    let hooked: Vec<JsonValue>;
+    let mut objects = select_objects(&parsed, matches);
+    if !matches.is_present("no_sort") {
+        objects.sort_by_key(|&v| kutils::dep_first(v));
+    }
+    if let Some(command) = matches.value_of("exec_hook") {
+        hooked = objects.iter()
+            .map(|&o| exec_hook(command, o))
+            .collect::<Result<_>>()?;
+        objects = hooked.iter().collect();
+    }
+    // After the hook, which may change the kind
+    check_kind_policy(&objects, matches)?;
+
+    if matches.is_present("plan") {
+        let plan = plan_objects(c, &objects, |_, _, live| Ok(if live.is_some() { "exists" } else { "create" }))?;
+        return write_plan(&plan, w);
+    }
+
+    let mut results = Results::default();
+    let r = ensure_namespaces(c, matches, &objects, &mut results)
+        .and_then(|()| create_objects(c, &objects, parallelism(matches)?, &mut results));
+    let written = write_results(matches, &results, w);
+    r.and(written)
+}
+
+#[test]
+fn test_kind_policy_after_exec_hook() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-hook-policy-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("cm.jsonnet");
+    fs::write(&file, r#"{apiVersion: "v1", kind: "ConfigMap", metadata: {name: "foo", namespace: "myns"}}"#).unwrap();
+
+    for &cmd in &["create", "update"] {
+        let matches = build_cli("test").get_matches_from(vec![
+            "kubecfg", cmd, "-f", file.to_str().unwrap(),
+            "--allowed-kinds", "v1/ConfigMap",
+            "--exec-hook", "sed s/ConfigMap/Secret/",
+        ]);
+        let matches = matches.subcommand_matches(cmd).unwrap();
+        // Nothing listening: any request would fail differently
+        let mut c = mock_context(Url::parse("http://localhost:1/").unwrap());
+        let e = if cmd == "create" {
+            do_create(&mut c, matches, vec![])
+        } else {
+            do_update(&mut c, matches, vec![])
+        }.unwrap_err();
+        match *e.kind() {
+            ErrorKind::KindNotAllowed(ref objects) =>
+                assert_eq!(objects, &vec!["secret/foo in namespace myns (v1.Secret)".to_owned()]),
+            ref k => panic!("unexpected error {:?}", k),
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Run `command` (with `sh -c`) with `o` on stdin.  Returns the object
+/// it writes to stdout, or `o` unchanged if it writes nothing.
+fn exec_hook(command: &str, o: &JsonValue) -> Result<JsonValue> {
+    use std::process::{Command,Stdio};
+
+    let id = o.k8s_id();
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .chain_err(|| format!("Unable to run --exec-hook for {}", id))?;
+
+    // Write from another thread, so a hook that writes before it has
+    // read everything can't deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = o.dump();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child.wait_with_output()
+        .chain_err(|| format!("Unable to run --exec-hook for {}", id))?;
+    // The hook may legitimately ignore its input
+    let _ = writer.join();
+
+    if !output.status.success() {
+        bail!("--exec-hook failed for {}: {}", id, output.status);
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .chain_err(|| format!("Invalid UTF-8 in --exec-hook output for {}", id))?;
+    if stdout.trim().is_empty() {
+        Ok(o.clone())
+    } else {
+        kutils::parse_json_or_yaml(&stdout)
+            .chain_err(|| format!("Invalid --exec-hook output for {}", id))
+    }
+}
+
+#[test]
+fn test_exec_hook() {
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo"},
+        "data" => object!{"key" => "PLACEHOLDER"}
+    };
+
+    assert_eq!(exec_hook("cat", &o).unwrap(), o);
+    assert_eq!(exec_hook("true", &o).unwrap(), o);
+
+    let res = exec_hook("sed s/PLACEHOLDER/secret/", &o).unwrap();
+    assert_eq!(res["data"]["key"], "secret");
+
+    let e = exec_hook("exit 3", &o).unwrap_err();
+    assert_eq!(e.to_string(), "--exec-hook failed for configmap/foo: exit status: 3");
+
+    assert!(exec_hook("echo '{'", &o).is_err());
+}
+
+/// With `--ensure-namespace`, check that each namespace `objects` are
+/// applied to either exists or is itself in `objects`.  With
+/// `--create-namespace`, create any that are missing.
+fn ensure_namespaces<'a>(c: &mut Context, matches: &ArgMatches<'a>, objects: &[&JsonValue], results: &mut Results) -> Result<()> {
+    let create = matches.is_present("create_namespace");
+    if !create && !matches.is_present("ensure_namespace") {
+        return Ok(());
+    }
+
+    let defined: BTreeSet<_> = objects.iter()
+        .filter(|o| o.is_k8s_kind(kutils::V1_NAMESPACE))
+        .filter_map(|o| o.k8s_name())
+        .collect();
+
+    let mut wanted = BTreeSet::new();
+    for &o in objects {
+        // The kind may not be known yet (eg a custom resource whose
+        // CRD is in this set), so only look it up if we must
+        if let Some(ns) = o.k8s_namespace() {
+            wanted.insert(ns.to_owned());
+            continue;
+        }
+        match c.api_resource(&o.k8s_kind()).map(|r| r.namespaced) {
+            Ok(true) => { wanted.insert(c.default_namespace.clone()); },
+            Ok(false) => (),
+            Err(e) => debug!("Not checking the namespace of {}: {}", o.k8s_id(), e),
+        }
+    }
+
+    for ns in wanted {
+        if defined.contains(ns.as_str()) {
+            continue;
+        }
+
+        let id = kutils::ObjectId {
+            kind: kutils::K8sKind::new(kutils::V1_NAMESPACE.0, kutils::V1_NAMESPACE.1),
+            namespace: None,
+            name: ns.clone(),
+        };
+        let url = c.url_for_id(&id)?;
+        let resp = c.request(Method::Get, url, None)?;
+        if resp.status != hyper::NotFound {
+            kube_result(resp)?;
+            continue;
+        }
+
+        if !create {
+            bail!("namespace '{}' does not exist; create it or add it to your manifest", ns);
+        }
+
+        info!("Creating namespace {}", ns);
+        let o = object!{
+            "apiVersion" => "v1",
+            "kind" => "Namespace",
+            "metadata" => object!{"name" => ns}
+        };
+        let r = create_one(c, &o);
+        results.record(id, r)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ensure_namespaces() {
+    let cm = |ns: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => ns}
+    };
+    let other = object!{
+        "apiVersion" => "v1",
+        "kind" => "Namespace",
+        "metadata" => object!{"name" => "other"}
+    };
+    let (a, b) = (cm("myns"), cm("other"));
+    let objects = vec![&a, &b, &other];
+    let context = |url| {
+        let mut c = mock_context(url);
+        c.api_cache.insert(kutils::K8sKind::new("v1", "Namespace"), kutils::ApiResource {
+            name: "namespaces".to_owned(),
+            kind: "Namespace".to_owned(),
+            namespaced: false,
+            subresources: vec![],
+            custom: None,
+        });
+        c
+    };
+
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+    ]);
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "update", "--ensure-namespace", "-f", "x"]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    let mut results = Results::default();
+    let e = ensure_namespaces(&mut context(url), matches, &objects, &mut results).unwrap_err();
+    assert_eq!(e.to_string(), "namespace 'myns' does not exist; create it or add it to your manifest");
+    assert_eq!(server.join().unwrap(), vec!["GET /api/v1/namespaces/myns HTTP/1.1"]);
+
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+        (201, r#"{"kind":"Namespace","metadata":{"name":"myns"}}"#),
+    ]);
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "update", "--create-namespace", "-f", "x"]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    ensure_namespaces(&mut context(url), matches, &objects, &mut results).unwrap();
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/myns HTTP/1.1",
+        "POST /api/v1/namespaces HTTP/1.1",
+    ]);
+    assert_eq!(results.outcomes[0].operation, Operation::Create);
+
+    // Kinds that don't exist yet
+    let widget = |ns: Option<&str>| {
+        let mut o = object!{
+            "apiVersion" => "example.com/v1",
+            "kind" => "Widget",
+            "metadata" => object!{"name" => "foo"}
+        };
+        if let Some(ns) = ns {
+            o["metadata"]["namespace"] = ns.into();
+        }
+        o
+    };
+    let (a, b) = (widget(Some("other")), widget(None));
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+    ]);
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "update", "--ensure-namespace", "-f", "x"]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    ensure_namespaces(&mut context(url), matches, &[&a, &b, &other], &mut results).unwrap();
+    assert_eq!(server.join().unwrap(), vec!["GET /apis/example.com/v1 HTTP/1.1"]);
+}
+
+/// Ask the server (with a SelfSubjectAccessReview) whether the current
+/// credentials may `verb` objects of `kind` in `namespace`.
+fn can_i(c: &mut Context, verb: &str, kind: &kutils::K8sKind, namespace: Option<&str>) -> Result<bool> {
+    let mut attributes = object!{
+        "verb" => verb,
+        "group" => kind.api_version.group.clone(),
+        "resource" => c.api_resource(kind)?.name.clone()
+    };
+    if let Some(ns) = namespace {
+        attributes["namespace"] = ns.into();
+    }
+    let review = object!{
+        "apiVersion" => "authorization.k8s.io/v1",
+        "kind" => "SelfSubjectAccessReview",
+        "spec" => object!{"resourceAttributes" => attributes}
+    };
+
+    let mut url = c.server_url.clone();
+    url.path_segments_mut().unwrap()
+        .extend(&["apis", "authorization.k8s.io", "v1", "selfsubjectaccessreviews"]);
+    let resp = c.request(Method::Post, url, Some((ContentType::json(), &review.dump())))?;
+    Ok(kube_result(resp)?["status"]["allowed"].as_bool().unwrap_or(false))
+}
+
+/// Report every object that the current credentials may not apply
+/// each of `verbs` to (or delete, for `prunable`).  Fails if there
+/// are any.
+fn check_permissions(c: &mut Context, objects: &[&JsonValue], verbs: &[&str], prunable: &[kutils::ObjectId]) -> Result<()> {
+    let mut wanted: Vec<(kutils::ObjectId, &[&str])> = vec![];
+    for o in objects {
+        wanted.push((c.resolved_id(o)?, verbs));
+    }
+    wanted.extend(prunable.iter().map(|id| (id.clone(), &["delete"][..])));
+
+    // Objects of the same kind and namespace share an answer
+    let mut reviews = BTreeMap::new();
+    let mut findings = vec![];
+    for (id, verbs) in wanted {
+        let mut denied = vec![];
+        for &verb in verbs {
+            let key = (verb, id.kind.clone(), id.namespace.clone());
+            let allowed = match reviews.get(&key) {
+                Some(&allowed) => allowed,
+                None => {
+                    let allowed = can_i(c, verb, &id.kind, id.namespace.as_deref())?;
+                    reviews.insert(key, allowed);
+                    allowed
+                },
+            };
+            if !allowed && !denied.contains(&verb) {
+                denied.push(verb);
+            }
+        }
+
+        if !denied.is_empty() {
+            findings.push(Finding {
+                object: id,
+                rule: "permission-denied",
+                severity: Severity::Error,
+                message: format!("not allowed to {}", denied.join(", ")),
+                path: None,
+                location: None,
+            });
+        }
+    }
+
+    findings::report(&findings, false, io::stderr())
+}
+
+#[test]
+fn test_check_permissions() {
+    let allowed = r#"{"kind":"SelfSubjectAccessReview","status":{"allowed":true}}"#;
+    let denied = r#"{"kind":"SelfSubjectAccessReview","status":{"allowed":false,"reason":"RBAC"}}"#;
+    let (url, server) = mock_server(vec![
+        (201, allowed),
+        (201, denied),
+        (201, denied),
+    ]);
+    let mut c = mock_context(url);
+    let o = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "x"}
+    };
+    let (a, b) = (o("a"), o("b"));
+    let stale = b.k8s_id();
+
+    let e = check_permissions(&mut c, &[&a, &b], &["get", "patch"], &[stale]).unwrap_err();
+    match *e.kind() {
+        // a and b may not be patched, b may not be pruned
+        ErrorKind::ValidationFailed(3) => (),
+        ref k => panic!("unexpected error {:?}", k),
+    }
+    // One review per verb, not per object
+    assert_eq!(server.join().unwrap(), vec![
+        "POST /apis/authorization.k8s.io/v1/selfsubjectaccessreviews HTTP/1.1",
+        "POST /apis/authorization.k8s.io/v1/selfsubjectaccessreviews HTTP/1.1",
+        "POST /apis/authorization.k8s.io/v1/selfsubjectaccessreviews HTTP/1.1",
+    ]);
+}
+
+/// Exit status after SIGINT, as for a shell
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Set by SIGINT, once `catch_interrupts` is called
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // A second Ctrl-C kills the process as usual
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL); }
+}
+
+/// Let SIGINT stop an apply loop between objects, rather than killing
+/// the process mid-request.
+fn catch_interrupts() {
+    let handler: extern "C" fn(libc::c_int) = on_sigint;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t); }
+}
+
+/// If SIGINT has been received, record `remaining` as skipped and
+/// return an error summarising what was done.
+fn check_interrupted<I>(client: &ApiClient, results: &mut Results, remaining: I) -> Result<()>
+    where I: IntoIterator<Item = kutils::ObjectId>
+{
+    if !client.interrupted() {
+        return Ok(());
+    }
+    let mut skipped = vec![];
+    for id in remaining {
+        skipped.push(id.to_string());
+        results.push(Outcome::new(id, Operation::Skip, None));
+    }
+    Err(ErrorKind::Interrupted(results.summary(), skipped).into())
+}
+
+fn create_objects(c: &mut Context, objects: &[&JsonValue], parallelism: usize, results: &mut Results) -> Result<()> {
+    catch_interrupts();
+    if parallelism <= 1 {
+        for (i, &o) in objects.iter().enumerate() {
+            check_interrupted(&c.client, results, objects[i..].iter().map(|o| o.k8s_id()))?;
+            let r = create_one(c, o);
+            results.record(o.k8s_id(), r)?;
+        }
+        return Ok(());
+    }
+
+    let mut done = 0;
+    for rank in dep_ranks(objects) {
+        check_interrupted(&c.client, results, objects[done..].iter().map(|o| o.k8s_id()))?;
+        done += rank.len();
+
+        // Resolving URLs may need API discovery, so is done up front
+        let mut requests = vec![];
+        let mut failed = None;
+        for &o in rank {
+            match create_request(c, o) {
+                Ok(req) => requests.push(req),
+                Err(e) => {
+                    failed = Some((o, e));
+                    break;
+                },
+            }
+        }
+
+        let client = &c.client;
+        let sent = parallel_map(&requests, parallelism, client.interrupted, |req| send_create(client, req));
+        let mut first_err = None;
+        let mut not_sent = vec![];
+        for (&o, r) in rank.iter().zip(sent) {
+            match r {
+                Some(r) => if let Err(e) = results.record(o.k8s_id(), r) {
+                    first_err.get_or_insert(e);
+                },
+                None => not_sent.push(o.k8s_id()),
+            }
+        }
+        if let Some(e) = first_err {
+            return Err(e);
+        }
+        if let Some((o, e)) = failed {
+            results.push(Outcome::error(o.k8s_id(), &e));
+            return Err(e);
+        }
+        // Objects are only left unsent without an error if interrupted
+        check_interrupted(&c.client, results, not_sent.into_iter().chain(objects[done..].iter().map(|o| o.k8s_id())))?;
+    }
+    Ok(())
+}
+
+fn create_one(c: &mut Context, o: &JsonValue) -> Result<(Operation, u16, JsonValue)> {
+    let req = create_request(c, o)?;
+    send_create(&c.client, &req)
+}
+
+/// The URL, content type and body to create `o` with
+fn create_request(c: &mut Context, o: &JsonValue) -> Result<(Url, ContentType, String)> {
+    let url = c.url_for(o, false)?;
+    let (content_type, body) = c.create_body(o)?;
+
+    // TODO: support --record?
+
+    Ok((url, content_type, body))
+}
+
+fn send_create(client: &ApiClient, req: &(Url, ContentType, String)) -> Result<(Operation, u16, JsonValue)> {
+    let (url, content_type, body) = req;
+    let resp = client.request(Method::Post, url.clone(), Some((content_type.clone(), body)))?;
+    let status = resp.status.to_u16();
+
+    Ok((Operation::Create, status, kube_result(resp)?))
+}
+
+#[test]
+fn test_create_objects_parallel() {
+    let ok = r#"{"metadata":{"name":"x"}}"#;
+    let (url, server) = mock_server(vec![(201, ok), (201, ok), (201, ok)]);
+    let mut c = mock_context(url);
+    c.api_cache.insert(kutils::K8sKind::new("v1", "Namespace"), kutils::ApiResource {
+        name: "namespaces".to_owned(),
+        kind: "Namespace".to_owned(),
+        namespaced: false,
+        subresources: vec![],
+        custom: None,
+    });
+    let ns = object!{"apiVersion" => "v1", "kind" => "Namespace", "metadata" => object!{"name" => "myns"}};
+    let cm = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns"}
+    };
+    let (a, b) = (cm("a"), cm("b"));
+
+    let mut results = Results::default();
+    create_objects(&mut c, &[&ns, &a, &b], 4, &mut results).unwrap();
+    let ops: Vec<_> = results.outcomes.iter().map(|o| (o.id.name.as_str(), o.operation)).collect();
+    assert_eq!(ops, vec![("myns", Operation::Create), ("a", Operation::Create), ("b", Operation::Create)]);
+
+    // The namespace is created before either ConfigMap is sent
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0], "POST /api/v1/namespaces HTTP/1.1");
+    assert_eq!(&requests[1..], &["POST /api/v1/namespaces/myns/configmaps HTTP/1.1"; 2]);
+
+    // Once interrupted, nothing more is sent
+    let (url, server) = mock_server(vec![]);
+    let mut c = mock_context(url);
+    Arc::get_mut(&mut c.client).unwrap().interrupted = Box::leak(Box::new(AtomicBool::new(true)));
+    let mut results = Results::default();
+    let e = create_objects(&mut c, &[&a, &b], 4, &mut results).unwrap_err();
+    assert!(matches!(*e.kind(), ErrorKind::Interrupted(..)), "{}", e);
+    assert_eq!(results.summary(), "2 skip");
+    assert!(server.join().unwrap().is_empty());
+}
+
+#[test]
+fn test_interrupted() {
+    let cm = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns"}
+    };
+    let (a, b) = (cm("a"), cm("b"));
+    let interrupted = |url| {
+        let mut c = mock_context(url);
+        Arc::get_mut(&mut c.client).unwrap().interrupted = Box::leak(Box::new(AtomicBool::new(true)));
+        c
+    };
+    let skipped = |e: Error| match *e.kind() {
+        ErrorKind::Interrupted(_, ref skipped) => skipped.clone(),
+        ref k => panic!("unexpected error {:?}", k),
+    };
+    let all = vec!["configmap/a in namespace myns".to_owned(), "configmap/b in namespace myns".to_owned()];
+
+    let (url, server) = mock_server(vec![]);
+    let mut c = interrupted(url);
+    let e = create_objects(&mut c, &[&a, &b], 1, &mut Results::default()).unwrap_err();
+    assert_eq!(e.to_string(), format!("Interrupted, after: 2 skip; not sent: {}", all.join(", ")));
+    assert_eq!(skipped(e), all);
+    assert!(server.join().unwrap().is_empty());
+
+    let (url, server) = mock_server(vec![]);
+    let mut c = interrupted(url);
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "update", "-f", "x"]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    let mut results = Results::default();
+    let e = update_objects(&mut c, matches, &[&a, &b], vec![], &mut results, &mut vec![]).unwrap_err();
+    assert_eq!(skipped(e), all);
+    assert_eq!(results.summary(), "2 skip");
+    assert!(server.join().unwrap().is_empty());
+}
+
+fn do_delete<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
+
+    let mut parsed = {
+        let filename = matches.value_of_os("file").unwrap();
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
+
+        json::parse(&json)
+            .chain_err(|| "Unable to parse jsonnet output")?
+    };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
+
+    let objects = select_objects(&parsed, matches);
+
+    if matches.is_present("plan") {
+        let plan = plan_objects(c, &objects, |_, _, live| Ok(if live.is_some() { "delete" } else { "absent" }))?;
+        return write_plan(&plan, w);
+    }
+
+    let body = delete_options(matches)?.dump();
+
+    let mut results = Results::default();
+    let mut r = delete_objects(c, &objects, &body, &mut results);
+    if r.is_ok() && matches.is_present("wait") {
+        let timeout = match matches.value_of("wait_timeout") {
+            Some(secs) => Duration::from_secs(secs.parse().chain_err(|| "Invalid --wait-timeout")?),
+            None => DELETE_TIMEOUT,
+        };
+        r = wait_for_deletion(c, &mut results, timeout);
+    }
+    let written = write_results(matches, &results, w);
+    r.and(written)
+}
+
+/// The `DeleteOptions` body for `delete`
+fn delete_options<'a>(matches: &ArgMatches<'a>) -> Result<JsonValue> {
+    if let Some(path) = matches.value_of_os("delete_options_file") {
+        return read_delete_options(Path::new(path));
+    }
+
+    let mut o = kutils::DeleteOptions::default();
+
+    // Unset means the object's own default, which is not the same as 0
+    if let Some(n) = matches.value_of("grace_period") {
+        let v = n.parse()
+            .chain_err(|| "Invalid --grace-period")?;
+        o.grace_period_seconds = Some(v);
+    }
+
+    // Delete dependent objects automatically
+    o.orphan_dependents = false;
+
+    Ok(o.into())
+}
+
+#[test]
+fn test_delete_get_post_process() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-delete-post-process-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("cm.jsonnet");
+    fs::write(&file, r#"{apiVersion: "v1", kind: "ConfigMap", metadata: {name: "a", namespace: "myns"}}"#).unwrap();
+    let lib = dir.join("rename.libsonnet");
+    fs::write(&lib, "function(o) o + {metadata+: {name: 'renamed'}}").unwrap();
+
+    // The same objects as create/update would send
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+    ]);
+    for &cmd in &["delete", "get"] {
+        let mut argv = vec!["kubecfg", cmd, "-f", file.to_str().unwrap(), "--post-process", lib.to_str().unwrap()];
+        if cmd == "delete" {
+            argv.push("--plan");
+        }
+        let matches = build_cli("test").get_matches_from(argv);
+        let matches = matches.subcommand_matches(cmd).unwrap();
+        let mut c = mock_context(url.clone());
+        if cmd == "delete" {
+            do_delete(&mut c, matches, vec![]).unwrap();
+        } else {
+            do_get(&mut c, matches, vec![]).unwrap();
+        }
+    }
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/myns/configmaps/renamed HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/renamed HTTP/1.1",
+    ]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_delete_options_grace_period() {
+    let options = |args: &[&str]| {
+        let mut argv = vec!["kubecfg", "delete", "-f", "x"];
+        argv.extend(args);
+        let matches = build_cli("test").get_matches_from(argv);
+        delete_options(matches.subcommand_matches("delete").unwrap())
+    };
+
+    let o = options(&["--grace-period", "0"]).unwrap();
+    assert_eq!(o["gracePeriodSeconds"], 0);
+    assert!(o.dump().contains(r#""gracePeriodSeconds":0"#));
+
+    let o = options(&[]).unwrap();
+    assert!(!o.has_key("gracePeriodSeconds"));
+
+    assert!(options(&["--grace-period", "soon"]).is_err());
+}
+
+/// Read a user-supplied `DeleteOptions` object from `path`
+fn read_delete_options(path: &Path) -> Result<JsonValue> {
+    let text = fs::read_to_string(path)
+        .chain_err(|| format!("Unable to read {}", path.display()))?;
+    let options = kutils::parse_json_or_yaml(&text)
+        .chain_err(|| format!("Unable to parse {}", path.display()))?;
+
+    if options["kind"] != "DeleteOptions" {
+        bail!("{} is not a DeleteOptions object", path.display());
+    }
+    Ok(options)
+}
+
+fn delete_objects(c: &mut Context, objects: &[&JsonValue], body: &str, results: &mut Results) -> Result<()> {
+    catch_interrupts();
+    for (i, &o) in objects.iter().enumerate() {
+        check_interrupted(&c.client, results, objects[i..].iter().map(|o| o.k8s_id()))?;
+        let r = c.url_for(o, true)
+            .and_then(|url| delete_at(c, url, body));
+        results.record(o.k8s_id(), r)?;
+    }
+    Ok(())
+}
+
+/// Wait until every object that `results` records as deleted is gone
+/// from the server.  Objects still present after `timeout` are
+/// recorded as errors, naming any finalizers holding them up.
+fn wait_for_deletion(c: &mut Context, results: &mut Results, timeout: Duration) -> Result<()> {
+    let mut pending = vec![];
+    for o in &results.outcomes {
+        if o.operation == Operation::Delete || o.operation == Operation::Deleting {
+            info!("Waiting for {} to be deleted", o.id);
+            pending.push((o.id.clone(), c.url_for_id(&o.id)?, JsonValue::Null));
+        }
+    }
+    let total = pending.len();
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut still_present = vec![];
+        for (id, url, _) in pending {
+            let resp = c.request(Method::Get, url.clone(), None)?;
+            if resp.status == hyper::NotFound {
+                info!("{} is gone", id);
+                if let Some(outcome) = results.last_mut(&id) {
+                    outcome.operation = Operation::Delete;
+                }
+            } else {
+                let live = kube_result(resp)?;
+                still_present.push((id, url, live));
+            }
+        }
+        pending = still_present;
+
+        if pending.is_empty() || Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(DELETE_POLL_INTERVAL);
+    }
+
+    info!("{} of {} objects deleted", total - pending.len(), total);
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut stuck = vec![];
+    for (id, _, live) in pending {
+        let finalizers: Vec<_> = live["metadata"]["finalizers"].members()
+            .filter_map(|f| f.as_str())
+            .collect();
+        let msg = if finalizers.is_empty() {
+            format!("{} is still present", id)
+        } else {
+            format!("{} is still present, waiting on finalizers {}", id, finalizers.join(", "))
+        };
+        warn!("{}", msg);
+        if let Some(outcome) = results.last_mut(&id) {
+            outcome.operation = Operation::Error;
+            outcome.error = Some(msg.clone());
+        }
+        stuck.push(msg);
+    }
+    bail!(ErrorKind::WaitTimeout(format!("deletion: {}", stuck.join("; "))))
+}
+
+#[test]
+fn test_wait_for_deletion() {
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+        (200, r#"{"kind":"ConfigMap","metadata":{"name":"b","finalizers":["example.com/protect"]}}"#),
+    ]);
+    let mut c = mock_context(url);
+
+    let id = |name: &str| kutils::ObjectId {
+        kind: kutils::K8sKind::new("v1", "ConfigMap"),
+        namespace: Some("myns".to_owned()),
+        name: name.to_owned(),
+    };
+    let mut results = Results::default();
+    results.push(Outcome::new(id("a"), Operation::Delete, Some(200)));
+    results.push(Outcome::new(id("b"), Operation::Deleting, Some(202)));
+    results.push(Outcome::new(id("c"), Operation::Skip, None));
+
+    // Checks each object once before timing out
+    let e = wait_for_deletion(&mut c, &mut results, Duration::from_secs(0)).unwrap_err();
+    assert!(e.to_string().contains("configmap/b in namespace myns is still present, waiting on finalizers example.com/protect"), "{}", e);
+
+    let ops: Vec<_> = results.outcomes.iter().map(|o| o.operation).collect();
+    assert_eq!(ops, vec![Operation::Delete, Operation::Error, Operation::Skip]);
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/myns/configmaps/a HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/b HTTP/1.1",
+    ]);
+}
+
+fn delete_at(c: &Context, url: Url, body: &str) -> Result<(Operation, u16, JsonValue)> {
+    let resp = c.request(Method::Delete, url, Some((ContentType::json(), body)))?;
+    let status = resp.status.to_u16();
+    let v = kube_result(resp)?;
+
+    // 202 Accepted: eg: waiting on finalizers or foreground deletion
+    let op = if status == 202 { Operation::Deleting } else { Operation::Delete };
+
+    Ok((op, status, v))
+}
+
+fn do_update<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    c.request_format = matches.value_of("request_format").unwrap().parse()?;
+    if let Some(values) = matches.values_of("patch_type_for") {
+        c.patch_types = values.map(kutils::PatchType::parse_override)
+            .collect::<Result<_>>()
+            .chain_err(|| "Invalid --patch-type-for")?;
+    }
+    init_vm_options(&mut c.vm, matches)?;
+
+    let mut parsed = {
+        let filename = matches.value_of_os("file").unwrap();
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
+
+        json::parse(&json)
+            .chain_err(|| "Unable to parse jsonnet output")?
+    };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
+    set_images(&mut parsed, matches);
+    set_annotations(&mut parsed, matches);
+
+    let hooked: Vec<JsonValue>;
+    let mut objects = select_objects(&parsed, matches);
+    if !matches.is_present("no_sort") {
+        objects.sort_by_key(|&v| kutils::dep_first(v));
+    }
+    if let Some(command) = matches.value_of("exec_hook") {
+        hooked = objects.iter()
+            .map(|&o| exec_hook(command, o))
+            .collect::<Result<_>>()?;
+        objects = hooked.iter().collect();
+    }
+    // After the hook, which may change the kind
+    check_kind_policy(&objects, matches)?;
+
+    let prune_namespaces: Option<Vec<_>> = matches.values_of("prune_namespace").map(|v| v.collect());
+    let prunable = match matches.value_of("selector") {
+        Some(selector) if matches.is_present("prune") =>
+            find_prunable(c, &objects, selector, prune_namespaces.as_deref())?,
+        _ => vec![],
+    };
+
+    let all_objects = objects.clone();
+    let mut results = Results::default();
+
+    if let Some(path) = matches.value_of_os("state_file") {
+        let state = read_state_file(Path::new(path))?;
+        objects.retain(|&o| {
+            if state[state_key(o)] == object_hash(o).as_str() {
+                info!("{} unchanged since last update", o.k8s_id());
+                results.push(Outcome::new(o.k8s_id(), Operation::Unchanged, None));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    if let Some(dir) = matches.value_of_os("output_patch") {
+        write_patches(c, Path::new(dir), &objects, matches.value_of("subresource") == Some("status"))?;
+    }
+
+    if matches.is_present("preview") {
+        let output: OutputFormat = matches.value_of("format").unwrap().parse()?;
+        let list = object!{
+            "apiVersion" => "v1",
+            "kind" => "List",
+            "items" => preview_objects(c, &objects)?
+        };
+        return output.emit(&list, w);
+    }
+
+    if matches.is_present("plan") {
+        let mut plan = plan_objects(c, &objects, plan_update(matches.is_present("create")))?;
+        plan.extend(prunable.into_iter().map(|id| ("prune", id)));
+        return write_plan(&plan, w);
+    }
+
+    if matches.is_present("dry_run") {
+        let quiet = matches.is_present("output_results");
+        for o in objects {
+            if !quiet {
+                writeln!(w, "would apply {}", o.k8s_id())?;
+            }
+            results.push(Outcome::new(o.k8s_id(), Operation::Skip, None));
+        }
+        for id in prunable {
+            if !quiet {
+                writeln!(w, "would prune {}", id)?;
+            }
+            results.push(Outcome::new(id, Operation::Skip, None));
+        }
+        return write_results(matches, &results, w);
+    }
+
+    if matches.is_present("check_permissions") {
+        let mut verbs = vec!["get", "patch"];
+        if matches.is_present("create") {
+            verbs.push("create");
+        }
+        if matches.is_present("force") {
+            verbs.extend(&["delete", "create"]);
+        }
+        check_permissions(c, &objects, &verbs, &prunable)?;
+    }
+
+    let mut updated = vec![];
+    let mut r = ensure_namespaces(c, matches, &objects, &mut results)
+        .and_then(|()| update_objects(c, matches, &objects, prunable, &mut results, &mut updated));
+    if let (true, Some(path)) = (r.is_ok(), matches.value_of_os("state_file")) {
+        r = write_state_file(Path::new(path), &all_objects);
+    }
+    if r.is_ok() && matches.is_present("show_ownership") {
+        let manager = c.client.user_agent.split('/').next().unwrap();
+        write_ownership(&updated, manager, &mut w)?;
+    }
+    let written = write_results(matches, &results, w);
+    r.and(written)
+}
+
+/// Predict the result of updating `objects`, by merging each into its
+/// live version as the server would.  Objects that don't exist yet are
+/// shown as-is.
+fn preview_objects(c: &mut Context, objects: &[&JsonValue]) -> Result<Vec<JsonValue>> {
+    let mut res = vec![];
+    for &o in objects {
+        let url = c.url_for(o, true)?;
+        let resp = c.request(Method::Get, url, None)?;
+
+        let merged = if resp.status == hyper::NotFound {
+            o.clone()
+        } else {
+            let mut v = kube_result(resp)?;
+            c.patch_type(&o.k8s_kind())?.apply(&mut v, o);
+            v
+        };
+        res.push(merged);
+    }
+    Ok(res)
+}
+
+#[test]
+fn test_preview_objects() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"foo","uid":"1234"},"data":{"a":"1","b":"2"}}"#),
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+    ]);
+    let mut c = mock_context(url);
+
+    let foo = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo"},
+        "data" => object!{"b" => "3", "c" => "4"}
+    };
+    let bar = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "bar"}
+    };
+
+    let res = preview_objects(&mut c, &[&foo, &bar]).unwrap();
+    assert_eq!(res, vec![
+        object!{
+            "apiVersion" => "v1",
+            "kind" => "ConfigMap",
+            "metadata" => object!{"name" => "foo", "uid" => "1234"},
+            "data" => object!{"a" => "1", "b" => "3", "c" => "4"}
+        },
+        bar,
+    ]);
+    server.join().unwrap();
+}
+
+/// Ask the user a yes/no question on the terminal.  Fails if there is
+/// no terminal to ask on.
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::{BufRead,IsTerminal};
+
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("{} Re-run with --yes to confirm non-interactively", prompt);
+    }
+    eprint!("{} [y/N] ", prompt);
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// Delete `o` from the server, wait for it to be gone, then create it
+/// again.  As `kubectl replace --force`.
+fn replace_one(c: &mut Context, o: &JsonValue) -> Result<(Operation, u16, JsonValue)> {
+    let url = c.url_for(o, true)?;
+    let options: JsonValue = kutils::DeleteOptions {
+        propagation_policy: Some(kutils::PropagationPolicy::Foreground),
+        ..Default::default()
+    }.into();
+    delete_at(c, url.clone(), &options.dump())?;
+
+    let deadline = Instant::now() + DELETE_TIMEOUT;
+    loop {
+        let resp = c.request(Method::Get, url.clone(), None)?;
+        if resp.status == hyper::NotFound {
+            break;
+        }
+        kube_result(resp)?;
+        if Instant::now() > deadline {
+            bail!(ErrorKind::WaitTimeout(format!("deletion of {}", o.k8s_id())));
+        }
+        thread::sleep(DELETE_POLL_INTERVAL);
+    }
+
+    let (_, status, v) = create_one(c, o)?;
+    Ok((Operation::Replace, status, v))
+}
+
+#[test]
+fn test_replace_immutable() {
+    let (url, server) = mock_server(vec![
+        (422, r#"{"kind":"Status","status":"Failure","reason":"Invalid","code":422,"message":"ConfigMap \"foo\" is invalid: data: Forbidden: field is immutable when `immutable` is set"}"#),
+        (200, r#"{"kind":"Status","status":"Success"}"#),
+        (200, r#"{"kind":"ConfigMap","metadata":{"name":"foo","deletionTimestamp":"2024-01-01T00:00:00Z"}}"#),
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+        (201, r#"{"kind":"ConfigMap","metadata":{"name":"foo","uid":"new"}}"#),
+    ]);
+    let mut c = mock_context(url);
+
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"},
+        "immutable" => true,
+        "data" => object!{"a" => "2"}
+    };
+    let e = update_one(&mut c, &o, false, 0).unwrap_err();
+    assert!(kutils::is_immutable_field_error(&e));
+
+    let (op, status, new_obj) = replace_one(&mut c, &o).unwrap();
+    assert_eq!(op, Operation::Replace);
+    assert_eq!(status, 201);
+    assert_eq!(new_obj["metadata"]["uid"], "new");
+
+    assert_eq!(server.join().unwrap(), vec![
+        "PATCH /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "DELETE /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "POST /api/v1/namespaces/myns/configmaps HTTP/1.1",
+    ]);
+}
+
+/// Objects returned by the server are added to `updated`.
+fn update_objects<'a>(c: &mut Context, matches: &ArgMatches<'a>, objects: &[&JsonValue], prunable: Vec<kutils::ObjectId>, results: &mut Results, updated: &mut Vec<JsonValue>) -> Result<()> {
+    let parallelism = parallelism(matches)?;
+    let creat = matches.is_present("create");
+    let wait = matches.is_present("wait");
+    let condition = matches.value_of("wait_for_condition");
+    let condition_kinds: Vec<kutils::K8sKind> = matches.values_of("wait_for_kinds")
+        .map(|v| v.map(parse_kind_arg).collect())
+        .transpose()?
+        .unwrap_or_default();
+    let conflict_retries = matches.value_of("max_conflict_retries").unwrap().parse()
+        .chain_err(|| "Invalid --max-conflict-retries")?;
+    let status_subresource = matches.value_of("subresource") == Some("status");
+    let force = matches.is_present("force");
+    let yes = matches.is_present("yes");
+    let quiet_unchanged = matches.is_present("quiet_unchanged");
+    let allowed_changes: Option<Vec<_>> = if matches.is_present("fail_on_server_change") {
+        let extra = matches.values_of("allow_server_change").into_iter().flatten();
+        Some(SERVER_CHANGES_ALLOWED.iter().cloned().chain(extra).collect())
+    } else {
+        None
+    };
+
+    let mut wait_objects = Vec::new();
+    let mut unchanged = 0;
+
+    // Everything but the first PATCH of each object is sent one at a
+    // time, in order.
+    let process = |c: &mut Context, results: &mut Results, updated: &mut Vec<JsonValue>,
+                   wait_objects: &mut Vec<JsonValue>, unchanged: &mut usize,
+                   o: &JsonValue, mut first: Option<Result<Response>>| -> Result<()> {
+        // The server doesn't change the resourceVersion for a patch
+        // that changes nothing, so compare it before and after.
+        let mut before = None;
+        if quiet_unchanged {
+            if let Some(live) = fetch_live(c, o)? {
+                before = live["metadata"]["resourceVersion"].as_str().map(|rv| rv.to_owned());
+                if first.is_none() && !status_subresource {
+                    first = Some(update_request(c, o).and_then(|(url, patch_type, body)| {
+                        c.client.request_at(log::Level::Debug, Method::Patch, url, Some((patch_type, &body)))
+                    }));
+                }
+            }
+        }
+
+        let r = if status_subresource {
+            update_with_status(c, o, creat, conflict_retries)
+        } else {
+            update_one_from(c, o, creat, conflict_retries, first)
+        };
+        let r = match r {
+            Err(e) if force && kutils::is_immutable_field_error(&e) => {
+                let prompt = format!("{} has changes to immutable fields. Delete and recreate it?", o.k8s_id());
+                if yes || confirm(&prompt)? {
+                    warn!("Replacing {}: {}", o.k8s_id(), e);
+                    replace_one(c, o)
+                } else {
+                    Err(e)
+                }
+            },
+            r => r,
+        };
+        let is_unchanged = match r {
+            Ok((Operation::Update, _, ref v)) =>
+                before.is_some() && v["metadata"]["resourceVersion"].as_str() == before.as_deref(),
+            _ => false,
+        };
+        let r = r.map(|(op, status, v)| (if is_unchanged { Operation::Unchanged } else { op }, status, v));
+        let new_obj = results.record(o.k8s_id(), r)?;
+        if is_unchanged {
+            debug!("{} unchanged", o.k8s_id());
+            *unchanged += 1;
+        } else if quiet_unchanged {
+            info!("Updated {}", o.k8s_id());
+        }
+        if let Some(ref allowed) = allowed_changes {
+            check_server_changes(o, &new_obj, allowed)?;
+        }
+
+        // TODO: (Optionally) Show diff between orig and server response
+
+        updated.push(new_obj.clone());
+        // Objects without conditions (eg ConfigMaps) would never
+        // become ready
+        let has_conditions = new_obj["status"]["conditions"].is_array() ||
+            condition_kinds.contains(&o.k8s_kind());
+        if (condition.is_some() && has_conditions) ||
+            (wait && kutils::ROLLOUT_KINDS.iter().any(|&k| o.is_k8s_kind(k))) {
+            wait_objects.push(new_obj);
+        }
+        Ok(())
+    };
+
+    catch_interrupts();
+    if parallelism > 1 && !quiet_unchanged && !status_subresource {
+        let mut done = 0;
+        for rank in dep_ranks(objects) {
+            check_interrupted(&c.client, results, objects[done..].iter().map(|o| o.k8s_id()).chain(prunable.iter().cloned()))?;
+            done += rank.len();
+
+            let mut sent = rank.iter().zip(send_patches(c, rank, parallelism)).enumerate();
+            while let Some((i, (&o, first))) = sent.next() {
+                if first.is_none() {
+                    // Not sent after a failure (which is handled below)
+                    // or interrupt
+                    let remaining = rank[i..].iter().chain(&objects[done..]).map(|o| o.k8s_id());
+                    check_interrupted(&c.client, results, remaining.chain(prunable.iter().cloned()))?;
+                }
+                let r = process(c, results, updated, &mut wait_objects, &mut unchanged, o, first);
+                if let Err(e) = r {
+                    // Record what happened to the rest, already sent
+                    for (_, (&o, first)) in sent {
+                        if let Some(resp) = first {
+                            let r = resp.and_then(|resp| {
+                                let status = resp.status.to_u16();
+                                Ok((Operation::Update, status, kube_result(resp)?))
+                            });
+                            let _ = results.record(o.k8s_id(), r);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    } else {
+        for (i, &o) in objects.iter().enumerate() {
+            check_interrupted(&c.client, results, objects[i..].iter().map(|o| o.k8s_id()).chain(prunable.iter().cloned()))?;
+            process(c, results, updated, &mut wait_objects, &mut unchanged, o, None)?;
+        }
+    }
+
+    if quiet_unchanged {
+        info!("{} unchanged", unchanged);
+    }
+
+    if !prunable.is_empty() {
+        let policy = matches.value_of("prune_propagation_policy").unwrap().parse()?;
+        let options: JsonValue = kutils::DeleteOptions {
+            propagation_policy: Some(policy),
+            ..Default::default()
+        }.into();
+        let body = options.dump();
+
+        for (i, id) in prunable.iter().enumerate() {
+            check_interrupted(&c.client, results, prunable[i..].iter().cloned())?;
+            let id = id.clone();
+            info!("Pruning {}", id);
+            let r = c.url_for_id(&id)
+                .and_then(|url| delete_at(c, url, &body));
+            results.record(id, r)?;
+        }
+    }
+
+    let timeout = match matches.value_of("wait_timeout") {
+        Some(secs) => Some(Duration::from_secs(secs.parse().chain_err(|| "Invalid --wait-timeout")?)),
+        None => None,
+    };
+    wait_for_all(c, wait_objects, condition, timeout)?;
+
+    Ok(())
+}
+
+/// Print the fields owned by each field manager of `objects`, with
+/// `manager` (the one the server assigns us) first.
+fn write_ownership<W: Write>(objects: &[JsonValue], manager: &str, mut w: W) -> Result<()> {
+    for o in objects {
+        writeln!(w, "{}:", o.k8s_id())?;
+        let mut owners = kutils::field_owners(o);
+        if owners.is_empty() {
+            writeln!(w, "  no managedFields")?;
+        }
+        owners.sort_by_key(|(m, _)| m != manager);
+        for (m, paths) in owners {
+            let ours = if m == manager { " (this client)" } else { "" };
+            writeln!(w, "  {}{}:", m, ours)?;
+            for p in paths {
+                writeln!(w, "    {}", p)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write_ownership() {
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{
+            "name" => "foo",
+            "managedFields" => array![
+                object!{"manager" => "other", "fieldsV1" => object!{"f:data" => object!{"f:b" => object!{}}}},
+                object!{"manager" => "kubecfg", "fieldsV1" => object!{"f:data" => object!{"f:a" => object!{}}}}
+            ]
+        }
+    };
+    let mut bare = o.clone();
+    bare["metadata"].remove("managedFields");
+
+    let mut buf = vec![];
+    write_ownership(&[o, bare], "kubecfg", &mut buf).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), "\
+configmap/foo:
+  kubecfg (this client):
+    data.a
+  other:
+    data.b
+configmap/foo:
+  no managedFields
+");
+
+    // Would be mixed in with the results JSON
+    for &cmd in &["update", "apply"] {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["kubecfg", cmd, "-f", "x"];
+            argv.extend(args);
+            build_cli("test").get_matches_from_safe(argv)
+        };
+        assert!(parse(&["--show-ownership"]).is_ok());
+        assert!(parse(&["--show-ownership", "--output-results", "json"]).is_err());
+    }
+}
+
+/// Fields that `--fail-on-server-change` always lets the server change
+const SERVER_CHANGES_ALLOWED: &'static [&'static str] = &["status", "metadata"];
+
+/// Fail if the server's response `returned` doesn't have the fields
+/// sent in `o`, other than those under the `allowed` paths.  Fields
+/// the server added (eg defaults) aren't checked.
+fn check_server_changes(o: &JsonValue, returned: &JsonValue, allowed: &[&str]) -> Result<()> {
+    let is_allowed = |f: &str| allowed.iter().any(|&a| {
+        f.strip_prefix(a).is_some_and(|rest| rest.is_empty() || rest.starts_with('.') || rest.starts_with('['))
+    });
+    let changed: Vec<_> = kutils::unapplied_fields(o, returned).into_iter()
+        .filter(|f| !is_allowed(f))
+        .collect();
+    if changed.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorKind::ServerChanged(o.k8s_id().to_string(), changed).into())
+    }
+}
+
+#[test]
+fn test_check_server_changes() {
+    let sent = object!{
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "labels" => object!{"team" => "a"}},
+        "data" => object!{"a" => "b"}
+    };
+    let returned = object!{
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "labels" => object!{"team" => "b"}, "uid" => "1234"},
+        "data" => object!{"a" => "b", "added" => "by webhook"}
+    };
+    assert!(check_server_changes(&sent, &returned, &["status", "metadata"]).is_ok());
+
+    let e = check_server_changes(&sent, &returned, &["status", "meta"]).unwrap_err();
+    match *e.kind() {
+        ErrorKind::ServerChanged(_, ref fields) => assert_eq!(fields, &["metadata.labels.team"]),
+        ref k => panic!("unexpected error {:?}", k),
+    }
+}
+
+/// Fetch the live version of `o`, or `None` if it doesn't exist.  The
+/// request is only logged at debug level.
+fn fetch_live(c: &mut Context, o: &JsonValue) -> Result<Option<JsonValue>> {
+    let url = c.url_for(o, true)?;
+    let resp = c.client.request_at(log::Level::Debug, Method::Get, url, None)?;
+    if resp.status == hyper::NotFound {
+        return Ok(None);
+    }
+    kube_result(resp).map(Some)
+}
+
+fn do_get<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
+
+    let mut parsed = {
+        let filename = matches.value_of_os("file").unwrap();
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
+
+        json::parse(&json)
+            .chain_err(|| "Unable to parse jsonnet output")?
+    };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
+
+    let objects = select_objects(&parsed, matches);
+    let items = get_objects(c, &objects, matches.is_present("strict"))?;
+
+    let output: OutputFormat = matches.value_of("format").unwrap().parse()?;
+    let list = object!{
+        "apiVersion" => "v1",
+        "kind" => "List",
+        "items" => JsonValue::Array(items)
+    };
+    output.emit(&list, w)
+}
+
+/// The live versions of `objects`.  Missing objects are left out with
+/// a warning, or are an error if `strict`.
+fn get_objects(c: &mut Context, objects: &[&JsonValue], strict: bool) -> Result<Vec<JsonValue>> {
+    let mut res = vec![];
+    for &o in objects {
+        match fetch_live(c, o)? {
+            Some(live) => res.push(live),
+            None if strict => bail!("{} not found", c.resolved_id(o)?),
+            None => warn!("{} not found", c.resolved_id(o)?),
+        }
+    }
+    Ok(res)
+}
+
+#[test]
+fn test_get_objects() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"a","uid":"1"}}"#),
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"a","uid":"1"}}"#),
+        (404, r#"{"kind":"Status","code":404,"reason":"NotFound"}"#),
+    ]);
+    let mut c = mock_context(url);
+    let cm = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name}
+    };
+    let (a, b) = (cm("a"), cm("b"));
+
+    let live = get_objects(&mut c, &[&a, &b], false).unwrap();
+    assert_eq!(live.len(), 1);
+    assert_eq!(live[0]["metadata"]["uid"], "1");
+
+    let e = get_objects(&mut c, &[&a, &b], true).unwrap_err();
+    assert_eq!(e.to_string(), "configmap/b in namespace default not found");
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/default/configmaps/a HTTP/1.1",
+        "GET /api/v1/namespaces/default/configmaps/b HTTP/1.1",
+        "GET /api/v1/namespaces/default/configmaps/a HTTP/1.1",
+        "GET /api/v1/namespaces/default/configmaps/b HTTP/1.1",
+    ]);
+}
+
+/// For `--plan`: what each of `objects` would have done to it, decided
+/// by `classify` from the object and its live version (if it exists).
+fn plan_objects<F>(c: &mut Context, objects: &[&JsonValue], classify: F) -> Result<Vec<(&'static str, kutils::ObjectId)>>
+    where F: Fn(&mut Context, &JsonValue, Option<&JsonValue>) -> Result<&'static str>
+{
+    let mut plan = vec![];
+    for &o in objects {
+        let live = fetch_live(c, o)?;
+        plan.push((classify(c, o, live.as_ref())?, c.resolved_id(o)?));
+    }
+    Ok(plan)
+}
+
+fn write_plan<W>(plan: &[(&'static str, kutils::ObjectId)], w: W) -> Result<()>
+    where W: Write
+{
+    let rows: Vec<_> = plan.iter()
+        .map(|(op, id)| vec![
+            op.to_string(),
+            id.kind.kind.clone(),
+            id.namespace.clone().unwrap_or_default(),
+            id.name.clone(),
+        ])
+        .collect();
+    write_table(&["OPERATION", "KIND", "NAMESPACE", "NAME"], &rows, w)
+}
+
+fn parallelism_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("parallelism")
+        .long("parallelism")
+        .value_name("N")
+        .default_value("1")
+        .help("Send requests for up to N objects at once. Namespaces, then objects pods may depend on, then everything else still each wait for the group before. Ignored with --no-sort")
+}
+
+/// `--parallelism`, or 1 with `--no-sort`
+fn parallelism<'a>(matches: &ArgMatches<'a>) -> Result<usize> {
+    if matches.is_present("no_sort") {
+        return Ok(1);
+    }
+    match matches.value_of("parallelism").unwrap().parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("Invalid --parallelism: expected a positive number"),
+    }
+}
+
+/// Split `objects` (sorted by `dep_first`) into groups of the same
+/// rank.  Each group can be applied concurrently, once the previous
+/// group is done.
+fn dep_ranks<'a, 'b>(objects: &'a [&'b JsonValue]) -> impl Iterator<Item = &'a [&'b JsonValue]> {
+    objects.chunk_by(|a, b| kutils::dep_first(a) == kutils::dep_first(b))
+}
+
+/// Apply `f` to each of `items`, with up to `parallelism` running at
+/// once, returning the results in order.  Once any fails, or `stop` is
+/// set, no more are started: those are `None`.  Since items are
+/// started in order, any `None` comes after an error or the stop.
+fn parallel_map<T, R, F>(items: &[T], parallelism: usize, stop: &AtomicBool, f: F) -> Vec<Option<Result<R>>>
+    where T: Sync, R: Send, F: Fn(&T) -> Result<R> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Vec<_> = items.iter().map(|_| Mutex::new(None)).collect();
+
+    let work = || while !failed.load(Ordering::SeqCst) && !stop.load(Ordering::SeqCst) {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        if i >= items.len() {
+            break;
+        }
+        let r = f(&items[i]);
+        if r.is_err() {
+            failed.store(true, Ordering::SeqCst);
+        }
+        *results[i].lock().unwrap() = Some(r);
+    };
+    if parallelism <= 1 || items.len() <= 1 {
+        work();
+    } else {
+        thread::scope(|s| {
+            for _ in 0..parallelism.min(items.len()) {
+                s.spawn(work);
+            }
+        });
+    }
+
+    results.into_iter().map(|r| r.into_inner().unwrap()).collect()
+}
+
+#[test]
+fn test_parallel_map() {
+    let items: Vec<u32> = (0..20).collect();
+    let stop = AtomicBool::new(false);
+    let doubled = parallel_map(&items, 4, &stop, |&i| Ok(i * 2));
+    let doubled: Vec<_> = doubled.into_iter().map(|r| r.unwrap().unwrap()).collect();
+    assert_eq!(doubled, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+
+    // Sequentially, nothing after a failure is started
+    let res = parallel_map(&items, 1, &stop, |&i| if i == 3 { bail!("three") } else { Ok(i) });
+    assert!(res[2].as_ref().unwrap().is_ok());
+    assert!(res[3].as_ref().unwrap().is_err());
+    assert!(res[4..].iter().all(|r| r.is_none()));
+
+    // Nor after being stopped
+    let res = parallel_map(&items, 1, &stop, |&i| {
+        if i == 3 {
+            stop.store(true, Ordering::SeqCst);
+        }
+        Ok(i)
+    });
+    assert!(res[..4].iter().all(|r| r.as_ref().unwrap().is_ok()));
+    assert!(res[4..].iter().all(|r| r.is_none()));
+    assert!(parallel_map(&items, 4, &stop, |&i| Ok(i)).iter().all(|r| r.is_none()));
+
+    let objects = [
+        object!{"apiVersion" => "v1", "kind" => "Namespace"},
+        object!{"apiVersion" => "v1", "kind" => "ConfigMap"},
+        object!{"apiVersion" => "v1", "kind" => "Secret"},
+        object!{"apiVersion" => "apps/v1", "kind" => "Deployment"},
+    ];
+    let objects: Vec<_> = objects.iter().collect();
+    let sizes: Vec<_> = dep_ranks(&objects).map(|r| r.len()).collect();
+    assert_eq!(sizes, vec![1, 2, 1]);
+}
+
+fn plan_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("plan")
+        .long("plan")
+        .help("Only print what would be done to each object, checking which objects already exist")
+}
+
+/// `--plan` classification for `update`
+fn plan_update(creat: bool) -> impl Fn(&mut Context, &JsonValue, Option<&JsonValue>) -> Result<&'static str> {
+    move |c, o, live| Ok(match live {
+        Some(live) if kutils::is_applied(o, live, c.patch_type(&o.k8s_kind())?) => "unchanged",
+        Some(_) => "update",
+        None if creat => "create",
+        None => "missing",
+    })
+}
+
+#[test]
+fn test_plan() {
+    let (url, server) = mock_server(vec![
+        (404, r#"{"kind":"Status","code":404}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"same","namespace":"myns"},"data":{"a":"b"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"changed","namespace":"myns"},"data":{"a":"old"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"owned","namespace":"myns","ownerReferences":[{"kind":"Foo","name":"x","controller":true}]},"data":{"a":"b"}}"#),
+        (404, r#"{"kind":"Status","code":404}"#),
+    ]);
+    let mut c = mock_context(url);
+    let configmap = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns"},
+        "data" => object!{"a" => "b"}
+    };
+    let (new, same, changed) = (configmap("new"), configmap("same"), configmap("changed"));
+    let mut unqualified = configmap("other");
+    unqualified["metadata"].remove("namespace");
+
+    // With a merge patch, a field removed from a list element is a
+    // change, as the list is replaced
+    c.patch_types = vec![kutils::PatchType::parse_override("v1/ConfigMap=merge").unwrap()];
+    let mut owned = configmap("owned");
+    owned["metadata"]["ownerReferences"] = array![object!{"kind" => "Foo", "name" => "x"}];
+
+    let plan = plan_objects(&mut c, &[&new, &same, &changed, &owned], plan_update(true)).unwrap();
+    let ops: Vec<_> = plan.iter().map(|&(op, _)| op).collect();
+    assert_eq!(ops, vec!["create", "unchanged", "update", "update"]);
+
+    let plan = plan_objects(&mut c, &[&unqualified], plan_update(false)).unwrap();
+    let mut out = vec![];
+    write_plan(&plan, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "OPERATION  KIND       NAMESPACE  NAME\n\
+                missing    ConfigMap  default    other\n");
+
+    assert_eq!(server.join().unwrap().len(), 5);
+}
+
+#[test]
+fn test_quiet_unchanged() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"same","namespace":"myns","resourceVersion":"1"},"data":{"a":"b"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"same","namespace":"myns","resourceVersion":"1"},"data":{"a":"b"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"changed","namespace":"myns","resourceVersion":"1"},"data":{"a":"old"}}"#),
+        (200, r#"{"metadata":{"name":"changed","resourceVersion":"2"},"data":{"a":"b"}}"#),
+    ]);
+    let mut c = mock_context(url);
+
+    let configmap = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns"},
+        "data" => object!{"a" => "b"}
+    };
+    let same = configmap("same");
+    let changed = configmap("changed");
+
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "update", "--quiet-unchanged", "-f", "x"]);
+    let matches = matches.subcommand_matches("update").unwrap();
+    let mut results = Results::default();
+    update_objects(&mut c, matches, &[&same, &changed], vec![], &mut results, &mut vec![]).unwrap();
+
+    let ops: Vec<_> = results.outcomes.iter().map(|o| o.operation).collect();
+    assert_eq!(ops, vec![Operation::Unchanged, Operation::Update]);
+    // Every object is still patched, so removals are always sent
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/myns/configmaps/same HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/same HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/changed HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/changed HTTP/1.1",
+    ]);
+}
+
+#[test]
+fn test_update_wait_for_condition() {
+    let ready = r#"{"type":"MODIFIED","object":{"metadata":{"resourceVersion":"3"},"status":{"conditions":[{"type":"Ready","status":"True"}]}}}
+"#;
+    let configmap = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns"}
+    };
+    let plain = configmap("plain");
+    let reporting = configmap("reporting");
+    let run = |args: Vec<&str>, responses| {
+        let (url, server) = mock_server(responses);
+        let mut c = mock_context(url);
+        let mut args = args;
+        args.extend(vec!["--wait-for-condition", "Ready", "--wait-timeout", "10", "-f", "x"]);
+        let matches = build_cli("test").get_matches_from(args);
+        let matches = matches.subcommand_matches("update").unwrap();
+        update_objects(&mut c, matches, &[&plain, &reporting], vec![], &mut Results::default(), &mut vec![]).unwrap();
+        let mut requests = server.join().unwrap();
+        requests.sort();
+        requests
+    };
+
+    // Only objects reporting conditions are waited on
+    assert_eq!(run(vec!["kubecfg", "update"], vec![
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"plain","namespace":"myns","resourceVersion":"2"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"reporting","namespace":"myns","resourceVersion":"2"},"status":{"conditions":[{"type":"Ready","status":"False"}]}}"#),
+        (200, ready),
+    ]), vec![
+        "GET /api/v1/namespaces/myns/configmaps/reporting?watch=true&resourceVersion=2 HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/plain HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/reporting HTTP/1.1",
+    ]);
+
+    // .. unless their kind is named
+    assert_eq!(run(vec!["kubecfg", "update", "--wait-for-kinds", "v1/ConfigMap"], vec![
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"plain","namespace":"myns","resourceVersion":"2"}}"#),
+        (200, r#"{"apiVersion":"v1","kind":"ConfigMap","metadata":{"name":"reporting","namespace":"myns","resourceVersion":"2"}}"#),
+        (200, ready),
+        (200, ready),
+    ]), vec![
+        "GET /api/v1/namespaces/myns/configmaps/plain?watch=true&resourceVersion=2 HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/reporting?watch=true&resourceVersion=2 HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/plain HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/reporting HTTP/1.1",
+    ]);
+}
+
+/// Serve the canned `(status, body)` responses, one per connection,
+/// to requests on a local port.  The returned thread yields the
+/// request lines that were received.
+#[cfg(test)]
+fn mock_server(responses: Vec<(u16, &'static str)>) -> (Url, thread::JoinHandle<Vec<String>>) {
+    let (url, server) = mock_server_headers(responses);
+    let handle = thread::spawn(move || {
+        server.join().unwrap().into_iter()
+            .map(|mut r| r.remove(0))
+            .collect()
+    });
+    (url, handle)
+}
+
+/// As `mock_server`, but also returns the headers of each request
+/// (after its request line).
+#[cfg(test)]
+fn mock_server_headers(responses: Vec<(u16, &'static str)>) -> (Url, thread::JoinHandle<Vec<Vec<String>>>) {
+    use std::io::{BufRead,BufReader,Read};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+    let handle = thread::spawn(move || {
+        let mut requests = vec![];
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(stream);
+
+            let mut line = String::new();
+            r.read_line(&mut line).unwrap();
+            let mut request = vec![line.trim_end().to_owned()];
+
+            let mut len = 0;
+            loop {
+                let mut header = String::new();
+                r.read_line(&mut header).unwrap();
+                if header.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(v) = header.to_lowercase().strip_prefix("content-length:") {
+                    len = v.trim().parse().unwrap();
+                }
+                request.push(header.trim_end().to_owned());
+            }
+            requests.push(request);
+            let mut req_body = vec![0; len];
+            r.read_exact(&mut req_body).unwrap();
+
+            write!(r.get_mut(), "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, body.len(), body).unwrap();
+        }
+        requests
+    });
+
+    (url, handle)
+}
+
+#[cfg(test)]
+fn mock_context(server_url: Url) -> Context {
+    let mut api_cache = ApiMap::new();
+    api_cache.insert(kutils::K8sKind::new("v1", "ConfigMap"), kutils::ApiResource {
+        name: "configmaps".to_owned(),
+        kind: "ConfigMap".to_owned(),
+        namespaced: true,
+        subresources: vec![],
+        custom: None,
+    });
+
+    Context {
+        vm: JsonnetVm::new(),
+        server_url,
+        client: Arc::new(ApiClient::new(Client::new())),
+        api_cache,
+        default_namespace: "default".to_owned(),
+        default_namespace_source: "built-in default".to_owned(),
+        force_namespace: false,
+        max_object_size: None,
+        request_format: OutputFormat::Json,
+        chunk_size: None,
+        patch_types: vec![],
+    }
+}
+
+#[test]
+fn test_max_object_size() {
+    let mut c = mock_context(Url::parse("http://dummy/").unwrap());
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo"},
+        "data" => object!{"big" => "x".repeat(100)}
+    };
+    assert_eq!(c.object_body(&o).unwrap(), o.dump());
+
+    c.max_object_size = Some(100);
+    let e = c.object_body(&o).unwrap_err();
+    match *e.kind() {
+        ErrorKind::ObjectTooLarge(ref id, size, 100) => {
+            assert_eq!(id, "configmap/foo");
+            assert_eq!(size, o.dump().len());
+        },
+        ref k => panic!("unexpected error {:?}", k),
+    }
+}
+
+#[test]
+fn test_force_namespace() {
+    let mut c = mock_context(Url::parse("http://dummy/").unwrap());
+    c.default_namespace = "target".to_owned();
+    let mut parsed = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "other"}
+    };
+
+    c.apply_force_namespace(&mut parsed);
+    assert_eq!(c.url_for(&parsed, true).unwrap().path(), "/api/v1/namespaces/other/configmaps/foo");
+
+    c.force_namespace = true;
+    c.apply_force_namespace(&mut parsed);
+    assert_eq!(c.url_for(&parsed, true).unwrap().path(), "/api/v1/namespaces/target/configmaps/foo");
+}
+
+#[test]
+fn test_create_body() {
+    let mut c = mock_context(Url::parse("http://dummy/").unwrap());
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo"}
+    };
+
+    let (content_type, body) = c.create_body(&o).unwrap();
+    assert_eq!(content_type, ContentType::json());
+    assert_eq!(body, o.dump());
+
+    c.request_format = OutputFormat::Yaml;
+    let (content_type, body) = c.create_body(&o).unwrap();
+    assert_eq!(content_type.to_string(), "application/yaml");
+    assert_eq!(body, "---\napiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: foo\n");
+}
+
+#[test]
+fn test_update_conflict_retry() {
+    let (url, server) = mock_server(vec![
+        (409, r#"{"kind":"Status","code":409,"reason":"Conflict"}"#),
+        (200, r#"{"metadata":{"name":"foo","resourceVersion":"42"}}"#),
+        (200, r#"{"metadata":{"name":"foo","resourceVersion":"43"}}"#),
+    ]);
+    let mut c = mock_context(url);
+
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+    let (op, status, new_obj) = update_one(&mut c, &o, false, 1).unwrap();
+    assert_eq!(op, Operation::Update);
+    assert_eq!(status, 200);
+    assert_eq!(new_obj["metadata"]["resourceVersion"], "43");
+
+    assert_eq!(server.join().unwrap(), vec![
+        "PATCH /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+    ]);
+
+    let (url, server) = mock_server(vec![
+        (409, r#"{"kind":"Status","code":409,"reason":"Conflict"}"#),
+    ]);
+    let mut c = mock_context(url);
+    assert!(update_one(&mut c, &o, false, 0).is_err());
+    server.join().unwrap();
+
+    // An exhausted budget stops retries despite --max-conflict-retries
+    let (url, server) = mock_server(vec![
+        (409, r#"{"kind":"Status","code":409,"reason":"Conflict"}"#),
+    ]);
+    let mut c = mock_context(url);
+    c.client.retry_budget.lock().unwrap().retries = Some(0);
+    assert!(update_one(&mut c, &o, false, 5).is_err());
+    assert!(c.client.retry_budget.lock().unwrap().exhausted);
+    server.join().unwrap();
+}
+
+fn is_done(o: &JsonValue, condition: Option<&str>) -> bool {
+    match condition {
+        Some(cond) => kutils::is_condition_true(o, cond),
+        None => kutils::is_rollout_done(o),
+    }
+}
+
+/// Watch the object at `url` until it is done.
+fn wait_for(client: &ApiClient, url: Url, mut resource_version: Option<String>, condition: Option<&str>) -> Result<()> {
+    let mut keep_going = true;
+
+    while keep_going {
+        let resp = client.request(Method::Get, watch_url(url.clone(), resource_version.as_deref()), None)?;
+
+        let mut expired = false;
+        keep_going = kutils::kube_watch(resp, |event| {
+            let o = &event["object"];
+            if event["type"] == "ERROR" {
+                // 410 Gone: resource_version is too old to watch from
+                if o["code"] == 410 {
+                    expired = true;
+                    return Ok(false);
+                }
+                bail!(ErrorKind::Kubernetes(o.clone()));
+            }
+            if let Some(rv) = o["metadata"]["resourceVersion"].as_str() {
+                resource_version = Some(rv.to_owned());
+            }
+            if let Some(reason) = kutils::rollout_failure(o) {
+                bail!(ErrorKind::RolloutFailed(reason));
+            }
+            Ok(!is_done(o, condition))
+        })?;
+
+        if expired {
+            // Restart from the current state
+            let o = kube_result(client.request(Method::Get, url.clone(), None)?)?;
+            if let Some(reason) = kutils::rollout_failure(&o) {
+                bail!(ErrorKind::RolloutFailed(reason));
+            }
+            resource_version = o["metadata"]["resourceVersion"].as_str()
+                .map(|rv| rv.to_owned());
+            keep_going = !is_done(&o, condition);
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait (concurrently) until all of `objects` are done, or `timeout`
+/// has passed.
+fn wait_for_all(c: &mut Context, objects: Vec<JsonValue>, condition: Option<&str>, timeout: Option<Duration>) -> Result<()> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let (tx, rx) = mpsc::channel();
+    let mut pending = BTreeMap::new();
+
+    for (i, o) in objects.into_iter().enumerate() {
+        // Watching from the applied resourceVersion won't replay the
+        // current state, so check it here first.
+        if is_done(&o, condition) {
+            continue;
+        }
+        if let Some(reason) = kutils::rollout_failure(&o) {
+            let e: Error = ErrorKind::RolloutFailed(reason).into();
+            return Err(e).chain_err(|| format!("Error waiting for {}", o.k8s_id()));
+        }
+
+        let id = o.k8s_id();
+        info!("Waiting for {}", id);
+
+        let url = c.url_for(&o, true)?;
+        let resource_version = o["metadata"]["resourceVersion"].as_str()
+            .map(|rv| rv.to_owned());
+        let client = c.client.clone();
+        let condition = condition.map(|s| s.to_owned());
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let r = wait_for(&client, url, resource_version, condition.as_deref());
+            // Receiver is gone if we already gave up
+            let _ = tx.send((i, r));
+        });
+
+        pending.insert(i, id);
+    }
+    // Only the threads hold senders now, so recv() fails rather than
+    // blocking forever if they all exit without sending
+    drop(tx);
+
+    let pending_ids = |pending: &BTreeMap<_, kutils::ObjectId>| {
+        pending.values().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+    };
+    while !pending.is_empty() {
+        let received = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let (i, r) = match received {
+            Ok(msg) => msg,
+            Err(mpsc::RecvTimeoutError::Timeout) => bail!(ErrorKind::WaitTimeout(pending_ids(&pending))),
+            // A thread exited (ie panicked) without sending
+            Err(mpsc::RecvTimeoutError::Disconnected) =>
+                bail!("Stopped waiting for {}", pending_ids(&pending)),
+        };
+
+        let id = pending.remove(&i).unwrap();
+        r.chain_err(|| format!("Error waiting for {}", id))?;
+        info!("{} is done", id);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_wait_for_all() {
+    let ready = r#"{"type":"MODIFIED","object":{"metadata":{"resourceVersion":"2"},"status":{"conditions":[{"type":"Ready","status":"True"}]}}}
+"#;
+    let (url, server) = mock_server(vec![(200, ready), (200, ready)]);
+    let mut c = mock_context(url);
+
+    let cm = |name: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name, "namespace" => "myns", "resourceVersion" => "1"}
+    };
+    wait_for_all(&mut c, vec![cm("a"), cm("b")], Some("Ready"), Some(Duration::from_secs(10))).unwrap();
+
+    let mut requests = server.join().unwrap();
+    requests.sort();
+    assert_eq!(requests, vec![
+        "GET /api/v1/namespaces/myns/configmaps/a?watch=true&resourceVersion=1 HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/b?watch=true&resourceVersion=1 HTTP/1.1",
+    ]);
+
+    let stalled = r#"{"type":"MODIFIED","object":{"status":{"conditions":[{"type":"Progressing","status":"False","reason":"ProgressDeadlineExceeded"}]}}}
+"#;
+    let (url, server) = mock_server(vec![(200, stalled)]);
+    let mut c = mock_context(url);
+    let mut rollout = cm("a");
+    rollout["spec"] = object!{"replicas" => 1};
+    let e = wait_for_all(&mut c, vec![rollout], None, None).unwrap_err();
+    assert_eq!(e.to_string(), "Error waiting for configmap/a in namespace myns");
+    assert_eq!(e.iter().nth(1).unwrap().to_string(), "Rollout failed: ProgressDeadlineExceeded");
+    server.join().unwrap();
+
+    // Accepts connections, but never responds
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut c = mock_context(Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap());
+    let e = wait_for_all(&mut c, vec![cm("a"), cm("b")], Some("Ready"), Some(Duration::from_millis(100)))
+        .unwrap_err();
+    assert_eq!(e.to_string(),
+               "Timed out waiting for configmap/a in namespace myns, configmap/b in namespace myns");
+}
+
+#[test]
+fn test_wait_for_error_event() {
+    let url = |base: &Url| base.join("api/v1/namespaces/myns/configmaps/a").unwrap();
+
+    // An expired resourceVersion restarts from the current object
+    let (base, server) = mock_server(vec![
+        (200, r#"{"type":"ERROR","object":{"kind":"Status","code":410,"reason":"Expired"}}
+"#),
+        (200, r#"{"metadata":{"name":"a","resourceVersion":"5"}}"#),
+        (200, r#"{"type":"MODIFIED","object":{"metadata":{"resourceVersion":"6"},"status":{"conditions":[{"type":"Ready","status":"True"}]}}}
+"#),
+    ]);
+    let c = mock_context(base.clone());
+    wait_for(&c.client, url(&base), Some("1".to_owned()), Some("Ready")).unwrap();
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1/namespaces/myns/configmaps/a?watch=true&resourceVersion=1 HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/a HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/a?watch=true&resourceVersion=5 HTTP/1.1",
+    ]);
+
+    // Any other error is returned
+    let (base, server) = mock_server(vec![
+        (200, r#"{"type":"ERROR","object":{"kind":"Status","code":500,"message":"oops"}}
+"#),
+    ]);
+    let c = mock_context(base.clone());
+    let e = wait_for(&c.client, url(&base), Some("1".to_owned()), Some("Ready")).unwrap_err();
+    match *e.kind() {
+        ErrorKind::Kubernetes(ref status) => assert_eq!(status["message"], "oops"),
+        ref k => panic!("unexpected error {:?}", k),
+    }
+    server.join().unwrap();
+}
+
+/// Turn the URL of an object into a watch on that object, starting
+/// after `resource_version` (if known).
+fn watch_url(mut url: Url, resource_version: Option<&str>) -> Url {
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("watch", "true");
+        if let Some(rv) = resource_version {
+            query.append_pair("resourceVersion", rv);
+        }
+    }
+    url
+}
+
+#[test]
+fn test_watch_url() {
+    let url = Url::parse("http://dummy/apis/apps/v1/namespaces/myns/deployments/foo").unwrap();
+
+    assert_eq!(watch_url(url.clone(), Some("1234")).as_str(),
+               "http://dummy/apis/apps/v1/namespaces/myns/deployments/foo?watch=true&resourceVersion=1234");
+    assert_eq!(watch_url(url, None).as_str(),
+               "http://dummy/apis/apps/v1/namespaces/myns/deployments/foo?watch=true");
+}
+
+/// Apply `o` as a merge patch, creating it if missing and `creat` is set.
+///
+/// If the object is modified by someone else at the same time (409
+/// Conflict), re-read it and apply again up to `conflict_retries` times.
+fn update_one(c: &mut Context, o: &JsonValue, creat: bool, conflict_retries: u32) -> Result<(Operation, u16, JsonValue)> {
+    update_one_from(c, o, creat, conflict_retries, None)
+}
+
+/// The URL, patch type and body of the PATCH updating `o`
+fn update_request(c: &mut Context, o: &JsonValue) -> Result<(Url, ContentType, String)> {
+    let url = c.url_for(o, true)?;
+
+    // TODO: set kubernetes.io/change-cause ?
+    let body = c.object_body(o)?;
+
+    let patch_type = c.patch_type(&o.k8s_kind())?;
+    let patch_type = ContentType(patch_type.content_type().parse().unwrap());
+    Ok((url, patch_type, body))
+}
+
+/// Send the first PATCH for each of `objects`, up to `parallelism` at
+/// once, for `update_one_from`.  None for those not sent, after a
+/// failure (or one that couldn't be prepared) or interrupt.
+fn send_patches(c: &mut Context, objects: &[&JsonValue], parallelism: usize) -> Vec<Option<Result<Response>>> {
+    let requests: Vec<_> = objects.iter()
+        .map_while(|&o| update_request(c, o).ok())
+        .collect();
+    let client = &c.client;
+    let mut sent = parallel_map(&requests, parallelism, client.interrupted, |(url, patch_type, body)| {
+        client.request(Method::Patch, url.clone(), Some((patch_type.clone(), body)))
+    });
+    sent.resize_with(objects.len(), || None);
+    sent
+}
+
+/// As `update_one`, given the response to the first PATCH if already
+/// sent.
+fn update_one_from(c: &mut Context, o: &JsonValue, creat: bool, conflict_retries: u32, first: Option<Result<Response>>) -> Result<(Operation, u16, JsonValue)> {
+    let (url, patch_type, mut body) = update_request(c, o)?;
+    let mut resp = match first {
+        Some(resp) => resp?,
+        None => c.request(Method::Patch, url.clone(), Some((patch_type.clone(), &body)))?,
+    };
+    let mut op = Operation::Update;
+
+    let mut attempt = 0;
+    while resp.status == hyper::status::StatusCode::Conflict && attempt < conflict_retries &&
+        c.client.retry_budget.lock().unwrap().try_retry()
+    {
+        let started = Instant::now();
+        attempt += 1;
+        info!("Conflict updating {}, retrying ({}/{})", o.k8s_tname(), attempt, conflict_retries);
+        thread::sleep(CONFLICT_BACKOFF * attempt);
+
+        // Re-apply on top of the latest version, so a further
+        // concurrent change is detected rather than overwritten.
+        let current = kube_result(c.request(Method::Get, url.clone(), None)?)?;
+        let mut retry = o.clone();
+        retry["metadata"]["resourceVersion"] = current["metadata"]["resourceVersion"].clone();
+        body = retry.dump();
+
+        resp = c.request(Method::Patch, url.clone(), Some((patch_type.clone(), &body)))?;
+        c.client.retry_budget.lock().unwrap().charge(started.elapsed());
+    }
+
+    if creat && resp.status == hyper::NotFound {
+        // Not found => create
+        info!("Creating {}", o.k8s_tname());
+        let url = c.url_for(o, false)?;
+        let (content_type, body) = c.create_body(o)?;
+
+        resp = c.request(Method::Post, url, Some((content_type, &body)))?;
+        op = Operation::Create;
+    }
+
+    let status = resp.status.to_u16();
+
+    Ok((op, status, kube_result(resp)?))
+}
+
+/// Content type for a server-side apply PATCH
+const APPLY_PATCH: &'static str = "application/apply-patch+yaml";
+
+fn do_apply<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
+
+    let mut parsed = {
+        let filename = matches.value_of_os("file").unwrap();
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
+
+        json::parse(&json)
+            .chain_err(|| "Unable to parse jsonnet output")?
+    };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
+
+    let mut objects = select_objects(&parsed, matches);
+    check_kind_policy(&objects, matches)?;
+    objects.sort_by_key(|&v| kutils::dep_first(v));
+    let manager = matches.value_of("field_manager").unwrap();
+
+    let mut results = Results::default();
+    let mut applied = vec![];
+    let r = ensure_namespaces(c, matches, &objects, &mut results)
+        .and_then(|()| apply_objects(c, &objects, manager, &mut results, &mut applied));
+    if r.is_ok() && matches.is_present("show_ownership") {
+        write_ownership(&applied, manager, &mut w)?;
+    }
+    let written = write_results(matches, &results, w);
+    r.and(written)
+}
+
+/// Objects returned by the server are added to `applied`.
+fn apply_objects(c: &mut Context, objects: &[&JsonValue], manager: &str, results: &mut Results, applied: &mut Vec<JsonValue>) -> Result<()> {
+    catch_interrupts();
+    for (i, &o) in objects.iter().enumerate() {
+        check_interrupted(&c.client, results, objects[i..].iter().map(|o| o.k8s_id()))?;
+        let r = apply_one(c, o, manager);
+        applied.push(results.record(o.k8s_id(), r)?);
+    }
+    Ok(())
+}
+
+fn apply_one(c: &mut Context, o: &JsonValue, manager: &str) -> Result<(Operation, u16, JsonValue)> {
+    let mut url = c.url_for(o, true)?;
+    url.query_pairs_mut()
+        .append_pair("fieldManager", manager)
+        .append_pair("force", "true");
+    // JSON is valid YAML
+    let body = c.object_body(o)?;
+
+    let resp = c.request(Method::Patch, url, Some((ContentType(APPLY_PATCH.parse().unwrap()), &body)))?;
+    let status = resp.status.to_u16();
+    let op = if resp.status == hyper::status::StatusCode::Created { Operation::Create } else { Operation::Update };
+
+    Ok((op, status, kube_result(resp)?))
+}
+
+#[test]
+fn test_apply_one() {
+    let (url, server) = mock_server_headers(vec![
+        (201, r#"{"metadata":{"name":"foo"}}"#),
+        (200, r#"{"metadata":{"name":"foo"}}"#),
+    ]);
+    let mut c = mock_context(url);
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+
+    let (op, status, _) = apply_one(&mut c, &o, "kubecfg").unwrap();
+    assert_eq!((op, status), (Operation::Create, 201));
+    let (op, _, _) = apply_one(&mut c, &o, "ci").unwrap();
+    assert_eq!(op, Operation::Update);
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0][0], "PATCH /api/v1/namespaces/myns/configmaps/foo?fieldManager=kubecfg&force=true HTTP/1.1");
+    assert!(requests[0].contains(&"Content-Type: application/apply-patch+yaml".to_owned()), "{:?}", requests[0]);
+    assert_eq!(requests[1][0], "PATCH /api/v1/namespaces/myns/configmaps/foo?fieldManager=ci&force=true HTTP/1.1");
+}
+
+#[test]
+fn test_update_patch_type() {
+    let ok = r#"{"metadata":{"name":"foo"}}"#;
+    let (url, server) = mock_server_headers(vec![
+        (200, ok),
+        (200, r#"{"kind":"CustomResourceDefinition"}"#), (200, ok),
+        (404, r#"{"kind":"Status","code":404}"#), (200, ok),
+        (200, r#"{"kind":"CustomResourceDefinition"}"#), (200, ok),
+        (200, ok),
+    ]);
+    let mut c = mock_context(url);
+    let kinds = [
+        ("v1", "ConfigMap", "configmaps"),
+        ("example.com/v1", "Widget", "widgets"),
+        ("networking.k8s.io/v1", "Ingress", "ingresses"),
+        ("gateway.networking.k8s.io/v1", "Gateway", "gateways"),
+        ("example.com/v1", "Widget", "widgets"),
+    ];
+    for &(api_version, kind, name) in &kinds {
+        c.api_cache.insert(kutils::K8sKind::new(api_version, kind), kutils::ApiResource {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            namespaced: true,
+            subresources: vec![],
+            custom: None,
+        });
+    }
+
+    for kind in &kinds {
+        let o = object!{
+            "apiVersion" => kind.0,
+            "kind" => kind.1,
+            "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+        };
+        update_one(&mut c, &o, false, 0).unwrap();
+    }
+
+    let requests = server.join().unwrap();
+    let request_lines: Vec<_> = requests.iter().map(|r| r[0].as_str()).collect();
+    assert_eq!(request_lines, vec![
+        "PATCH /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+        "GET /apis/apiextensions.k8s.io/v1/customresourcedefinitions/widgets.example.com HTTP/1.1",
+        "PATCH /apis/example.com/v1/namespaces/myns/widgets/foo HTTP/1.1",
+        "GET /apis/apiextensions.k8s.io/v1/customresourcedefinitions/ingresses.networking.k8s.io HTTP/1.1",
+        "PATCH /apis/networking.k8s.io/v1/namespaces/myns/ingresses/foo HTTP/1.1",
+        "GET /apis/apiextensions.k8s.io/v1/customresourcedefinitions/gateways.gateway.networking.k8s.io HTTP/1.1",
+        "PATCH /apis/gateway.networking.k8s.io/v1/namespaces/myns/gateways/foo HTTP/1.1",
+        // The CRD lookup is only done once per kind
+        "PATCH /apis/example.com/v1/namespaces/myns/widgets/foo HTTP/1.1",
+    ]);
+    let content_type = |i: usize| requests[i].iter()
+        .find(|h| h.starts_with("Content-Type:"))
+        .cloned()
+        .unwrap_or_default();
+    assert_eq!(content_type(0), "Content-Type: application/strategic-merge-patch+json");
+    assert_eq!(content_type(2), "Content-Type: application/merge-patch+json");
+    assert_eq!(content_type(4), "Content-Type: application/strategic-merge-patch+json");
+    assert_eq!(content_type(6), "Content-Type: application/merge-patch+json");
+    assert_eq!(content_type(7), "Content-Type: application/merge-patch+json");
+}
+
+/// With the status subresource, `o` is sent as the object without its
+/// status, then a merge patch of the status alone to `/status`.
+/// Returns those two, or None if `o` is sent whole.
+fn split_status(c: &mut Context, o: &JsonValue) -> Result<Option<(JsonValue, String)>> {
+    if !o.has_key("status") || !c.api_resource(&o.k8s_kind())?.has_subresource("status") {
+        return Ok(None);
+    }
+    let mut main = o.clone();
+    let status = main.remove("status");
+    let body = c.check_size(o, object!{"status" => status}.dump())?;
+    Ok(Some((main, body)))
+}
+
+/// For `--output-patch`, write the body of each PATCH that updating
+/// `objects` sends to a file in `dir`.
+fn write_patches(c: &mut Context, dir: &Path, objects: &[&JsonValue], status_subresource: bool) -> Result<()> {
+    fs::create_dir_all(dir)
+        .chain_err(|| format!("Unable to create directory {}", dir.display()))?;
+    let ext = c.request_format.extension();
+
+    for &o in objects {
+        let name = kutils::object_filename(o);
+        let split = if status_subresource { split_status(c, o)? } else { None };
+        let mut files = vec![];
+        match split {
+            Some((main, status)) => {
+                files.push((format!("{}.{}", name, ext), update_request(c, &main)?.2));
+                files.push((format!("{}.status.json", name), status));
+            },
+            None => files.push((format!("{}.{}", name, ext), update_request(c, o)?.2)),
+        }
+        for (filename, body) in files {
+            let path = dir.join(filename);
+            info!("Writing {}", path.display());
+            fs::write(&path, body)
+                .chain_err(|| format!("Unable to write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write_patches() {
+    let mut c = mock_context(Url::parse("http://localhost:1/").unwrap());
+    c.api_cache.insert(kutils::K8sKind::new("example.com/v1", "Widget"), kutils::ApiResource {
+        name: "widgets".to_owned(),
+        kind: "Widget".to_owned(),
+        namespaced: true,
+        subresources: vec!["status".to_owned()],
+        custom: Some(true),
+    });
+    let widget = object!{
+        "apiVersion" => "example.com/v1",
+        "kind" => "Widget",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"},
+        "status" => object!{"ready" => true}
+    };
+    let dir = env::temp_dir().join(format!("kubecfg-test-write-patches-{}", ::std::process::id()));
+    let read = |name: &str| json::parse(&fs::read_to_string(dir.join(name)).unwrap()).unwrap();
+
+    write_patches(&mut c, &dir, &[&widget], false).unwrap();
+    assert_eq!(read("myns-widget-foo.json"), widget);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The status is sent separately to /status
+    write_patches(&mut c, &dir, &[&widget], true).unwrap();
+    let mut main = widget.clone();
+    main.remove("status");
+    assert_eq!(read("myns-widget-foo.json"), main);
+    assert_eq!(read("myns-widget-foo.status.json"), object!{"status" => object!{"ready" => true}});
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// As `update_one`, but send `status` to the status subresource if the
+/// resource has one.  The main resource ignores (or rejects) status
+/// changes in that case, so it is stripped from the main patch.
+fn update_with_status(c: &mut Context, o: &JsonValue, creat: bool, conflict_retries: u32) -> Result<(Operation, u16, JsonValue)> {
+    let (main, body) = match split_status(c, o)? {
+        Some(split) => split,
+        None => return update_one(c, o, creat, conflict_retries),
+    };
+    let (op, _, _) = update_one(c, &main, creat, conflict_retries)?;
+
+    let mut url = c.url_for(o, true)?;
+    url.path_segments_mut().unwrap().push("status");
+
+    let merge_patch = ContentType("application/merge-patch+json".parse().unwrap());
+    let resp = c.request(Method::Patch, url, Some((merge_patch, &body)))?;
+    let code = resp.status.to_u16();
+
+    Ok((op, code, kube_result(resp)?))
+}
+
+#[test]
+fn test_update_with_status() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"metadata":{"name":"foo","resourceVersion":"42"}}"#),
+        (200, r#"{"metadata":{"name":"foo","resourceVersion":"43"},"status":{"ready":true}}"#),
+        (200, r#"{"metadata":{"name":"foo","resourceVersion":"44"}}"#),
+    ]);
+    let mut c = mock_context(url);
+    c.api_cache.insert(kutils::K8sKind::new("example.com/v1", "Widget"), kutils::ApiResource {
+        name: "widgets".to_owned(),
+        kind: "Widget".to_owned(),
+        namespaced: true,
+        subresources: vec!["status".to_owned()],
+        custom: Some(true),
+    });
+
+    let widget = object!{
+        "apiVersion" => "example.com/v1",
+        "kind" => "Widget",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"},
+        "status" => object!{"ready" => true}
+    };
+    let (op, _, new_obj) = update_with_status(&mut c, &widget, false, 0).unwrap();
+    assert_eq!(op, Operation::Update);
+    assert_eq!(new_obj["status"]["ready"], true);
+
+    // No status subresource: a single patch
+    let cm = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"},
+        "status" => object!{"ready" => true}
+    };
+    update_with_status(&mut c, &cm, false, 0).unwrap();
+
+    assert_eq!(server.join().unwrap(), vec![
+        "PATCH /apis/example.com/v1/namespaces/myns/widgets/foo HTTP/1.1",
+        "PATCH /apis/example.com/v1/namespaces/myns/widgets/foo/status HTTP/1.1",
+        "PATCH /api/v1/namespaces/myns/configmaps/foo HTTP/1.1",
+    ]);
+}
+
+/// Write a summary of `results` to `w`, if requested with
+/// `--output-results`.
+fn write_results<'a,W>(matches: &ArgMatches<'a>, results: &Results, w: W) -> Result<()>
+    where W: Write
+{
+    match matches.value_of("output_results") {
+        Some("json") => OutputFormat::Json.emit(&results.to_json(), w),
+        _ => Ok(()),
+    }
+}
+
+/// The API path used for `kind`, with a `{namespace}` placeholder if
+/// it is namespaced.
+fn api_path_template(map: &ApiMap, kind: &kutils::K8sKind) -> Result<String> {
+    let mut url = Url::parse("http://dummy/").unwrap();
+    api_path_for_type(&mut url.path_segments_mut().unwrap(), map, kind, Some("{namespace}"))?;
+    Ok(url::percent_encoding::percent_decode(url.path().as_bytes())
+       .decode_utf8_lossy()
+       .into_owned())
+}
+
+/// Describe each entry of `map`, for `api-resources`
+fn api_resources_json(map: &ApiMap) -> Result<JsonValue> {
+    let mut res = vec![];
+    for (kind, api) in map {
+        res.push(object!{
+            "kind" => kind.kind.clone(),
+            "apiVersion" => kind.api_version.to_string(),
+            "name" => api.name.clone(),
+            "namespaced" => api.namespaced,
+            "subresources" => api.subresources.clone(),
+            "path" => api_path_template(map, kind)?
+        });
+    }
+    Ok(JsonValue::Array(res))
+}
+
+#[test]
+fn test_api_resources_json() {
+    let mut map = ApiMap::new();
+    add_api_resources(&mut map, "apps/v1", &array![
+        object!{"name" => "deployments", "kind" => "Deployment", "namespaced" => true},
+        object!{"name" => "deployments/status", "kind" => "Deployment", "namespaced" => true}
+    ]).unwrap();
+    add_api_resources(&mut map, "v1", &array![
+        object!{"name" => "namespaces", "kind" => "Namespace", "namespaced" => false}
+    ]).unwrap();
+
+    assert_eq!(api_resources_json(&map).unwrap(), array![
+        object!{
+            "kind" => "Namespace",
+            "apiVersion" => "v1",
+            "name" => "namespaces",
+            "namespaced" => false,
+            "subresources" => array![],
+            "path" => "/api/v1/namespaces"
+        },
+        object!{
+            "kind" => "Deployment",
+            "apiVersion" => "apps/v1",
+            "name" => "deployments",
+            "namespaced" => true,
+            "subresources" => array!["status"],
+            "path" => "/apis/apps/v1/namespaces/{namespace}/deployments"
+        }
+    ]);
+}
+
+fn do_api_resources<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    c.fetch_all_api_info()?;
+    let resources = api_resources_json(&c.api_cache)?;
+
+    match matches.value_of("format").unwrap() {
+        "text" => {
+            let rows: Vec<Vec<String>> = resources.members()
+                .map(|r| ["kind", "apiVersion", "name", "namespaced", "path"].iter()
+                     .map(|k| r[*k].to_string())
+                     .collect())
+                .collect();
+            write_table(&["KIND", "APIVERSION", "NAME", "NAMESPACED", "PATH"], &rows, w)
+        },
+        format => format.parse::<OutputFormat>()?.emit(&resources, w),
+    }
+}
+
+/// Write `rows` as left-aligned columns under `header`
+fn write_table<W>(header: &[&str], rows: &[Vec<String>], mut w: W) -> Result<()>
+    where W: Write
+{
+    let widths: Vec<_> = (0..header.len())
+        .map(|i| rows.iter().map(|r| r[i].len()).chain(iter::once(header[i].len())).max().unwrap())
+        .collect();
+
+    let header: Vec<_> = header.iter().map(|h| h.to_string()).collect();
+    for row in iter::once(&header).chain(rows) {
+        let cols: Vec<_> = row.iter().zip(&widths)
+            .map(|(c, &width)| format!("{:<width$}", c, width = width))
+            .collect();
+        writeln!(w, "{}", cols.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Find the kind named `name` (case-insensitively, or by resource
+/// name), in `api_version` or else the server's preferred versions.
+fn find_kind(c: &mut Context, name: &str, api_version: Option<&str>) -> Result<kutils::K8sKind> {
+    let api_version = api_version.map(kutils::ApiVersion::from);
+    match api_version {
+        Some(ref v) => c.fetch_api_info(v)?,
+        None => c.fetch_all_api_info()?,
+    }
+
+    let name = name.to_lowercase();
+    c.api_cache.iter()
+        .find(|&(k, api)| {
+            api_version.as_ref().is_none_or(|v| k.api_version == *v) &&
+                (k.kind.to_lowercase() == name || api.name == name)
+        })
+        .map(|(k, _)| k.clone())
+        .ok_or_else(|| ErrorKind::UnknownResource(name).into())
+}
+
+fn do_explain<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    let arg = matches.value_of("field").unwrap();
+    let mut path: Vec<_> = arg.split('.').collect();
+    let resource = path.remove(0);
+
+    let kind = find_kind(c, resource, matches.value_of("api_version"))?;
+    let schema = c.fetch_schema()?;
+    let def = schema.definition_for(&kind)
+        .ok_or_else(|| format!("No schema for {}", kind))?;
+    let field = schema.field(def, &path)
+        .ok_or_else(|| format!("Field \"{}\" does not exist in {}", path.join("."), kind.kind))?;
+
+    writeln!(w, "KIND:     {}", kind.kind)?;
+    writeln!(w, "VERSION:  {}", kind.api_version)?;
+    if let Some(name) = path.last() {
+        writeln!(w, "\nFIELD:    {} <{}>", name, schema.type_name(field))?;
+    }
+    writeln!(w)?;
+    schema.explain(field, w)?;
+    Ok(())
+}
+
+fn do_graph<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
 
     let parsed = {
         let filename = matches.value_of_os("file").unwrap();
-        let json = c.vm.evaluate_file(filename)
-            .map_err(|e| e.as_str().to_owned())?;
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
 
         json::parse(&json)
             .chain_err(|| "Unable to parse jsonnet output")?
     };
-    let mut objects = kutils::flatten_list(&parsed);
-    objects.sort_by_key(|&v| kutils::dep_first(v));
-
-    for o in objects {
-        let url = c.url_for(&o, false)?;
-        let body = o.dump();
 
-        // TODO: support --record?
-
-        info!("=> POST {}", url);
-        let req = c.client.post(url)
-            .header(ContentType::json())
-            .header(Accept::json())
-            .body(&body);
+    let objects = select_objects(&parsed, matches);
+    write_dot(&objects, &kutils::references(&objects), w)
+}
 
-        let resp = req.send()
-            .chain_err(|| "Error sending request")?;
-        info!("<= {}", resp.status);
+/// Write `objects` and the `refs` between them as a Graphviz DOT
+/// graph.  Referenced objects missing from `objects` are dashed.
+fn write_dot<W>(objects: &[&JsonValue], refs: &[kutils::Reference], mut w: W) -> Result<()>
+    where W: Write
+{
+    let quote = |id: &kutils::ObjectId| format!("{:?}", id.to_string());
+    let defined: BTreeSet<_> = objects.iter().map(|o| o.k8s_id()).collect();
 
-        kube_result(resp)?;
+    writeln!(w, "digraph kubecfg {{")?;
+    for id in &defined {
+        writeln!(w, "  {};", quote(id))?;
     }
-
+    let missing: BTreeSet<_> = refs.iter()
+        .map(|r| &r.to)
+        .filter(|id| !defined.contains(id))
+        .collect();
+    for id in missing {
+        writeln!(w, "  {} [style=dashed];", quote(id))?;
+    }
+    for r in refs {
+        writeln!(w, "  {} -> {} [label={:?}];", quote(&r.from), quote(&r.to), r.relation)?;
+    }
+    writeln!(w, "}}")?;
     Ok(())
 }
 
-fn do_delete<'a>(c: &mut Context, matches: &ArgMatches<'a>) -> Result<()> {
-    init_vm_options(&mut c.vm, matches);
+#[test]
+fn test_write_dot() {
+    let deploy = object!{
+        "apiVersion" => "apps/v1",
+        "kind" => "Deployment",
+        "metadata" => object!{"name" => "web"},
+        "spec" => object!{"template" => object!{"spec" => object!{
+            "volumes" => array![object!{"name" => "data", "persistentVolumeClaim" => object!{"claimName" => "data"}}]
+        }}}
+    };
+    let objects = [&deploy];
+
+    let mut out = vec![];
+    write_dot(&objects, &kutils::references(&objects), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "\
+digraph kubecfg {
+  \"deployment/web\";
+  \"persistentvolumeclaim/data\" [style=dashed];
+  \"deployment/web\" -> \"persistentvolumeclaim/data\" [label=\"mounts\"];
+}
+");
+}
+
+fn do_check<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, w: W) -> Result<()>
+    where W: Write
+{
+    init_vm_options(&mut c.vm, matches)?;
 
     let parsed = {
         let filename = matches.value_of_os("file").unwrap();
-        let json = c.vm.evaluate_file(filename)
-            .map_err(|e| e.as_str().to_owned())?;
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
 
         json::parse(&json)
             .chain_err(|| "Unable to parse jsonnet output")?
@@ -438,203 +5583,826 @@ fn do_delete<'a>(c: &mut Context, matches: &ArgMatches<'a>) -> Result<()> {
 
     let objects = kutils::flatten_list(&parsed);
 
-    let options: JsonValue = {
-        let mut o = kutils::DeleteOptions::default();
-
-        if let Some(n) = matches.value_of("grace_period") {
-            let v = n.parse()
-                .chain_err(|| "Invalid --grace-period")?;
-            o.grace_period_seconds = Some(v);
-        }
-
-        // Delete dependent objects automatically
-        o.orphan_dependents = false;
+    let mut findings = vec![];
 
-        o.into()
-    };
-    let body = options.dump();
+    if matches.is_present("strict") {
+        findings.extend(check_unknown_fields(c, &objects)?);
+    }
 
-    for o in objects {
-        let url = c.url_for(&o, true)?;
+    if matches.is_present("references") {
+        findings.extend(check_config_refs(c, &objects, matches.is_present("check_cluster"))?);
+    }
 
-        info!("DELETE {}", url);
-        let req = c.client.delete(url)
-            .header(ContentType::json())
-            .header(Accept::json())
-            .body(&body);
+    if matches.is_present("owners") {
+        findings.extend(check_owner_refs(&objects));
+    }
 
-        let resp = req.send()
-            .chain_err(|| "Error sending request")?;
-        info!("<= {}", resp.status);
+    // TODO: jsonschema validation
+    warn!("jsonschema validation not yet implemented");
 
-        kube_result(resp)?;
+    if matches.is_present("source_locations") {
+        let sources = sources::read_sources(Path::new(matches.value_of_os("file").unwrap()));
+        findings::locate(&mut findings, &sources);
     }
 
-    Ok(())
+    findings::report(&findings, matches.value_of("format") == Some("json"), w)
 }
 
-fn do_update<'a>(c: &mut Context, matches: &ArgMatches<'a>) -> Result<()> {
-    let creat = matches.is_present("create");
-    let wait = matches.is_present("wait");
+fn do_diff<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+    where W: Write
+{
+    use std::io::IsTerminal;
 
-    init_vm_options(&mut c.vm, matches);
+    init_vm_options(&mut c.vm, matches)?;
 
-    let parsed = {
-        let filename = matches.value_of_os("file").unwrap();
-        let json = c.vm.evaluate_file(filename)
-            .map_err(|e| e.as_str().to_owned())?;
+    let filename = matches.value_of_os("file").unwrap();
+    let mut parsed = {
+        let json = evaluate_input(&mut c.vm, matches, filename)?;
 
         json::parse(&json)
             .chain_err(|| "Unable to parse jsonnet output")?
     };
+    c.apply_force_namespace(&mut parsed);
+    post_process(&mut c.vm, &mut parsed, matches)?;
 
-    let mut objects = kutils::flatten_list(&parsed);
-    objects.sort_by_key(|&v| kutils::dep_first(v));
-
-    let mut wait_objects = Vec::new();
-
-    for o in objects {
-        let url = c.url_for(&o, true)?;
+    let mut objects = select_objects(&parsed, matches);
+    objects.sort_by_key(|item| item.k8s_name());
+    let last_applied_only = matches.is_present("last_applied_only");
+    let filename = filename.to_string_lossy();
+    // With --emit-changed, the objects that differ
+    let mut changed = if matches.is_present("emit_changed") { Some(vec![]) } else { None };
+    let exit_code: i32 = matches.value_of("error_exitcode").unwrap().parse()
+        .chain_err(|| "Invalid --error-exitcode")?;
+    let mut found = false;
+    let mut out = DiffOutput {
+        format: matches.value_of("format").unwrap(),
+        filename: &filename,
+        target: "the running service",
+        width: terminal_width(),
+        color: match matches.value_of("color").unwrap() {
+            "always" => true,
+            "never" => false,
+            _ => io::stdout().is_terminal(),
+        },
+    };
 
-        // TODO: set kubernetes.io/change-cause ?
-        let body = o.dump();
+    if let Some(against) = matches.value_of_os("against_file") {
+        let mut against_parsed = {
+            let json = evaluate_input(&mut c.vm, matches, against)?;
+            json::parse(&json)
+                .chain_err(|| "Unable to parse jsonnet output")?
+        };
+        c.apply_force_namespace(&mut against_parsed);
+        post_process(&mut c.vm, &mut against_parsed, matches)?;
+        let against_desc = format!("{}", Path::new(against).display());
+        out.target = &against_desc;
 
-        info!("=> PATCH {}", url);
-        let mut resp = {
-            let req = c.client.patch(url)
-                .header(ContentType("application/merge-patch+json".parse().unwrap()))
-                .header(Accept::json())
-                .body(&body);
+        for (id, old, new) in match_objects(select_objects(&against_parsed, matches), objects) {
+            if let Some(ref mut changed) = changed {
+                if !diff::diff_walk(0, old, new).is_empty() {
+                    found = true;
+                    // Objects only in the old file can't be applied
+                    if !new.is_null() {
+                        changed.push(new.clone());
+                    }
+                }
+            } else {
+                found |= write_diff(&mut w, &out, &id, old, new)?;
+            }
+        }
+        emit_changed(matches, changed, w)?;
+        return diffs_found(found, exit_code);
+    }
 
-            let resp = req.send()
-                .chain_err(|| "Error sending request")?;
-            info!("<= {}", resp.status);
-            resp
-        };
+    // TODO: optionally find everything else already in the namespace
 
-        if creat && resp.status == hyper::NotFound {
-            // Not found => create
-            info!("Creating {}", o.k8s_tname());
-            let url = c.url_for(&o, false)?;
+    let urls = objects.iter()
+        .map(|o| c.url_for(o, true))
+        .collect::<Result<Vec<_>>>()?;
+    let client = &c.client;
+    let fetched = parallel_map(&urls, parallelism(matches)?, client.interrupted, |url| {
+        let resp = client.request(Method::Get, url.clone(), None)?;
+        if resp.status == hyper::NotFound {
+            return Ok(JsonValue::Null);
+        }
+        let mut v = kube_result(resp)?;
+        Ok(match kutils::last_applied(&v) {
+            Some(applied) if last_applied_only => applied,
+            _ => {
+                // TODO: more cleaning. `metadata.selfLink`, etc.
+                v.remove("status");
+                v
+            },
+        })
+    });
 
-            info!("=> POST {}", url);
-            let req = c.client.post(url)
-                .header(ContentType::json())
-                .header(Accept::json())
-                .body(&body);
+    // Nothing is left out of `fetched` before the first error
+    for (o, existing) in objects.into_iter().zip(fetched.into_iter().flatten()) {
+        let existing = existing?;
 
-            resp = req.send()
-                .chain_err(|| "Error sending request")?;
-            info!("<= {}", resp.status);
+        if let Some(ref mut changed) = changed {
+            if !diff::diff_walk(0, &existing, o).is_empty() {
+                found = true;
+                changed.push(o.clone());
+            }
+        } else {
+            found |= write_diff(&mut w, &out, &o.k8s_id(), &existing, o)?;
         }
+    }
 
-        let new_obj = kube_result(resp)?;
+    emit_changed(matches, changed, w)?;
+    diffs_found(found, exit_code)
+}
 
-        // TODO: (Optionally) Show diff between orig and server response
+/// The result of a `diff` run: `--error-exitcode`, if anything
+/// differed.
+fn diffs_found(found: bool, exit_code: i32) -> Result<()> {
+    if found && exit_code != 0 {
+        Err(ErrorKind::DiffsFound(exit_code).into())
+    } else {
+        Ok(())
+    }
+}
 
-        if wait && o.is_k8s_kind(kutils::V1BETA1_DEPLOYMENT) {
-            wait_objects.push(new_obj);
-        }
+/// Write the `changed` objects, if collected for `--emit-changed`, as
+/// a List
+fn emit_changed<'a,W>(matches: &ArgMatches<'a>, changed: Option<Vec<JsonValue>>, w: W) -> Result<()>
+    where W: Write
+{
+    match changed {
+        Some(items) => {
+            let output: OutputFormat = matches.value_of("emit_changed").unwrap().parse()?;
+            let list = object!{
+                "apiVersion" => "v1",
+                "kind" => "List",
+                "items" => JsonValue::Array(items)
+            };
+            output.emit(&list, w)
+        },
+        None => Ok(()),
     }
+}
 
-    for o in wait_objects {
-        info!("Waiting for {}", o);
+#[test]
+fn test_diff_against_file_post_process() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-diff-post-process-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("cm.jsonnet");
+    fs::write(&file, r#"{apiVersion: "v1", kind: "ConfigMap", metadata: {name: "a", namespace: "orig"}}"#).unwrap();
+    let lib = dir.join("ns.libsonnet");
+    fs::write(&lib, "function(o) o + {metadata+: {namespace: 'moved'}}").unwrap();
 
-        let mut keep_going = true;
+    // Both sides are processed the same way, so don't differ
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "diff", "--local",
+        "--against-file", file.to_str().unwrap(),
+        "-f", file.to_str().unwrap(),
+        "--post-process", lib.to_str().unwrap(),
+        "--error-exitcode", "3",
+    ]);
+    let mut c = mock_context(Url::parse("http://localhost:1/").unwrap());
+    c.force_namespace = true;
+    let mut out = vec![];
+    do_diff(&mut c, matches.subcommand_matches("diff").unwrap(), &mut out).unwrap();
 
-        while keep_going {
-            let mut url = c.url_for(&o, true)?;
-            url.query_pairs_mut().append_pair("watch", "true");
+    fs::remove_dir_all(&dir).unwrap();
+}
 
-            info!("=> GET {}", url);
-            let req = c.client.get(url)
-                .header(Accept::json());
+#[test]
+fn test_emit_changed() {
+    let dir = env::temp_dir().join(format!("kubecfg-test-emit-changed-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cm = |name: &str, v: &str| format!(r#"{{apiVersion: "v1", kind: "ConfigMap", metadata: {{name: "{}"}}, data: {{v: "{}"}}}}"#, name, v);
+    let list = |items: [String; 3]| format!(r#"{{apiVersion: "v1", kind: "List", items: [{}]}}"#, items.join(", "));
+    fs::write(dir.join("old.jsonnet"), list([cm("same", "1"), cm("changed", "1"), cm("removed", "1")])).unwrap();
+    fs::write(dir.join("new.jsonnet"), list([cm("same", "1"), cm("changed", "2"), cm("added", "1")])).unwrap();
 
-            let resp = req.send()
-                .chain_err(|| "Error sending request")?;
-            info!("<= {}", resp.status);
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "diff", "--local",
+        "--against-file", dir.join("old.jsonnet").to_str().unwrap(),
+        "-f", dir.join("new.jsonnet").to_str().unwrap(),
+        "--emit-changed", "json",
+    ]);
+    let mut c = mock_context(Url::parse("http://localhost:1/").unwrap());
+    let mut out = vec![];
+    let e = do_diff(&mut c, matches.subcommand_matches("diff").unwrap(), &mut out).unwrap_err();
+    assert!(matches!(*e.kind(), ErrorKind::DiffsFound(2)), "{:?}", e);
 
-            keep_going = kutils::kube_watch(resp, |o| {
-                Ok(!::kutils::is_rollout_done(&o))
-            })?
-        }
-    }
+    // No differences, or differences with --error-exitcode 0
+    let mut diff = |old: &str, new: &str, args: &[&str]| {
+        let mut argv = vec![
+            "kubecfg", "diff", "--local",
+            "--against-file", old,
+            "-f", new,
+        ];
+        argv.extend(args);
+        let matches = build_cli("test").get_matches_from(argv);
+        do_diff(&mut c, matches.subcommand_matches("diff").unwrap(), vec![])
+    };
+    let (old, new) = (dir.join("old.jsonnet"), dir.join("new.jsonnet"));
+    assert!(diff(old.to_str().unwrap(), old.to_str().unwrap(), &[]).is_ok());
+    assert!(diff(old.to_str().unwrap(), new.to_str().unwrap(), &["--error-exitcode", "0"]).is_ok());
+    let e = diff(old.to_str().unwrap(), new.to_str().unwrap(), &["--error-exitcode", "3"]).unwrap_err();
+    assert!(matches!(*e.kind(), ErrorKind::DiffsFound(3)), "{:?}", e);
+    fs::remove_dir_all(&dir).unwrap();
 
-    Ok(())
+    let list = json::parse(&String::from_utf8(out).unwrap()).unwrap();
+    let names: Vec<_> = list["items"].members().map(|o| o.k8s_name().unwrap()).collect();
+    assert_eq!(names, vec!["added", "changed"]);
 }
 
-fn do_check<'a>(c: &mut Context, matches: &ArgMatches<'a>) -> Result<()> {
-    init_vm_options(&mut c.vm, matches);
+/// Pair up the objects in `old` and `new` by identity, in `new`'s
+/// order followed by those only in `old`.  A missing side is `null`.
+fn match_objects<'a>(old: Vec<&'a JsonValue>, new: Vec<&'a JsonValue>) -> Vec<(kutils::ObjectId, &'a JsonValue, &'a JsonValue)> {
+    const MISSING: &JsonValue = &JsonValue::Null;
 
-    let filename = matches.value_of_os("file").unwrap();
-    let json = c.vm.evaluate_file(filename)
-        .map_err(|e| e.as_str().to_owned())?;
+    let mut old: BTreeMap<_, _> = old.into_iter().map(|o| (o.k8s_id(), o)).collect();
+    let mut res: Vec<_> = new.into_iter()
+        .map(|o| {
+            let id = o.k8s_id();
+            let prev = old.remove(&id).unwrap_or(MISSING);
+            (id, prev, o)
+        })
+        .collect();
+    res.extend(old.into_iter().map(|(id, o)| (id, o, MISSING)));
+    res
+}
 
-    let parsed = json::parse(&json)
-        .chain_err(|| "Unable to parse jsonnet output")?;
+#[test]
+fn test_match_objects() {
+    let cm = |name: &str, v: &str| object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => name},
+        "data" => object!{"v" => v}
+    };
+    let (a1, a2) = (cm("both", "1"), cm("removed", "1"));
+    let (b1, b2) = (cm("added", "1"), cm("both", "2"));
 
-    // TODO: jsonschema validation
-    let _ = parsed;
-    warn!("jsonschema validation not yet implemented");
+    let pairs: Vec<_> = match_objects(vec![&a1, &a2], vec![&b1, &b2]).into_iter()
+        .map(|(id, old, new)| (id.name, old["data"]["v"].as_str(), new["data"]["v"].as_str()))
+        .collect();
+    assert_eq!(pairs, vec![
+        ("added".to_owned(), None, Some("1")),
+        ("both".to_owned(), Some("1"), Some("2")),
+        ("removed".to_owned(), Some("1"), None),
+    ]);
+}
 
-    Ok(())
+/// How `diff` writes each object's differences
+struct DiffOutput<'a> {
+    /// `--format`
+    format: &'a str,
+    /// The input file, for annotations
+    filename: &'a str,
+    /// Where the old version came from
+    target: &'a str,
+    width: usize,
+    /// `--color`, resolved
+    color: bool,
 }
 
-fn do_diff<'a,W>(c: &mut Context, matches: &ArgMatches<'a>, mut w: W) -> Result<()>
+/// Write the differences between `old` and `new`, either of which may
+/// be `null`.  Returns whether there were any.
+fn write_diff<W>(mut w: W, out: &DiffOutput, id: &kutils::ObjectId, old: &JsonValue, new: &JsonValue) -> Result<bool>
     where W: Write
 {
-    init_vm_options(&mut c.vm, matches);
+    let diffs = diff::diff_walk(0, old, new);
+    if diffs.is_empty() {
+        return Ok(false);
+    }
 
-    let filename = matches.value_of_os("file").unwrap();
-    let parsed = {
-        let json = c.vm.evaluate_file(filename)
-            .map_err(|e| e.as_str().to_owned())?;
+    if out.format == "github" {
+        let lines: Vec<_> = diffs.iter().map(|d| d.to_string()).collect();
+        let title = if old.is_null() {
+            format!("{} would be created", id)
+        } else if new.is_null() {
+            format!("{} would be removed", id)
+        } else {
+            format!("{} differs from {}", id, out.target)
+        };
+        writeln!(w, "{}", diff::github_warning(out.filename, &title, &lines.join("\n")))?;
+    } else {
+        let namespace = id.namespace.as_deref().unwrap_or_default();
+        writeln!(w, "--- old {}/{}", namespace, id.name)?;
+        writeln!(w, "+++ new {}/{}", namespace, id.name)?;
+        if out.format == "side-by-side" {
+            let yaml = |v: &JsonValue| if v.is_null() { Ok(String::new()) } else { yaml_string(v) };
+            for line in diff::side_by_side(&diff::line_diff(&yaml(old)?, &yaml(new)?), out.width) {
+                writeln!(w, "{}", line)?;
+            }
+        } else {
+            for diff in diffs {
+                trace!("Got diff: {:?}", diff);
+                if out.color {
+                    writeln!(w, "{}", diff::Colored(&diff))?;
+                } else {
+                    writeln!(w, "{}", diff)?;
+                }
+            }
+        }
+    }
+    Ok(true)
+}
 
-        json::parse(&json)
-            .chain_err(|| "Unable to parse jsonnet output")?
+fn yaml_string(v: &JsonValue) -> Result<String> {
+    let mut buf = vec![];
+    OutputFormat::Yaml.emit(v, &mut buf)?;
+    String::from_utf8(buf)
+        .chain_err(|| "Invalid UTF-8 in YAML output")
+}
+
+/// Width of the terminal on stdout, or 130 columns (as `diff -y`) if
+/// stdout isn't a terminal or its size can't be found.
+fn terminal_width() -> usize {
+    use std::io::IsTerminal;
+    use std::process::{Command,Stdio};
+
+    const DEFAULT_WIDTH: usize = 130;
+    if !io::stdout().is_terminal() {
+        return DEFAULT_WIDTH;
+    }
+
+    if let Some(cols) = env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return cols;
+    }
+
+    // `stty size` prints "ROWS COLS" for the terminal on its stdin
+    fs::File::open("/dev/tty").ok()
+        .and_then(|tty| Command::new("stty").arg("size")
+                  .stdin(tty)
+                  .stderr(Stdio::null())
+                  .output().ok())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .and_then(|s| s.split_whitespace().nth(1).and_then(|c| c.parse().ok()))
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// The kubeconfig context to use, unless `--server` was given
+/// without `--context`.
+fn load_kubeconfig<'a>(matches: &ArgMatches<'a>) -> Result<Option<kubeconfig::KubeConfig>> {
+    if matches.occurrences_of("server") > 0 && !matches.is_present("context") {
+        return Ok(None);
+    }
+    let config = kubeconfig::load(&kubeconfig::default_paths(), matches.value_of("context"))?;
+    if let Some(ref config) = config {
+        debug!("Using kubeconfig context {}", config.context);
+    }
+    Ok(config)
+}
+
+/// The namespace for objects without one, and where it came from:
+/// `--namespace`, then the kubeconfig context, then "default".
+fn resolve_default_namespace<'a>(matches: &ArgMatches<'a>, kubeconfig: Option<&kubeconfig::KubeConfig>) -> (String, String) {
+    let flag = matches.value_of("default_namespace").unwrap().to_owned();
+    if matches.occurrences_of("default_namespace") > 0 {
+        return (flag, "--namespace".to_owned());
+    }
+    match kubeconfig.and_then(|k| k.namespace.as_ref().map(|ns| (k, ns))) {
+        Some((k, ns)) => (ns.clone(), format!("kubeconfig context {}", k.context)),
+        None => (flag, "built-in default".to_owned()),
+    }
+}
+
+#[test]
+fn test_resolve_default_namespace() {
+    let k = kubeconfig::KubeConfig {
+        context: "prod".to_owned(),
+        namespace: Some("prodns".to_owned()),
+        ..Default::default()
+    };
+    let resolve = |args: &[&str], k: Option<&kubeconfig::KubeConfig>| {
+        let mut argv = vec!["kubecfg"];
+        argv.extend(args);
+        argv.extend(&["show", "-f", "x"]);
+        resolve_default_namespace(&build_cli("test").get_matches_from(argv), k)
     };
+    let pair = |ns: &str, source: &str| (ns.to_owned(), source.to_owned());
 
-    let mut objects = kutils::flatten_list(&parsed);
-    objects.sort_by_key(|item| item.k8s_name());
+    assert_eq!(resolve(&[], None), pair("default", "built-in default"));
+    assert_eq!(resolve(&[], Some(&k)), pair("prodns", "kubeconfig context prod"));
+    assert_eq!(resolve(&["-n", "mine"], Some(&k)), pair("mine", "--namespace"));
+    assert_eq!(resolve(&["-n", "mine"], None), pair("mine", "--namespace"));
+}
 
-    // TODO: optionally find everything else already in the namespace
+fn build_context<'a>(matches: &ArgMatches<'a>) -> Result<Context> {
+    let mut vm = JsonnetVm::new();
 
-    for o in objects {
-        let url = c.url_for(&o, true)?;
+    let kubeconfig = load_kubeconfig(matches)?;
+    let server = match kubeconfig.as_ref().and_then(|k| k.server.as_ref()) {
+        Some(server) if matches.occurrences_of("server") == 0 => server.as_str(),
+        _ => matches.value_of("server").unwrap(),
+    };
+    let server_url = Url::parse(server)
+        .chain_err(|| format!("Invalid server URL {}", server))?;
+
+    // Plain HTTP is for `kubectl proxy`, which does its own
+    // authentication.  Don't leak credentials over it.
+    let plain_http = server_url.scheme() == "http";
+    let mut client = ApiClient::new(if plain_http { Client::new() } else { build_client(matches, kubeconfig.as_ref())? });
+
+    client.token = match (matches.value_of("token"), matches.value_of_os("token_file")) {
+        (Some(t), _) => Some(auth::Token::Static(t.to_owned())),
+        (None, Some(p)) => Some(auth::Token::File(auth::TokenFile::new(Path::new(p)))),
+        (None, None) => kubeconfig.as_ref().and_then(|k| match (&k.token_file, &k.token) {
+            (Some(p), _) => Some(auth::Token::File(auth::TokenFile::new(p))),
+            (None, Some(t)) => Some(auth::Token::Static(t.clone())),
+            (None, None) => None,
+        }),
+    };
+    if plain_http && client.token.is_some() {
+        warn!("Not sending bearer token credentials to plain HTTP server {}", server_url);
+        client.token = None;
+    }
+
+    client.user_agent = match matches.value_of("user_agent") {
+        Some(ua) => ua.to_owned(),
+        None => default_user_agent(matches.subcommand_name()),
+    };
+    // --low-bandwidth only changes the defaults of these
+    let low_bandwidth = matches.is_present("low_bandwidth");
+    client.accept_gzip = match matches.value_of("response_compression") {
+        Some(c) => c == "gzip",
+        None => low_bandwidth,
+    };
+    let chunk_size = match matches.value_of("chunk_size") {
+        Some(n) => n.parse().chain_err(|| "Invalid --chunk-size")?,
+        None if low_bandwidth => LOW_BANDWIDTH_CHUNK_SIZE,
+        None => 0,
+    };
+    let chunk_size = if chunk_size == 0 { None } else { Some(chunk_size) };
+    client.max_retries = matches.value_of("max_retries").unwrap().parse()
+        .chain_err(|| "Invalid --max-retries")?;
+    client.retry_budget = Mutex::new(RetryBudget {
+        retries: match matches.value_of("retry_budget") {
+            Some(n) => Some(n.parse().chain_err(|| "Invalid --retry-budget")?),
+            None => None,
+        },
+        time: match matches.value_of("retry_time_budget") {
+            Some(secs) => Some(Duration::from_secs(secs.parse().chain_err(|| "Invalid --retry-time-budget")?)),
+            None => None,
+        },
+        exhausted: false,
+    });
+
+    let client = Arc::new(client);
+    let (default_namespace, default_namespace_source) = resolve_default_namespace(matches, kubeconfig.as_ref());
+
+    if matches.is_present("allow_cluster_reads") {
+        ClusterReader::new(client.clone(), server_url.clone(), default_namespace.clone())
+            .register(&mut vm);
+    }
+
+    Ok(Context {
+        vm: vm,
+        server_url: server_url,
+        client,
+        api_cache: BTreeMap::new(),
+        default_namespace,
+        default_namespace_source,
+        force_namespace: matches.is_present("force_namespace"),
+        max_object_size: match matches.value_of("max_object_size").unwrap().parse()
+            .chain_err(|| "Invalid --max-object-size")?
+        {
+            0 => None,
+            n => Some(n),
+        },
+        request_format: OutputFormat::Json,
+        chunk_size,
+        patch_types: vec![],
+    })
+}
+
+/// Reads objects from the server during jsonnet evaluation, for
+/// `--allow-cluster-reads`.  Each object is only fetched once per run.
+struct ClusterReader {
+    client: Arc<ApiClient>,
+    server_url: Url,
+    default_namespace: String,
+    api_cache: RefCell<ApiMap>,
+    objects: RefCell<BTreeMap<Url, JsonValue>>,
+}
+
+impl ClusterReader {
+    fn new(client: Arc<ApiClient>, server_url: Url, default_namespace: String) -> Self {
+        ClusterReader {
+            client,
+            server_url,
+            default_namespace,
+            api_cache: RefCell::new(ApiMap::new()),
+            objects: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add `std.native('kubeGet')` to `vm`
+    fn register(self, vm: &mut JsonnetVm) {
+        vm.native_callback("kubeGet", move |vm, args| {
+            let arg = |i: usize, name: &str| args[i].as_str()
+                .ok_or_else(|| format!("kubeGet: {} must be a string", name));
+            let namespace = match args[2].as_null() {
+                Some(()) => None,
+                None => Some(arg(2, "namespace")?),
+            };
+            let kind = kutils::K8sKind::new(arg(0, "apiVersion")?, arg(1, "kind")?);
+            let v = self.get(&kind, namespace, arg(3, "name")?)
+                .map_err(|e| format!("kubeGet: {}", e))?;
+            Ok(to_jsonnet(vm, &v))
+        }, &["apiVersion", "kind", "namespace", "name"]);
+    }
 
-        info!("=> GET {}", url);
-        let req = c.client.get(url)
-            .header(Accept::json());
+    /// Fetch an object, or `null` if it doesn't exist
+    fn get(&self, kind: &kutils::K8sKind, namespace: Option<&str>, name: &str) -> Result<JsonValue> {
+        if !self.api_cache.borrow().contains_key(kind) {
+            let mut url = self.server_url.clone();
+            kind.api_version.path_segments(&mut url.path_segments_mut().unwrap());
+            let list = kube_result(self.client.request(Method::Get, url, None)?)?;
+            let group_version = list["groupVersion"].as_str()
+                .ok_or_else(|| ErrorKind::MalformedObject(list.clone()))?;
+            add_api_resources(&mut self.api_cache.borrow_mut(), group_version, &list["resources"])?;
+        }
 
-        let resp = req.send()
-            .chain_err(|| "Error sending request")?;
-        info!("<= {}", resp.status);
+        let mut url = self.server_url.clone();
+        {
+            let mut path = url.path_segments_mut().unwrap();
+            let namespace = namespace.unwrap_or(&self.default_namespace);
+            api_path_for_type(&mut path, &self.api_cache.borrow(), kind, Some(namespace))?;
+            path.push(name);
+        }
 
-        let existing = if resp.status == hyper::NotFound {
+        if let Some(v) = self.objects.borrow().get(&url) {
+            return Ok(v.clone());
+        }
+        let resp = self.client.request(Method::Get, url.clone(), None)?;
+        let v = if resp.status == hyper::NotFound {
             JsonValue::Null
         } else {
-            let mut v = kube_result(resp)?;
-            // TODO: more cleaning. `metadata.selfLink`, etc.
-            v.remove("status");
-            v
+            kube_result(resp)?
         };
+        self.objects.borrow_mut().insert(url, v.clone());
+        Ok(v)
+    }
+}
 
-        let diffs = diff::diff_walk(0, &existing, o);
-        if !diffs.is_empty() {
-            writeln!(w, "--- old {}/{}", o.k8s_namespace().unwrap_or_default(), o.k8s_name().unwrap_or_default())?;
-            writeln!(w, "+++ new {}/{}", o.k8s_namespace().unwrap_or_default(), o.k8s_name().unwrap_or_default())?;
-            for diff in diffs {
-                trace!("Got diff: {:?}", diff);
-                writeln!(w, "{}", diff)?;
-            }
+/// Convert `v` to a value that can be returned to jsonnet
+fn to_jsonnet<'a>(vm: &'a JsonnetVm, v: &JsonValue) -> jsonnet::JsonValue<'a> {
+    match *v {
+        JsonValue::Null => jsonnet::JsonValue::null(vm),
+        JsonValue::Boolean(b) => jsonnet::JsonValue::from_bool(vm, b),
+        JsonValue::Number(n) => jsonnet::JsonValue::from_num(vm, n.into()),
+        JsonValue::Short(_) | JsonValue::String(_) =>
+            jsonnet::JsonValue::from_str(vm, v.as_str().unwrap()),
+        JsonValue::Array(ref items) =>
+            jsonnet::JsonValue::from_array(vm, items.iter().map(|i| to_jsonnet(vm, i))),
+        JsonValue::Object(ref o) => {
+            // Kubernetes field names never contain NULs
+            let keys: Vec<_> = o.iter()
+                .map(|(k, _)| CString::new(k).unwrap_or_default())
+                .collect();
+            let values: Vec<_> = o.iter().map(|(_, v)| to_jsonnet(vm, v)).collect();
+            jsonnet::JsonValue::from_map(vm, keys.iter().map(|k| k.as_c_str()).zip(values))
+        },
+    }
+}
+
+#[test]
+fn test_cluster_reads() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"groupVersion":"v1","resources":[{"name":"configmaps","kind":"ConfigMap","namespaced":true}]}"#),
+        (200, r#"{"kind":"ConfigMap","metadata":{"name":"settings"},"data":{"replicas":"3","debug":true}}"#),
+        (404, r#"{"kind":"Status","code":404}"#),
+    ]);
+
+    let mut vm = JsonnetVm::new();
+    ClusterReader::new(Arc::new(ApiClient::new(Client::new())), url, "default".to_owned())
+        .register(&mut vm);
+
+    let result = vm.evaluate_snippet("test", r#"
+        local kubeGet = std.native('kubeGet');
+        {
+            replicas: std.parseInt(kubeGet('v1', 'ConfigMap', 'myns', 'settings').data.replicas),
+            again: kubeGet('v1', 'ConfigMap', 'myns', 'settings').data.debug,
+            missing: kubeGet('v1', 'ConfigMap', null, 'other'),
+        }"#).unwrap().as_str().to_owned();
+    assert_eq!(json::parse(&result).unwrap(), object!{
+        "replicas" => 3,
+        "again" => true,
+        "missing" => JsonValue::Null
+    });
+
+    assert_eq!(server.join().unwrap(), vec![
+        "GET /api/v1 HTTP/1.1",
+        "GET /api/v1/namespaces/myns/configmaps/settings HTTP/1.1",
+        "GET /api/v1/namespaces/default/configmaps/other HTTP/1.1",
+    ]);
+
+    let e = vm.evaluate_snippet("test", "std.native('kubeGet')('v1', 'ConfigMap', 'myns', 42)").unwrap_err();
+    assert!(e.as_str().contains("kubeGet: name must be a string"), "{}", e.as_str());
+}
+
+#[test]
+fn test_user_agent() {
+    let (url, server) = mock_server_headers(vec![
+        (201, r#"{"kind":"ConfigMap","metadata":{"name":"foo"}}"#),
+        (201, r#"{"kind":"ConfigMap","metadata":{"name":"foo"}}"#),
+    ]);
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadata" => object!{"name" => "foo", "namespace" => "myns"}
+    };
+
+    let mut c = mock_context(url.clone());
+    Arc::get_mut(&mut c.client).unwrap().user_agent = default_user_agent(Some("create"));
+    create_one(&mut c, &o).unwrap();
+
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "--server", url.as_str(), "--user-agent", "my-pipeline/1.0", "create", "-f", "x",
+    ]);
+    let mut c = build_context(&matches).unwrap();
+    c.api_cache = mock_context(url).api_cache;
+    create_one(&mut c, &o).unwrap();
+
+    let requests = server.join().unwrap();
+    let expected = format!("User-Agent: kubecfg/{} create (jsonnet {})", crate_version!(), jsonnet_version());
+    assert!(requests[0].contains(&expected), "{:?}", requests[0]);
+    assert!(requests[1].contains(&"User-Agent: my-pipeline/1.0".to_owned()), "{:?}", requests[1]);
+}
+
+#[test]
+fn test_plain_http_context() {
+    let (url, server) = mock_server(vec![
+        (200, r#"{"groupVersion":"v1","resources":[]}"#),
+    ]);
+    let matches = build_cli("test").get_matches_from(vec![
+        "kubecfg", "--server", url.as_str(), "--token-file", "/nonexistent", "check", "-f", "x",
+    ]);
+    let mut c = build_context(&matches).unwrap();
+    assert!(c.client.token.is_none());
+
+    // No TLS, and no attempt to read the token file
+    c.fetch_api_info(&kutils::ApiVersion::from("v1")).unwrap();
+    assert_eq!(server.join().unwrap(), vec!["GET /api/v1 HTTP/1.1"]);
+}
+
+#[test]
+fn test_token_arg() {
+    let context = |args: &[&str]| {
+        let mut argv = vec!["kubecfg", "--server", "https://localhost:6443/"];
+        argv.extend(args);
+        argv.extend(&["show", "-f", "x"]);
+        build_context(&build_cli("test").get_matches_from(argv)).unwrap()
+    };
+
+    let c = context(&["--token", "abc"]);
+    assert_eq!(c.client.token.as_ref().unwrap().value().unwrap(), "abc");
+    assert!(context(&[]).client.token.is_none());
+    assert!(build_cli("test").get_matches_from_safe(vec![
+        "kubecfg", "--token", "abc", "--token-file", "t", "show", "-f", "x",
+    ]).is_err());
+}
+
+#[test]
+fn test_client_certificate_args() {
+    let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata");
+    let cert = format!("{}/client.crt", testdata);
+    let key = format!("{}/client.key", testdata);
+    let ca = format!("{}/ca.pem", testdata);
+    let context = |args: &[&str]| {
+        let mut argv = vec!["kubecfg", "--server", "https://localhost:6443/"];
+        argv.extend(args);
+        argv.extend(&["show", "-f", "x"]);
+        build_cli("test").get_matches_from_safe(argv)
+            .chain_err(|| "Invalid arguments")
+            .and_then(|m| build_context(&m))
+    };
+
+    assert!(context(&["--client-certificate", &cert, "--client-key", &key, "--certificate-authority", &ca]).is_ok());
+    // Key without certificate, and vice versa
+    assert!(context(&["--client-key", &key]).is_err());
+    assert!(context(&["--client-certificate", &cert]).is_err());
+    assert!(context(&["--client-certificate", &cert, "--client-key", &cert]).is_err());
+}
+
+fn build_client<'a>(matches: &ArgMatches<'a>, kubeconfig: Option<&kubeconfig::KubeConfig>) -> Result<Client> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(dir) = matches.value_of_os("certificate_authority_dir") {
+        for cert in tls::read_cert_dir(Path::new(dir))? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(path) = matches.value_of_os("certificate_authority") {
+        for cert in tls::read_pem_certs(Path::new(path))? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(ca) = kubeconfig.and_then(|k| k.certificate_authority.as_ref()) {
+        let pem = String::from_utf8(ca.read()?)
+            .chain_err(|| "Invalid kubeconfig certificate-authority")?;
+        for cert in tls::parse_pem_certs(&pem, "kubeconfig certificate-authority")? {
+            builder.add_root_certificate(cert);
         }
     }
 
-    Ok(())
+    if let Some(path) = matches.value_of_os("pfx") {
+        let password = matches.value_of("pfx_password").unwrap_or("");
+        builder.identity(tls::read_pkcs12(Path::new(path), password)?);
+    } else if let (Some(cert), Some(key)) = (matches.value_of_os("client_certificate"), matches.value_of_os("client_key")) {
+        let read = |path: &OsStr| fs::read(path)
+            .chain_err(|| format!("Unable to read {}", Path::new(path).display()));
+        builder.identity(tls::pem_identity(&read(cert)?, &read(key)?)?);
+    } else if let Some(k) = kubeconfig {
+        match (&k.client_certificate, &k.client_key) {
+            (Some(cert), Some(key)) => { builder.identity(tls::pem_identity(&cert.read()?, &key.read()?)?); },
+            (None, None) => (),
+            _ => bail!("kubeconfig user needs both client-certificate and client-key"),
+        }
+    }
+
+    let insecure = if matches.is_present("insecure_skip_tls_verify") {
+        Some("--insecure-skip-tls-verify")
+    } else if kubeconfig.is_some_and(|k| k.insecure_skip_tls_verify) {
+        Some("kubeconfig insecure-skip-tls-verify")
+    } else {
+        None
+    };
+    if let Some(source) = insecure {
+        // Deliberately not a log message, so it can't be filtered out
+        writeln!(io::stderr(), "WARNING: {}: the server's TLS certificate is NOT being verified. \
+                                Anyone able to intercept this connection can impersonate the server. \
+                                Never use this outside development.", source)?;
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    let connector = builder.build()
+        .chain_err(|| "Error initialising TLS")?;
+
+    let mut ssl = tls::TlsClient::new(connector);
+    let server_name = matches.value_of("tls_server_name")
+        .or_else(|| kubeconfig.and_then(|k| k.tls_server_name.as_deref()));
+    if let Some(name) = server_name {
+        ssl = ssl.server_name(name);
+    }
+    if let Some(pin) = matches.value_of("pin_server_cert_sha256") {
+        let pin = tls::parse_fingerprint(pin)
+            .chain_err(|| "Invalid --pin-server-cert-sha256")?;
+        ssl = ssl.pin_sha256(pin);
+    }
+
+    // Reuse keep-alive connections across requests
+    let pool = Pool::with_connector(Default::default(), HttpsConnector::new(ssl));
+    // TODO: HTTP/2 (with a flag, falling back to HTTP/1.1 when the
+    // server doesn't negotiate h2).  Not supported yet: hyper 0.10
+    // only speaks HTTP/1.1, so this needs a move to a newer (async)
+    // hyper first.
+    Ok(Client::with_connector(pool))
+}
+
+#[test]
+fn test_build_client_reuses_connections() {
+    use std::io::{BufRead,BufReader,Read};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    // Serves every request on the first connection only
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut r = BufReader::new(stream);
+        let mut served = 0;
+        loop {
+            let mut line = String::new();
+            if r.read_line(&mut line).unwrap_or(0) == 0 {
+                return served;
+            }
+            if line == "\r\n" {
+                served += 1;
+                r.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            }
+        }
+    });
+
+    let matches = build_cli("test").get_matches_from(vec!["kubecfg", "show", "-f", "x"]);
+    let mut client = build_client(&matches, None).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5)));
+    for _ in 0..2 {
+        let mut body = String::new();
+        client.get(url.as_str()).send().unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "ok");
+    }
+    drop(client);
+    assert_eq!(server.join().unwrap(), 2);
 }
 
 fn main() {
     if let Err(ref e) = main_() {
+        // Not a failure as such, so no message
+        if let ErrorKind::DiffsFound(code) = *e.kind() {
+            ::std::process::exit(code);
+        }
+
         let stderr = &mut io::stderr();
         let errmsg = "Error writing to stderr";
 
@@ -649,59 +6417,59 @@ fn main() {
             writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
         }
 
-        ::std::process::exit(1);
+        let code = match *e.kind() {
+            ErrorKind::Interrupted(..) => EXIT_INTERRUPTED,
+            _ => 1,
+        };
+        ::std::process::exit(code);
     }
 }
 
 fn main_() -> Result<()> {
-    env_logger::init()
-        .chain_err(|| "Error initialising logging")?;
-
     let version = format!("{} (jsonnet {})", crate_version!(), jsonnet_version());
     let matches = build_cli(&version).get_matches();
+    init_logging(&matches)?;
+    chdir(&matches)?;
 
-    let mut context = {
-        let mut vm = JsonnetVm::new();
-        if let Some(paths) = env::var_os(OsStr::new(JPATH_ENVVAR)) {
-            for path in env::split_paths(&paths) {
-                vm.jpath_add(path);
-            }
-        }
-
-        let server_url = Url::parse(matches.value_of("server").unwrap())
-            .chain_err(|| "Invalid --server URL")?;
-
-        let ssl = NativeTlsClient::new().unwrap();
-        let connector = HttpsConnector::new(ssl);
-        let client = Client::with_connector(connector);
-
-        Context {
-            vm: vm,
-            server_url: server_url,
-            client: client,
-            api_cache: BTreeMap::new(),
-        }
-    };
+    let mut context = build_context(&matches)?;
 
     if let Some(ref matches) = matches.subcommand_matches("completions") {
         let shell = value_t!(matches, "shell", Shell)
             .unwrap_or_else(|e| e.exit());
         build_cli(&version).gen_completions_to("kubecfg", shell, &mut io::stdout());
 
+    } else if let Some(ref matches) = matches.subcommand_matches("__complete") {
+        do_complete(&mut context, matches, io::stdout())?
+
     } else if let Some(ref matches) = matches.subcommand_matches("show") {
         do_show(&mut context, matches, io::stdout())?
 
     } else if let Some(ref matches) = matches.subcommand_matches("create") {
-        do_create(&mut context, matches)?
+        do_create(&mut context, matches, io::stdout())?
 
     } else if let Some(ref matches) = matches.subcommand_matches("delete") {
-        do_delete(&mut context, matches)?
+        do_delete(&mut context, matches, io::stdout())?
 
     } else if let Some(ref matches) = matches.subcommand_matches("update") {
-        do_update(&mut context, matches)?
+        do_update(&mut context, matches, io::stdout())?
+
+    } else if let Some(ref matches) = matches.subcommand_matches("apply") {
+        do_apply(&mut context, matches, io::stdout())?
+
+    } else if let Some(ref matches) = matches.subcommand_matches("get") {
+        do_get(&mut context, matches, io::stdout())?
+
+    } else if let Some(ref matches) = matches.subcommand_matches("api-resources") {
+        do_api_resources(&mut context, matches, io::stdout())?
+
+    } else if let Some(ref matches) = matches.subcommand_matches("explain") {
+        do_explain(&mut context, matches, io::stdout())?
+
+    } else if let Some(ref matches) = matches.subcommand_matches("graph") {
+        do_graph(&mut context, matches, io::stdout())?
 
     } else if let Some(ref matches) = matches.subcommand_matches("check") {
-        do_check(&mut context, matches)?
+        do_check(&mut context, matches, io::stdout())?
 
     } else if let Some(ref matches) = matches.subcommand_matches("diff") {
         do_diff(&mut context, matches, io::stdout())?
@@ -0,0 +1,173 @@
+use json::JsonValue;
+
+use errors::*;
+use kutils::ObjectId;
+
+/// What was done to an object
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Operation {
+    Create,
+    Update,
+    /// Deleted and created again (`update --force`)
+    Replace,
+    Unchanged,
+    Delete,
+    /// Deletion accepted, but still in progress
+    Deleting,
+    Skip,
+    Error,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Replace => "replace",
+            Operation::Unchanged => "unchanged",
+            Operation::Delete => "delete",
+            Operation::Deleting => "deleting",
+            Operation::Skip => "skip",
+            Operation::Error => "error",
+        }
+    }
+}
+
+/// The outcome of processing a single object
+#[derive(Debug,Clone,PartialEq)]
+pub struct Outcome {
+    pub id: ObjectId,
+    pub operation: Operation,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    pub fn new(id: ObjectId, operation: Operation, status: Option<u16>) -> Self {
+        Outcome { id, operation, status, error: None }
+    }
+
+    pub fn error(id: ObjectId, e: &Error) -> Self {
+        let status = match *e.kind() {
+            ErrorKind::Kubernetes(ref v) => v["code"].as_u16(),
+            _ => None,
+        };
+        Outcome {
+            id,
+            operation: Operation::Error,
+            status,
+            error: Some(e.to_string()),
+        }
+    }
+}
+
+impl<'a> From<&'a Outcome> for JsonValue {
+    fn from(o: &'a Outcome) -> Self {
+        let mut res = object!{
+            "apiVersion" => o.id.kind.api_version.to_string(),
+            "kind" => o.id.kind.kind.clone(),
+            "name" => o.id.name.clone(),
+            "operation" => o.operation.as_str()
+        };
+        if let Some(ref ns) = o.id.namespace {
+            res["namespace"] = ns.clone().into();
+        }
+        if let Some(status) = o.status {
+            res["status"] = status.into();
+        }
+        if let Some(ref e) = o.error {
+            res["error"] = e.clone().into();
+        }
+        res
+    }
+}
+
+/// Outcomes for every object processed during a run
+#[derive(Debug,Default)]
+pub struct Results {
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Results {
+    pub fn push(&mut self, o: Outcome) {
+        self.outcomes.push(o);
+    }
+
+    /// Record the outcome of a request for `id`, passing on the
+    /// response body or error.
+    pub fn record<T>(&mut self, id: ObjectId, r: Result<(Operation, u16, T)>) -> Result<T> {
+        match r {
+            Ok((op, status, v)) => {
+                self.push(Outcome::new(id, op, Some(status)));
+                Ok(v)
+            },
+            Err(e) => {
+                self.push(Outcome::error(id, &e));
+                Err(e)
+            },
+        }
+    }
+
+    /// The latest outcome recorded for `id`
+    pub fn last_mut(&mut self, id: &ObjectId) -> Option<&mut Outcome> {
+        self.outcomes.iter_mut().rev().find(|o| o.id == *id)
+    }
+
+    /// Count of each operation, eg `2 update, 1 skip`
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(Operation, usize)> = vec![];
+        for o in &self.outcomes {
+            match counts.iter_mut().find(|(op, _)| *op == o.operation) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((o.operation, 1)),
+            }
+        }
+        counts.iter()
+            .map(|(op, n)| format!("{} {}", n, op.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Array(self.outcomes.iter().map(JsonValue::from).collect())
+    }
+}
+
+#[test]
+fn test_results_json() {
+    use kutils::K8sKind;
+
+    let mut results = Results::default();
+    let id = ObjectId {
+        kind: K8sKind::new("v1", "ConfigMap"),
+        namespace: Some("myns".to_owned()),
+        name: "foo".to_owned(),
+    };
+    let r = results.record(id.clone(), Ok((Operation::Update, 200, "body")));
+    assert_eq!(r.unwrap(), "body");
+
+    let e: Error = ErrorKind::Kubernetes(object!{"code" => 409, "message" => "conflict"}).into();
+    let r: Result<()> = results.record(id, Err(e));
+    assert!(r.is_err());
+
+    assert_eq!(results.to_json(), array![
+        object!{
+            "apiVersion" => "v1",
+            "kind" => "ConfigMap",
+            "name" => "foo",
+            "operation" => "update",
+            "namespace" => "myns",
+            "status" => 200
+        },
+        object!{
+            "apiVersion" => "v1",
+            "kind" => "ConfigMap",
+            "name" => "foo",
+            "operation" => "error",
+            "namespace" => "myns",
+            "status" => 409,
+            "error" => "Error from Kubernetes: conflict"
+        }
+    ]);
+    assert_eq!(results.summary(), "1 update, 1 error");
+}
@@ -0,0 +1,260 @@
+use std::io::{self,Write};
+use json::JsonValue;
+
+use kutils::{JsonValueExt,K8sKind};
+
+/// OpenAPI (v2) schema, as served by the Kubernetes API server at
+/// `/openapi/v2`.
+#[derive(Debug)]
+pub struct Schema {
+    definitions: JsonValue,
+}
+
+impl Schema {
+    pub fn new_from_json(mut v: JsonValue) -> Self {
+        Schema { definitions: v["definitions"].take() }
+    }
+
+    /// Find the definition describing objects of `kind`
+    pub fn definition_for(&self, kind: &K8sKind) -> Option<&JsonValue> {
+        self.definitions.entries()
+            .map(|(_, d)| d)
+            .find(|d| {
+                d["x-kubernetes-group-version-kind"].members().any(|gvk| {
+                    gvk["group"].as_str().unwrap_or_default() == kind.api_version.group &&
+                        gvk["version"] == kind.api_version.version.as_str() &&
+                        gvk["kind"] == kind.kind.as_str()
+                })
+            })
+    }
+
+    /// Top-level fields of `o` that are not described by the schema.
+    /// Returns `None` if the schema doesn't know about this kind, or
+    /// allows any field.
+    pub fn unknown_fields<'a>(&self, o: &'a JsonValue) -> Option<Vec<&'a str>> {
+        let def = self.definition_for(&o.k8s_kind())?;
+        let props = &def["properties"];
+        // eg a CRD without a structural schema
+        if !props.is_object() || def["x-kubernetes-preserve-unknown-fields"] == true {
+            return None;
+        }
+        Some(o.entries()
+             .map(|(k, _)| k)
+             .filter(|k| !props.has_key(k))
+             .collect())
+    }
+
+    /// Follow any `$ref` in `v` to the definition it refers to
+    fn deref<'a>(&'a self, mut v: &'a JsonValue) -> &'a JsonValue {
+        while let Some(name) = v["$ref"].as_str().and_then(|r| r.strip_prefix("#/definitions/")) {
+            v = &self.definitions[name];
+        }
+        v
+    }
+
+    /// The schema of the field at `path` (eg: `["spec", "template"]`)
+    /// within `def`.  Array fields are descended into transparently.
+    pub fn field<'a>(&'a self, def: &'a JsonValue, path: &[&str]) -> Option<&'a JsonValue> {
+        let mut cur = def;
+        for p in path {
+            let mut v = self.deref(cur);
+            if v["type"] == "array" {
+                v = self.deref(&v["items"]);
+            }
+            cur = &v["properties"][*p];
+            if cur.is_null() {
+                return None;
+            }
+        }
+        Some(cur)
+    }
+
+    /// A short description of the type of `v`, in the style of
+    /// `kubectl explain`.
+    pub fn type_name(&self, v: &JsonValue) -> String {
+        if v.has_key("$ref") {
+            return "Object".to_owned();
+        }
+        match v["type"].as_str() {
+            Some("array") => format!("[]{}", self.type_name(&v["items"])),
+            Some("object") if v.has_key("additionalProperties") =>
+                format!("map[string]{}", self.type_name(&v["additionalProperties"])),
+            Some("object") | None => "Object".to_owned(),
+            Some(t) => t.to_owned(),
+        }
+    }
+
+    /// Write the description of `v`, and of each of its fields.
+    pub fn explain<W>(&self, v: &JsonValue, mut w: W) -> io::Result<()>
+        where W: Write
+    {
+        let mut def = self.deref(v);
+        let description = v["description"].as_str()
+            .or_else(|| def["description"].as_str())
+            .unwrap_or("<empty>");
+        writeln!(w, "DESCRIPTION:")?;
+        for line in description.lines() {
+            writeln!(w, "    {}", line)?;
+        }
+
+        if def["type"] == "array" {
+            def = self.deref(&def["items"]);
+        }
+        let required: Vec<_> = def["required"].members()
+            .filter_map(|r| r.as_str())
+            .collect();
+        let mut fields: Vec<_> = def["properties"].entries().collect();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        fields.sort_by_key(|&(k, _)| k);
+
+        writeln!(w, "\nFIELDS:")?;
+        for (name, f) in fields {
+            let req = if required.contains(&name) { " -required-" } else { "" };
+            writeln!(w, "  {}\t<{}>{}", name, self.type_name(f), req)?;
+            let description = f["description"].as_str()
+                .or_else(|| self.deref(f)["description"].as_str())
+                .unwrap_or("<empty>");
+            for line in description.lines() {
+                writeln!(w, "    {}", line)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_schema() -> Schema {
+    Schema::new_from_json(object!{
+        "definitions" => object!{
+            "io.k8s.api.apps.v1.Deployment" => object!{
+                "description" => "Deployment enables declarative updates for Pods.",
+                "properties" => object!{
+                    "spec" => object!{
+                        "$ref" => "#/definitions/io.k8s.api.apps.v1.DeploymentSpec",
+                        "description" => "Specification of the desired behavior."
+                    }
+                },
+                "x-kubernetes-group-version-kind" => array![
+                    object!{"group" => "apps", "kind" => "Deployment", "version" => "v1"}
+                ]
+            },
+            "io.k8s.api.apps.v1.DeploymentSpec" => object!{
+                "description" => "DeploymentSpec is the specification of a Deployment.",
+                "required" => array!["template"],
+                "properties" => object!{
+                    "replicas" => object!{"type" => "integer", "description" => "Number of pods."},
+                    "template" => object!{"$ref" => "#/definitions/io.k8s.api.core.v1.PodTemplateSpec"}
+                }
+            },
+            "io.k8s.api.core.v1.PodTemplateSpec" => object!{
+                "description" => "Describes a pod.",
+                "properties" => object!{
+                    "containers" => object!{
+                        "type" => "array",
+                        "items" => object!{"$ref" => "#/definitions/io.k8s.api.core.v1.Container"}
+                    },
+                    "labels" => object!{
+                        "type" => "object",
+                        "additionalProperties" => object!{"type" => "string"}
+                    }
+                }
+            },
+            "io.k8s.api.core.v1.Container" => object!{
+                "properties" => object!{
+                    "image" => object!{"type" => "string", "description" => "Container image name."}
+                }
+            }
+        }
+    })
+}
+
+#[test]
+fn test_explain() {
+    let schema = test_schema();
+    let def = schema.definition_for(&K8sKind::new("apps/v1", "Deployment")).unwrap();
+
+    assert_eq!(schema.field(def, &[]), Some(def));
+    let containers = schema.field(def, &["spec", "template", "containers"]).unwrap();
+    assert_eq!(schema.type_name(containers), "[]Object");
+    let image = schema.field(def, &["spec", "template", "containers", "image"]).unwrap();
+    assert_eq!(schema.type_name(image), "string");
+    let labels = schema.field(def, &["spec", "template", "labels"]).unwrap();
+    assert_eq!(schema.type_name(labels), "map[string]string");
+    assert_eq!(schema.field(def, &["spec", "nonexistent"]), None);
+
+    let mut out = vec![];
+    schema.explain(schema.field(def, &["spec"]).unwrap(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "\
+DESCRIPTION:
+    Specification of the desired behavior.
+
+FIELDS:
+  replicas\t<integer>
+    Number of pods.
+
+  template\t<Object> -required-
+    Describes a pod.
+
+");
+}
+
+#[test]
+fn test_unknown_fields() {
+    let schema = Schema::new_from_json(object!{
+        "definitions" => object!{
+            "io.k8s.api.core.v1.ConfigMap" => object!{
+                "properties" => object!{
+                    "apiVersion" => object!{},
+                    "kind" => object!{},
+                    "metadata" => object!{},
+                    "data" => object!{}
+                },
+                "x-kubernetes-group-version-kind" => array![
+                    object!{"group" => "", "kind" => "ConfigMap", "version" => "v1"}
+                ]
+            }
+        }
+    });
+
+    let o = object!{
+        "apiVersion" => "v1",
+        "kind" => "ConfigMap",
+        "metadta" => object!{},
+        "data" => object!{}
+    };
+    assert_eq!(schema.unknown_fields(&o), Some(vec!["metadta"]));
+
+    let o = object!{
+        "apiVersion" => "example.com/v1",
+        "kind" => "Unknown"
+    };
+    assert_eq!(schema.unknown_fields(&o), None);
+
+    let crd = |extra: JsonValue| {
+        let mut def = object!{
+            "x-kubernetes-group-version-kind" => array![
+                object!{"group" => "example.com", "kind" => "Widget", "version" => "v1"}
+            ]
+        };
+        for (k, v) in extra.entries() {
+            def[k] = v.clone();
+        }
+        Schema::new_from_json(object!{
+            "definitions" => object!{"com.example.v1.Widget" => def}
+        })
+    };
+    let o = object!{
+        "apiVersion" => "example.com/v1",
+        "kind" => "Widget",
+        "spec" => object!{}
+    };
+    assert_eq!(crd(object!{}).unknown_fields(&o), None);
+    let preserved = crd(object!{
+        "properties" => object!{"apiVersion" => object!{}, "kind" => object!{}},
+        "x-kubernetes-preserve-unknown-fields" => true
+    });
+    assert_eq!(preserved.unknown_fields(&o), None);
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use kutils::ObjectId;
+
+/// `main` and the other jsonnet files beside it, which are the
+/// likeliest sources of its objects.  Unreadable files are skipped.
+pub fn read_sources(main: &Path) -> Vec<(PathBuf, String)> {
+    let mut paths = vec![main.to_owned()];
+    let dir = main.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut siblings: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| matches!(p.extension().and_then(|e| e.to_str()),
+                                 Some("jsonnet") | Some("libsonnet")))
+            .filter(|p| p.file_name() != main.file_name())
+            .collect();
+        siblings.sort();
+        paths.extend(siblings);
+    }
+
+    paths.into_iter()
+        .filter_map(|p| fs::read_to_string(&p).ok().map(|s| (p, s)))
+        .collect()
+}
+
+/// Best-effort `file:line` where `id` was defined, since jsonnet
+/// output doesn't record it: the first line in `sources` quoting the
+/// object's name, preferring a `name:` field.
+pub fn locate(id: &ObjectId, sources: &[(PathBuf, String)]) -> Option<String> {
+    let quoted = [format!("\"{}\"", id.name), format!("'{}'", id.name)];
+    let lines = || sources.iter()
+        .flat_map(|(path, text)| text.lines().enumerate().map(move |(i, l)| (path, i + 1, l)))
+        .filter(|&(_, _, l)| quoted.iter().any(|q| l.contains(q.as_str())));
+
+    lines()
+        .find(|&(_, _, l)| l.contains("name"))
+        .or_else(|| lines().next())
+        .map(|(path, n, _)| format!("{}:{}", path.display(), n))
+}
+
+#[test]
+fn test_locate() {
+    use kutils::K8sKind;
+
+    let dir = ::std::env::temp_dir().join(format!("kubecfg-test-locate-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("main.jsonnet"), "local lib = import 'lib.libsonnet';\n{\n  foo: lib.cm('web'),\n}\n").unwrap();
+    fs::write(dir.join("lib.libsonnet"), "{\n  cm(n):: {\n    kind: 'ConfigMap',\n    metadata: { name: n },\n  },\n  other: { metadata: { name: \"db\" } },\n}\n").unwrap();
+    fs::write(dir.join("notes.txt"), "name: 'missing'\n").unwrap();
+
+    let sources = read_sources(&dir.join("main.jsonnet"));
+    assert_eq!(sources.len(), 2);
+
+    let id = |name: &str| ObjectId {
+        kind: K8sKind::new("v1", "ConfigMap"),
+        namespace: None,
+        name: name.to_owned(),
+    };
+    assert_eq!(locate(&id("web"), &sources), Some(format!("{}:3", dir.join("main.jsonnet").display())));
+    assert_eq!(locate(&id("db"), &sources), Some(format!("{}:6", dir.join("lib.libsonnet").display())));
+    assert_eq!(locate(&id("missing"), &sources), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use hyper;
+use hyper::net::{SslClient,NetworkStream};
+use hyper_native_tls::{NativeTlsClient,TlsStream};
+use hyper_native_tls::native_tls::{Certificate,Identity,TlsConnector};
+use sha2::{Digest,Sha256};
+
+use errors::*;
+
+const PEM_CERT_BEGIN: &'static str = "-----BEGIN CERTIFICATE-----";
+
+/// Read all the certificates in a PEM file.
+pub fn read_pem_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = fs::read_to_string(path)
+        .chain_err(|| format!("Unable to read {}", path.display()))?;
+    parse_pem_certs(&pem, &path.display().to_string())
+}
+
+/// Parse all the certificates in `pem`, which came from `source`.
+pub fn parse_pem_certs(pem: &str, source: &str) -> Result<Vec<Certificate>> {
+    pem.match_indices(PEM_CERT_BEGIN)
+        .map(|(i, _)| {
+            let end = pem[i + 1..].find(PEM_CERT_BEGIN)
+                .map(|j| i + 1 + j)
+                .unwrap_or_else(|| pem.len());
+            Certificate::from_pem(&pem.as_bytes()[i..end])
+                .chain_err(|| format!("Invalid certificate in {}", source))
+        })
+        .collect()
+}
+
+/// Read the certificates from every `.pem` or `.crt` file in `dir`.
+pub fn read_cert_dir(dir: &Path) -> Result<Vec<Certificate>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).chain_err(|| format!("Unable to read {}", dir.display()))? {
+        let path = entry?.path();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("pem") | Some("crt") => paths.push(path),
+            _ => (),
+        }
+    }
+    paths.sort();
+
+    let mut certs = vec![];
+    for path in paths {
+        debug!("Adding trusted certificates from {}", path.display());
+        certs.extend(read_pem_certs(&path)?);
+    }
+    Ok(certs)
+}
+
+#[test]
+fn test_read_cert_dir() {
+    let dir = ::std::env::temp_dir().join(format!("kubecfg-test-certs-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let pem = include_str!("testdata/ca.pem");
+    fs::write(dir.join("one.pem"), pem).unwrap();
+    fs::write(dir.join("bundle.crt"), format!("{}{}", pem, pem)).unwrap();
+    fs::write(dir.join("README"), "not a certificate").unwrap();
+
+    let certs = read_cert_dir(&dir).unwrap();
+    assert_eq!(certs.len(), 3);
+
+    fs::write(dir.join("broken.pem"), format!("{}\ngarbage\n", PEM_CERT_BEGIN)).unwrap();
+    assert!(read_cert_dir(&dir).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Read a client certificate and key (and any intermediate
+/// certificates) from a PKCS#12 (`.p12`/`.pfx`) bundle.
+pub fn read_pkcs12(path: &Path, password: &str) -> Result<Identity> {
+    let der = fs::read(path)
+        .chain_err(|| format!("Unable to read {}", path.display()))?;
+    Identity::from_pkcs12(&der, password)
+        .chain_err(|| format!("Unable to decrypt PKCS#12 bundle {}. Is --pfx-password correct?", path.display()))
+}
+
+#[test]
+fn test_read_pkcs12() {
+    let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/client.p12"));
+
+    let identity = read_pkcs12(path, "kubecfg-test").unwrap();
+    assert!(TlsConnector::builder().identity(identity).build().is_ok());
+
+    let e = read_pkcs12(path, "wrong").err().unwrap();
+    assert!(e.to_string().contains("--pfx-password"), "{}", e);
+    assert!(read_pkcs12(&path.with_file_name("missing.p12"), "").is_err());
+}
+
+/// A client identity from a PEM certificate (chain) and PKCS#8
+/// private key.
+pub fn pem_identity(cert: &[u8], key: &[u8]) -> Result<Identity> {
+    Identity::from_pkcs8(cert, key)
+        .chain_err(|| "Invalid client certificate or key")
+}
+
+#[test]
+fn test_pem_identity() {
+    let cert = include_bytes!("testdata/client.crt");
+    let key = include_bytes!("testdata/client.key");
+
+    let identity = pem_identity(cert, key).unwrap();
+    assert!(TlsConnector::builder().identity(identity).build().is_ok());
+    assert!(pem_identity(cert, cert).is_err());
+}
+
+/// Parse a SHA-256 certificate fingerprint, as lowercase hex.  Accepts
+/// the `AB:CD:...` form printed by `openssl x509 -fingerprint`.
+pub fn parse_fingerprint(s: &str) -> Result<String> {
+    let hex: String = s.chars()
+        .filter(|&c| c != ':')
+        .collect::<String>()
+        .to_lowercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Expected 64 hex digits, got '{}'", s);
+    }
+    Ok(hex)
+}
+
+/// Check that the DER-encoded certificate `der` has SHA-256
+/// fingerprint `pin`.
+fn check_pin(der: &[u8], pin: &str) -> ::std::result::Result<(), String> {
+    let actual = ::hex(&Sha256::digest(der));
+    if actual == pin {
+        Ok(())
+    } else {
+        Err(format!("server certificate SHA-256 fingerprint {} does not match pinned {}", actual, pin))
+    }
+}
+
+#[test]
+fn test_check_pin() {
+    let cert = Certificate::from_pem(include_bytes!("testdata/ca.pem")).unwrap();
+    let der = cert.to_der().unwrap();
+
+    let pin = parse_fingerprint("3D:A0:E1:9C:79:CB:4C:C3:C4:16:35:A0:31:55:20:24:07:A2:83:A9:98:D1:24:7E:E3:10:25:66:B9:81:93:A6").unwrap();
+    assert_eq!(pin, "3da0e19c79cb4cc3c41635a03155202407a283a998d1247ee3102566b98193a6");
+    assert!(check_pin(&der, &pin).is_ok());
+
+    let wrong = "00".repeat(32);
+    let e = check_pin(&der, &wrong).unwrap_err();
+    assert!(e.contains(&pin), "actual fingerprint missing from: {}", e);
+
+    assert!(parse_fingerprint("3da0e1").is_err());
+    assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+}
+
+/// An `SslClient` for talking to the Kubernetes API server.
+pub struct TlsClient {
+    inner: NativeTlsClient,
+    server_name: Option<String>,
+    pin_sha256: Option<String>,
+}
+
+impl TlsClient {
+    pub fn new(connector: TlsConnector) -> Self {
+        TlsClient {
+            inner: connector.into(),
+            server_name: None,
+            pin_sha256: None,
+        }
+    }
+
+    /// Also require the server's certificate to have this SHA-256
+    /// fingerprint (as from `parse_fingerprint`).
+    pub fn pin_sha256(mut self, fingerprint: String) -> Self {
+        self.pin_sha256 = Some(fingerprint);
+        self
+    }
+
+    /// Use `name` for SNI and certificate verification, rather than
+    /// the host from the server URL.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+}
+
+impl<T> SslClient<T> for TlsClient
+    where T: NetworkStream + Send + Clone + fmt::Debug + Sync
+{
+    type Stream = TlsStream<T>;
+
+    fn wrap_client(&self, stream: T, host: &str) -> hyper::Result<TlsStream<T>> {
+        let host = self.server_name.as_deref().unwrap_or(host);
+        let stream = self.inner.wrap_client(stream, host)?;
+
+        if let Some(ref pin) = self.pin_sha256 {
+            let cert = stream.lock().peer_certificate()
+                .map_err(|e| hyper::Error::Ssl(Box::new(e)))?
+                .ok_or_else(|| hyper::Error::Ssl("server sent no certificate".into()))?;
+            let der = cert.to_der()
+                .map_err(|e| hyper::Error::Ssl(Box::new(e)))?;
+            check_pin(&der, pin)
+                .map_err(|msg| hyper::Error::Ssl(msg.into()))?;
+        }
+
+        Ok(stream)
+    }
+}